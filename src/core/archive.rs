@@ -0,0 +1,366 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use super::hash::fnv1a;
+
+const MAGIC: [u8; 4] = *b"VXPK";
+const VERSION: u32 = 1;
+
+/// magic + version + entry count
+const HEADER_SIZE: usize = 4 + 4 + 4;
+
+/// Index entry without the path: path length, offset, stored size, size, hash, flags.
+const ENTRY_SIZE: usize = 2 + 8 * 4 + 1;
+
+/// zlib can't compress more than about 1032 to 1, a larger size is a corrupted index.
+const MAX_COMPRESSION: u64 = 1032;
+
+const FLAG_COMPRESSED: u8 = 1;
+
+/// One file in the archive.
+#[derive(Clone, Debug)]
+struct ArchiveEntry {
+    offset: u64,
+    /// Bytes in the archive, compressed or not.
+    stored_size: u64,
+    size: u64,
+    hash: u64,
+    compressed: bool,
+}
+
+/// Read only pack of asset files, for builds that are not started next to the loose assets.
+///
+/// Layout:
+/// * `magic` - "VXPK"
+/// * `version` - u32
+/// * `count` - u32
+/// * `index` - per file: path length: u16, path: utf-8 with '/', offset: u64, stored size: u64, size: u64, hash: u64, flags: u8
+/// * `payloads` - the files, zlib compressed if the flag is set and it made them smaller.
+///
+/// The hash is `fnv1a` of the uncompressed file. All numbers are little endian.
+pub struct Archive {
+    path: PathBuf,
+    index: HashMap<String, ArchiveEntry>,
+    file: Mutex<File>,
+}
+
+impl Archive {
+    /// Only reads the index, the files are read when they are used.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = File::open(&path)?;
+
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact(&mut header).map_err(|_| invalid_data("not an asset archive"))?;
+        if header[0..4] != MAGIC {
+            return Err(invalid_data("not an asset archive"));
+        }
+
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid_data(&format!("unsupported archive version {}", version)));
+        }
+
+        let count = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let archive_size = file.metadata()?.len();
+        // the count is read before anything is allocated for it
+        if count as u64 * ENTRY_SIZE as u64 > archive_size - HEADER_SIZE as u64 {
+            return Err(invalid_data("archive index is truncated"));
+        }
+
+        let mut reader = io::BufReader::new(&mut file);
+        let mut index = HashMap::with_capacity(count as usize);
+        for _ in 0..count {
+            let path_len = u16::from_le_bytes(read_array(&mut reader)?) as usize;
+            let mut path = vec![0; path_len];
+            reader.read_exact(&mut path)?;
+            let path = String::from_utf8(path).map_err(|_| invalid_data("archive path is not utf-8"))?;
+
+            let entry = ArchiveEntry {
+                offset: u64::from_le_bytes(read_array(&mut reader)?),
+                stored_size: u64::from_le_bytes(read_array(&mut reader)?),
+                size: u64::from_le_bytes(read_array(&mut reader)?),
+                hash: u64::from_le_bytes(read_array(&mut reader)?),
+                compressed: read_array::<1>(&mut reader)?[0] & FLAG_COMPRESSED != 0,
+            };
+
+            if entry.offset.checked_add(entry.stored_size).is_none_or(|end| end > archive_size) {
+                return Err(invalid_data(&format!("{} is out of bounds", path)));
+            }
+            let max_size = match entry.compressed {
+                true => entry.stored_size.saturating_mul(MAX_COMPRESSION),
+                false => entry.stored_size,
+            };
+            if entry.size > max_size || (!entry.compressed && entry.size != entry.stored_size) {
+                return Err(invalid_data(&format!("{} has an invalid size", path)));
+            }
+            index.insert(path, entry);
+        }
+
+        Ok(Self { path, index, file: Mutex::new(file) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.index.contains_key(path)
+    }
+
+    /// Paths of every file, in no order.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(String::as_str)
+    }
+
+    /// None if the file is not in the archive, an error if it does not match its hash.
+    pub fn read(&self, path: &str) -> Option<io::Result<Vec<u8>>> {
+        let entry = self.index.get(path)?;
+        Some(self.read_entry(path, entry))
+    }
+
+    fn read_entry(&self, path: &str, entry: &ArchiveEntry) -> io::Result<Vec<u8>> {
+        let mut stored = vec![0; entry.stored_size as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(entry.offset))?;
+            file.read_exact(&mut stored)?;
+        }
+
+        let data = match entry.compressed {
+            true => {
+                let mut data = Vec::with_capacity(entry.size as usize);
+                // one byte more than the size is enough to know it is corrupted
+                ZlibDecoder::new(stored.as_slice()).take(entry.size + 1).read_to_end(&mut data)?;
+                data
+            }
+            false => stored,
+        };
+
+        if data.len() as u64 != entry.size || fnv1a(&data) != entry.hash {
+            return Err(invalid_data(&format!("{} in {} is corrupted", path, self.path.display())));
+        }
+        Ok(data)
+    }
+}
+
+/// Collects files and writes them as an `Archive`.
+pub struct ArchiveBuilder {
+    files: Vec<(String, Vec<u8>)>,
+    compress: bool,
+}
+
+impl ArchiveBuilder {
+    pub fn new(compress: bool) -> Self {
+        Self { files: vec![], compress }
+    }
+
+    /// A file added twice keeps the last data.
+    pub fn add(&mut self, path: &str, data: Vec<u8>) {
+        let path = normalize(path);
+        self.files.retain(|(file, _)| *file != path);
+        self.files.push((path, data));
+    }
+
+    /// Adds every file under `dir`, their path is `prefix` followed by the path relative to `dir`.
+    pub fn add_directory(&mut self, dir: impl AsRef<Path>, prefix: &str) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut files = vec![];
+        collect_files(dir, &mut files)?;
+        // same archive for the same directory
+        files.sort();
+
+        for file in files {
+            let relative = file.strip_prefix(dir).unwrap();
+            let relative = relative.to_str().ok_or_else(|| invalid_data(&format!("{} is not utf-8", relative.display())))?;
+            self.add(&format!("{}{}", prefix, relative), fs::read(&file)?);
+        }
+        Ok(())
+    }
+
+    /// Written next to it and renamed, like the region files.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+
+        let mut index = vec![];
+        let mut payloads = vec![];
+        let mut entries = Vec::with_capacity(self.files.len());
+        for (file, data) in &self.files {
+            let (stored, compressed) = self.encode(data)?;
+            entries.push((file, payloads.len() as u64, stored.len() as u64, data.len() as u64, fnv1a(data), compressed));
+            payloads.extend_from_slice(&stored);
+        }
+
+        let index_size: usize = self.files.iter().map(|(file, _)| ENTRY_SIZE + file.len()).sum();
+        let payload_start = (HEADER_SIZE + index_size) as u64;
+
+        index.extend_from_slice(&MAGIC);
+        index.extend_from_slice(&VERSION.to_le_bytes());
+        index.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for (file, offset, stored_size, size, hash, compressed) in entries {
+            let file_len = u16::try_from(file.len()).map_err(|_| invalid_data(&format!("{} is too long", file)))?;
+            index.extend_from_slice(&file_len.to_le_bytes());
+            index.extend_from_slice(file.as_bytes());
+            index.extend_from_slice(&(payload_start + offset).to_le_bytes());
+            index.extend_from_slice(&stored_size.to_le_bytes());
+            index.extend_from_slice(&size.to_le_bytes());
+            index.extend_from_slice(&hash.to_le_bytes());
+            index.push(if compressed { FLAG_COMPRESSED } else { 0 });
+        }
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&index)?;
+        file.write_all(&payloads)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Stored as is when compressing does not make it smaller, e.g. png files.
+    fn encode(&self, data: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        if !self.compress {
+            return Ok((data.to_vec(), false));
+        }
+
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        Ok(match compressed.len() < data.len() {
+            true => (compressed, true),
+            false => (data.to_vec(), false),
+        })
+    }
+}
+
+/// Packs every file under `dir` into an archive, with the paths relative to `dir`.
+pub fn pack_directory(dir: impl AsRef<Path>, archive: impl AsRef<Path>, compress: bool) -> io::Result<()> {
+    let mut builder = ArchiveBuilder::new(compress);
+    builder.add_directory(dir, "")?;
+    builder.write(archive)
+}
+
+/// Archive paths use '/' and are relative, "./assets\\a.png" is "assets/a.png".
+pub fn normalize(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let mut path = path.as_str();
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+    path.to_owned()
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes).map_err(|_| invalid_data("archive index is truncated"))?;
+    Ok(bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("voxelengine-archive-{}-{}.vxpk", name, std::process::id()))
+    }
+
+    fn build(name: &str, compress: bool) -> PathBuf {
+        let mut builder = ArchiveBuilder::new(compress);
+        builder.add("./textures\\a.txt", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec());
+        builder.add("b.bin", vec![1, 2, 3]);
+        builder.add("b.bin", vec![4, 5]);
+
+        let path = temp_path(name);
+        builder.write(&path).unwrap();
+        path
+    }
+
+    /// Overwrites the bytes at `at` and opens the archive.
+    fn open_patched(name: &str, at: usize, bytes: &[u8]) -> io::Result<Archive> {
+        let path = build(name, false);
+        let mut data = fs::read(&path).unwrap();
+        data[at..at + bytes.len()].copy_from_slice(bytes);
+        fs::write(&path, data).unwrap();
+        Archive::open(&path)
+    }
+
+    /// Offset of the first index entry after its path, the path is "textures/a.txt".
+    const FIRST_OFFSET: usize = HEADER_SIZE + 2 + 14;
+
+    #[test]
+    fn files_are_read_back() {
+        for compress in [false, true] {
+            let archive = Archive::open(build(&format!("roundtrip-{}", compress), compress)).unwrap();
+
+            let mut files: Vec<&str> = archive.files().collect();
+            files.sort();
+            assert_eq!(files, ["b.bin", "textures/a.txt"]);
+            assert_eq!(archive.read("textures/a.txt").unwrap().unwrap(), [b'a'; 32]);
+            assert_eq!(archive.read("b.bin").unwrap().unwrap(), [4, 5]);
+            assert!(archive.read("c.bin").is_none());
+        }
+    }
+
+    #[test]
+    fn huge_counts_are_rejected() {
+        let error = open_patched("count", 8, &u32::MAX.to_le_bytes()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn entries_out_of_bounds_are_rejected() {
+        let offset = open_patched("offset", FIRST_OFFSET, &u64::MAX.to_le_bytes()).err().unwrap();
+        assert_eq!(offset.kind(), io::ErrorKind::InvalidData);
+
+        let stored_size = open_patched("stored-size", FIRST_OFFSET + 8, &(u64::MAX - 100).to_le_bytes()).err().unwrap();
+        assert_eq!(stored_size.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sizes_larger_than_the_payload_are_rejected() {
+        let error = open_patched("size", FIRST_OFFSET + 16, &u64::MAX.to_le_bytes()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupted_files_fail_their_hash() {
+        let path = build("hash", true);
+        let archive = Archive::open(&path).unwrap();
+        let entry = archive.index["b.bin"].clone();
+        drop(archive);
+
+        let mut data = fs::read(&path).unwrap();
+        data[entry.offset as usize] ^= 1;
+        fs::write(&path, data).unwrap();
+
+        let error = Archive::open(&path).unwrap().read("b.bin").unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
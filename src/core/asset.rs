@@ -1,202 +1,817 @@
-use std::{any::Any, borrow::{Borrow, BorrowMut}, cell::UnsafeCell, collections::HashMap, mem::ManuallyDrop, sync::{mpsc::{self, channel, Receiver, Sender}, Arc, Mutex, RwLock}};
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    fmt, fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
 
 use lazy_static::lazy_static;
-use tasc::{sync::SyncHandle, StdSignal};
 
-pub trait Loader{
-    type T: Clone + 'static;
-    fn load(file_str: &str) -> Box<dyn Any>;
-    fn save(file_str: &str, any: Box<dyn Any>);
-    fn reinterpret_bytes(any: Box<dyn Any>) -> Box<Self::T>;
+use crate::t_thread::{Stage, ThreadPool};
+
+use super::vfs::Vfs;
+
+/// Reads one kind of asset from disk.
+pub trait Loader: 'static {
+    type T: Send + Sync + 'static;
+
+    fn load(file_str: &str) -> Result<Self::T, AssetError>;
+
+    fn save(file_str: &str, asset: &Self::T) -> Result<(), AssetError> {
+        let _ = (file_str, asset);
+        Err(AssetError::Unsupported)
+    }
+
     fn get_full_path(file_str: &str) -> String;
-        
 }
 
+#[derive(Clone, Debug)]
+pub enum AssetError {
+    Io(Arc<io::Error>),
+    /// The file was read, but its content is not valid for the loader.
+    Invalid(String),
+    /// The loader panicked, the panic message.
+    Panicked(String),
+    /// The same path is already loaded as another type.
+    TypeMismatch,
+    Unsupported,
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::Io(e) => write!(f, "io error: {}", e),
+            AssetError::Invalid(message) => write!(f, "invalid asset: {}", message),
+            AssetError::Panicked(message) => write!(f, "loader panicked: {}", message),
+            AssetError::TypeMismatch => write!(f, "asset is already loaded as another type"),
+            AssetError::Unsupported => write!(f, "not supported by the loader"),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
 
-struct Queue{
-    sender_save: Arc<Mutex<Sender<(String, u32, usize)>>>,
-    reciever_save: Receiver<(String, u32, usize)>
+impl From<io::Error> for AssetError {
+    fn from(e: io::Error) -> Self {
+        AssetError::Io(Arc::new(e))
+    }
+}
 
+pub enum AssetState<T> {
+    Loading,
+    Ready(Arc<T>),
+    Failed(AssetError),
 }
-impl Queue {
-    pub fn get_sender(&self) -> Arc<Mutex<Sender<(String, u32, usize)>>> {
-        todo!()
+
+impl<T> Clone for AssetState<T> {
+    fn clone(&self) -> Self {
+        match self {
+            AssetState::Loading => AssetState::Loading,
+            AssetState::Ready(value) => AssetState::Ready(value.clone()),
+            AssetState::Failed(e) => AssetState::Failed(e.clone()),
+        }
     }
 }
-#[derive(Default)]
-struct ZeroMarker;
 
-struct Asset{
-    data: Arc<RwLock<Box<dyn Any>>>,
+/// Shared by every handle of the same file.
+struct Entry<T> {
+    path: String,
+    state: Mutex<AssetState<T>>,
+    changed: Condvar,
+    mutation: Arc<Mutation>,
 }
 
-impl Asset {
+/// Changes made through `Handle::set`, and how many of them are written to disk.
+#[derive(Default)]
+struct Mutation {
+    edits: AtomicU64,
+    flushed: AtomicU64,
 }
 
-impl Default for Asset {
-    fn default() -> Self {
-        Self { data: Arc::new(RwLock::new(Box::new(ZeroMarker::default()))) }
+impl<T> Entry<T> {
+    fn set_state(&self, state: AssetState<T>) {
+        *self.state.lock().unwrap() = state;
+        self.changed.notify_all();
     }
 }
 
-lazy_static! {
-    static ref ASSET_LOADER: AssetLoader = {
-        AssetLoader::new()
-    };
+impl<T> Drop for Entry<T> {
+    /// The last handle is gone, the asset is unloaded.
+    fn drop(&mut self) {
+        let mut cache = ASSET_LOADER.cache.lock().unwrap();
+        // the path could already be loaded again by a new entry
+        if cache.get(&self.path).is_some_and(|weak| weak.strong_count() == 0) {
+            cache.remove(&self.path);
+            ASSET_LOADER.loaded.lock().unwrap().remove(&self.path);
+        }
+    }
+}
+
+/// Sent to the subscribers after an asset was read again.
+#[derive(Clone, Debug)]
+pub struct AssetEvent {
+    /// Full path of the asset, as returned by `Loader::get_full_path`.
+    pub path: String,
+    /// On an error the handles keep the old value.
+    pub result: Result<(), AssetError>,
+}
+
+/// Reads a loaded asset again, with the loader it was loaded with.
+type Reloader = fn(&str) -> Result<(), AssetError>;
+
+/// What is known about a loaded asset without its type.
+struct Loaded {
+    reload: Reloader,
+    mutation: Arc<Mutation>,
 }
 
+/// Writes the file to the temp path given as the second argument, the first one is the path of the file.
+type Write = Box<dyn FnOnce(&str, &str) -> Result<(), AssetError> + Send>;
+
+struct Save {
+    write: Write,
+    /// Edits of the cached asset that are on disk once this is written.
+    flushes: Option<(Arc<Mutation>, u64)>,
+}
+
+/// Stage of every save job, see `AssetLoader::wait_for_saves`.
+const SAVE_STAGE: Stage = Stage::new("asset save");
+
+/// Typed reference to a loaded asset, the asset stays loaded while a handle exists.
+pub struct Handle<T> {
+    entry: Arc<Entry<T>>,
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self { entry: self.entry.clone() }
+    }
+}
+
+impl<T: Send + Sync + 'static> Handle<T> {
+    fn failed(path: String, error: AssetError) -> Self {
+        let state = Mutex::new(AssetState::Failed(error));
+        Self { entry: Arc::new(Entry { path, state, changed: Condvar::new(), mutation: Default::default() }) }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.entry.path
+    }
+
+    pub fn state(&self) -> AssetState<T> {
+        self.entry.state.lock().unwrap().clone()
+    }
+
+    pub fn is_loading(&self) -> bool {
+        matches!(*self.entry.state.lock().unwrap(), AssetState::Loading)
+    }
+
+    /// None while it is loading or if it failed.
+    pub fn try_get(&self) -> Option<Arc<T>> {
+        match &*self.entry.state.lock().unwrap() {
+            AssetState::Ready(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
 
+    /// Waits until the load finished.
+    pub fn wait(&self) -> Result<Arc<T>, AssetError> {
+        let state = self.entry.state.lock().unwrap();
+        let state = self.entry.changed.wait_while(state, |state| matches!(state, AssetState::Loading)).unwrap();
 
+        match &*state {
+            AssetState::Ready(value) => Ok(value.clone()),
+            AssetState::Failed(e) => Err(e.clone()),
+            AssetState::Loading => unreachable!(),
+        }
+    }
+
+    /// Replaces the asset in memory, it stays mutated until it is saved.
+    pub fn set(&self, value: T) {
+        self.entry.mutation.edits.fetch_add(1, Ordering::AcqRel);
+        self.entry.set_state(AssetState::Ready(Arc::new(value)));
+    }
 
+    /// Changed with `set` after the last save that was written.
+    pub fn is_mutated(&self) -> bool {
+        let mutation = &self.entry.mutation;
+        mutation.flushed.load(Ordering::Acquire) < mutation.edits.load(Ordering::Acquire)
+    }
 
-pub struct AssetLoader{
-    cache: RwLock<HashMap<String, Asset>>, 
-    is_mutated: RwLock<HashMap<String, bool>>,
-    load_func: RwLock<HashMap<String, SyncHandle<()>>>,
+    /// Handles that point to the same asset.
+    pub fn ptr_eq(&self, other: &Handle<T>) -> bool {
+        Arc::ptr_eq(&self.entry, &other.entry)
+    }
 }
 
-unsafe impl Sync for AssetLoader {
-    
+lazy_static! {
+    static ref ASSET_LOADER: AssetLoader = AssetLoader::new();
 }
 
+/// Loads assets on the thread pool and keeps them cached while they are used.
+pub struct AssetLoader {
+    /// Full path to the entry, the entry is a `Entry<Loader::T>`.
+    cache: Mutex<HashMap<String, Weak<dyn Any + Send + Sync>>>,
+    /// Full path of every loaded asset to how it is reloaded.
+    loaded: Mutex<HashMap<String, Loaded>>,
+    subscribers: Mutex<Vec<mpsc::Sender<AssetEvent>>>,
+    /// Saves that are not written yet, a path is only in here while a job writes its saves.
+    saves: Mutex<HashMap<String, VecDeque<Save>>>,
+}
 
 impl AssetLoader {
-    fn new() -> Self{
+    fn new() -> Self {
+        Self {
+            cache: Mutex::new(HashMap::new()),
+            loaded: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(vec![]),
+            saves: Mutex::new(HashMap::new()),
+        }
+    }
 
-        Self { cache: RwLock::new(HashMap::new()), is_mutated: RwLock::new(HashMap::new()), load_func: RwLock::new(HashMap::new()) }
+    /// Receives an event every time an asset is reloaded, e.g. to rebuild the texture array.
+    pub fn subscribe() -> mpsc::Receiver<AssetEvent> {
+        let (sender, receiver) = mpsc::channel();
+        ASSET_LOADER.subscribers.lock().unwrap().push(sender);
+        receiver
     }
 
-    pub fn save(file_name:&str, element_offset: u32, element_size: usize, data: Vec<u8>){
-        
+    fn notify(event: AssetEvent) {
+        // receivers that are gone are dropped
+        ASSET_LOADER.subscribers.lock().unwrap().retain(|sender| sender.send(event.clone()).is_ok());
     }
 
+    /// Replaces the elements from `element_offset` on with `data` in the file at the full path, the file grows if needed.
+    /// Written on the thread pool, saves of the same file are written in order.
+    pub fn save(file_name: &str, element_offset: u32, element_size: usize, data: Vec<u8>) {
+        if element_size == 0 || !data.len().is_multiple_of(element_size) {
+            log::error!("Failed to save asset {}: {} bytes are not a multiple of the element size {}", file_name, data.len(), element_size);
+            return;
+        }
+
+        let start = element_offset as usize * element_size;
+        Self::queue_save(file_name.to_owned(), Box::new(move |path, temp| Self::patch(path, temp, start, &data)));
+    }
 
-    pub fn get<loader: Loader>(file_name: &str) -> loader::T{
-        let file_name = loader::get_full_path(file_name);
-        if Self::is_loading(&file_name){
-            let test;
-          if let Some(value) = ASSET_LOADER.load_func.write().unwrap().remove(&file_name){
-             test = value;
-          }
-          else{
-            panic!("it is loading, but there is no join function");
-          }
+    /// Writes the asset as it is in memory with `Loader::save`, on the thread pool.
+    pub fn save_asset<L: Loader>(file_name: &str) {
+        let file_name = L::get_full_path(file_name);
 
-           test.wait();      
+        let value = match Self::cached::<L::T>(&file_name).map(|handle| handle.map(|handle| handle.try_get())) {
+            Some(Ok(Some(value))) => value,
+            Some(Err(e)) => return log::error!("Failed to save asset {}: {}", file_name, e),
+            _ => return log::error!("Failed to save asset {}: it is not loaded", file_name),
+        };
+
+        Self::queue_save(file_name, Box::new(move |_, temp| L::save(temp, &value)));
+    }
+
+    /// Waits until every save is written.
+    pub fn wait_for_saves() {
+        ThreadPool::wait_on_stage(SAVE_STAGE);
+    }
+
+    fn queue_save(file_name: String, write: Write) {
+        // everything edited so far is on disk once this save is written
+        let flushes = ASSET_LOADER.loaded.lock().unwrap().get(&file_name).map(|loaded| {
+            let mutation = loaded.mutation.clone();
+            let edits = mutation.edits.load(Ordering::Acquire);
+            (mutation, edits)
+        });
+
+        let mut saves = ASSET_LOADER.saves.lock().unwrap();
+        if let Some(queue) = saves.get_mut(&file_name) {
+            queue.push_back(Save { write, flushes });
+            return;
         }
-        else{
-            // load it and wait for it
-            Self::load::<loader>(&file_name, true);
+
+        saves.insert(file_name.clone(), VecDeque::from([Save { write, flushes }]));
+        let _ = ThreadPool::execute_stage(move || Self::write_saves(file_name), SAVE_STAGE);
+    }
+
+    fn write_saves(file_name: String) {
+        loop {
+            let save = {
+                let mut saves = ASSET_LOADER.saves.lock().unwrap();
+                match saves.get_mut(&file_name).and_then(VecDeque::pop_front) {
+                    Some(save) => save,
+                    None => {
+                        saves.remove(&file_name);
+                        return;
+                    }
+                }
+            };
+
+            match Self::write_save(&file_name, save.write) {
+                Ok(()) => {
+                    if let Some((mutation, edits)) = save.flushes {
+                        mutation.flushed.fetch_max(edits, Ordering::AcqRel);
+                    }
+                }
+                Err(e) => log::error!("Failed to save asset {}: {}", file_name, e),
+            }
         }
+    }
 
-        let cache = ASSET_LOADER.cache.read().unwrap();
+    /// Writes a temp file next to the file and renames it, the file is never half written.
+    fn write_save(file_name: &str, write: Write) -> Result<(), AssetError> {
+        let temp = format!("{}.tmp", file_name);
 
-        // Get the entry from the cache
-        if let Some(entry) = cache.get(&file_name) {
-            // Lock the data for reading
-            let data = entry.data.read().unwrap();
+        let result = Self::catch_panic(|| write(file_name, &temp))
+            .and_then(|()| Ok(fs::File::open(&temp)?.sync_all()?))
+            .and_then(|()| Ok(fs::rename(&temp, file_name)?));
 
-            // Attempt to downcast to Box<loader::T>
-            return data.downcast_ref::<loader::T>().unwrap().clone();
-            
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
         }
-        panic!("the loaded value does not exist ?????");
+        result
     }
-    
-    pub fn hot_reload<loader:Loader>(file_name: &str, wait_on: bool){
-        // just reload from file, no saving done
-        let file_name = loader::get_full_path(file_name);
-        Self::load::<loader>(&file_name, wait_on);
+
+    fn patch(file_name: &str, temp: &str, start: usize, data: &[u8]) -> Result<(), AssetError> {
+        let mut bytes = match fs::read(file_name) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        let end = start + data.len();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(data);
+
+        Ok(fs::write(temp, bytes)?)
     }
 
-    pub fn load_resource<loader:Loader>(file_name: &str){
-        let file_name = loader::get_full_path(file_name);
-        Self::load::<loader>(&file_name, false);
+    /// Reads a file for a `Loader`, from the mounted archives or the loose files.
+    pub fn read(file_name: &str) -> Result<Vec<u8>, AssetError> {
+        Ok(Vfs::read(file_name)?)
     }
 
-    fn load<loader:Loader>(file_name: &str, wait_on: bool){
+    /// Loads the asset and waits for it.
+    pub fn get<L: Loader>(file_name: &str) -> Result<Arc<L::T>, AssetError> {
+        Self::load::<L>(file_name).wait()
+    }
 
+    /// Only returns the asset if it is already loaded, never waits or starts a load.
+    pub fn try_get<L: Loader>(file_name: &str) -> Option<Arc<L::T>> {
+        let file_name = L::get_full_path(file_name);
+        Self::cached::<L::T>(&file_name)?.ok()?.try_get()
+    }
 
-        if Self::is_loading(&file_name){
-            return;
+    /// Reads the file again, the handles keep the old value until the new one is loaded.
+    /// With `wait_on` the error of the reload is returned, the handles keep the old value then.
+    pub fn hot_reload<L: Loader>(file_name: &str, wait_on: bool) -> Result<Handle<L::T>, AssetError> {
+        let file_name = L::get_full_path(file_name);
+
+        let handle = match Self::cached::<L::T>(&file_name) {
+            Some(handle) => handle?,
+            None => return Ok(Self::load_full_path::<L>(file_name)),
+        };
+
+        let job = ThreadPool::execute(move || Self::reload_full_path::<L>(&file_name));
+
+        if wait_on {
+            job.join()?;
         }
-        else{
-            Self::set_is_loading(&file_name);
+        Ok(handle)
+    }
+
+    /// Swaps in the new value once it is loaded, the old one stays if it fails.
+    fn reload_full_path<L: Loader>(file_name: &str) -> Result<(), AssetError> {
+        let handle = match Self::cached::<L::T>(file_name) {
+            Some(handle) => handle?,
+            // unloaded in the meantime
+            None => return Ok(()),
+        };
+
+        let result = Self::run_loader::<L>(file_name).map(|value| handle.entry.set_state(AssetState::Ready(Arc::new(value))));
+        if let Err(e) = &result {
+            log::error!("Failed to reload asset {}: {}", file_name, e);
         }
 
-        Self::set_default_cache_value(&file_name);
+        Self::notify(AssetEvent { path: file_name.to_owned(), result: result.clone() });
+        result
+    }
+
+    /// Starts loading the asset, the handle is ready once it is loaded.
+    /// The asset is only loaded once, the next call returns the same asset.
+    pub fn load_resource<L: Loader>(file_name: &str) -> Handle<L::T> {
+        Self::load::<L>(file_name)
+    }
+
+    pub fn load<L: Loader>(file_name: &str) -> Handle<L::T> {
+        Self::load_full_path::<L>(L::get_full_path(file_name))
+    }
 
-        let file_name2 = file_name.to_owned();
-        let wait_on2 = wait_on.clone();
+    fn load_full_path<L: Loader>(file_name: String) -> Handle<L::T> {
+        let entry = {
+            let mut cache = ASSET_LOADER.cache.lock().unwrap();
 
-        let test: SyncHandle<()> = tasc::sync::task(move ||{
-            let file_name = file_name2;
-            let wait_on = wait_on2;
-            let mut box_any =  ManuallyDrop::new(loader::load(&file_name));
+            if let Some(cached) = cache.get(&file_name).and_then(Weak::upgrade) {
+                drop(cache);
+                return match cached.downcast::<Entry<L::T>>() {
+                    Ok(entry) => Handle { entry },
+                    Err(_) => Handle::failed(file_name, AssetError::TypeMismatch),
+                };
+            }
 
-            // need to send a complete job
-            AssetLoader::load_complete(file_name, box_any, wait_on);
+            let mutation = Arc::new(Mutation::default());
+            let loaded = Loaded { reload: Self::reload_full_path::<L>, mutation: mutation.clone() };
+
+            let state = Mutex::new(AssetState::Loading);
+            let entry = Arc::new(Entry { path: file_name.clone(), state, changed: Condvar::new(), mutation });
+            let weak: Weak<dyn Any + Send + Sync> = Arc::downgrade(&entry) as Weak<dyn Any + Send + Sync>;
+            cache.insert(file_name.clone(), weak);
+            ASSET_LOADER.loaded.lock().unwrap().insert(file_name, loaded);
+            entry
+        };
+
+        let job_entry = entry.clone();
+        let _ = ThreadPool::execute(move || {
+            let state = match Self::run_loader::<L>(&job_entry.path) {
+                Ok(value) => AssetState::Ready(Arc::new(value)),
+                Err(e) => {
+                    log::error!("Failed to load asset {}: {}", job_entry.path, e);
+                    AssetState::Failed(e)
+                }
+            };
+            job_entry.set_state(state);
         });
 
-        if wait_on{
-            test.wait();
+        Handle { entry }
+    }
+
+    /// A panicking loader fails the asset instead of the job.
+    fn run_loader<L: Loader>(file_name: &str) -> Result<L::T, AssetError> {
+        Self::catch_panic(|| L::load(file_name))
+    }
+
+    fn catch_panic<T>(f: impl FnOnce() -> Result<T, AssetError>) -> Result<T, AssetError> {
+        panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(AssetError::Panicked(message))
+        })
+    }
+
+    /// None if it is not loaded.
+    fn cached<T: Send + Sync + 'static>(file_name: &str) -> Option<Result<Handle<T>, AssetError>> {
+        let cached = ASSET_LOADER.cache.lock().unwrap().get(file_name).and_then(Weak::upgrade)?;
+
+        Some(cached.downcast::<Entry<T>>().map(|entry| Handle { entry }).map_err(|_| AssetError::TypeMismatch))
+    }
+
+    /// Loaded or loading, false once every handle is dropped.
+    pub fn is_loaded<L: Loader>(file_name: &str) -> bool {
+        let file_name = L::get_full_path(file_name);
+        ASSET_LOADER.cache.lock().unwrap().get(&file_name).is_some_and(|weak| weak.strong_count() > 0)
+    }
+}
+
+/// Polls the modification time of every file under the roots and reloads the loaded assets that changed.
+/// Polling keeps it free of platform dependencies.
+///
+/// ```ignore
+/// let _watcher = AssetWatcher::new([SHADER_FOLDER, TEXTURE_FOLDER]).spawn(Duration::from_millis(500));
+/// let events = AssetLoader::subscribe();
+/// ```
+pub struct AssetWatcher {
+    roots: Vec<PathBuf>,
+    /// Modification time and size, a write within the same mtime tick still changes the size most of the time.
+    files: HashMap<PathBuf, (SystemTime, u64)>,
+    scanned: bool,
+}
+
+impl AssetWatcher {
+    pub fn new<P: Into<PathBuf>>(roots: impl IntoIterator<Item = P>) -> Self {
+        Self { roots: roots.into_iter().map(Into::into).collect(), files: HashMap::new(), scanned: false }
+    }
+
+    fn scan(dir: &Path, files: &mut HashMap<PathBuf, (SystemTime, u64)>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+
+            if metadata.is_dir() {
+                Self::scan(&path, files);
+            } else if let Ok(modified) = metadata.modified() {
+                files.insert(path, (modified, metadata.len()));
+            }
         }
-        else{
-            ASSET_LOADER.load_func.write().unwrap().insert(file_name.to_owned(), test);
+    }
+
+    /// Reloads the loaded assets whose file changed since the last poll, the first poll only looks at the files.
+    /// Returns the events that were sent.
+    pub fn poll(&mut self) -> Vec<AssetEvent> {
+        let mut files = HashMap::new();
+        for root in &self.roots {
+            Self::scan(root, &mut files);
+        }
+
+        let changed: Vec<PathBuf> = match self.scanned {
+            true => files.iter().filter(|(path, state)| self.files.get(*path) != Some(state)).map(|(path, _)| path.clone()).collect(),
+            false => vec![],
+        };
+        self.files = files;
+        self.scanned = true;
+
+        if changed.is_empty() {
+            return vec![];
         }
+
+        // the asset paths are relative to the working directory, the scanned ones to the roots
+        let reloaders: Vec<(String, Reloader)> = ASSET_LOADER.loaded.lock().unwrap().iter().map(|(path, loaded)| (path.clone(), loaded.reload)).collect();
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+
+        let mut events = vec![];
+        for path in changed {
+            let path = canonical(&path);
+
+            for (asset_path, reload) in &reloaders {
+                if canonical(Path::new(asset_path)) == path {
+                    events.push(AssetEvent { path: asset_path.clone(), result: reload(asset_path) });
+                }
+            }
+        }
+        events
+    }
+
+    /// Polls on its own thread until the returned handle is dropped.
+    pub fn spawn(mut self, interval: Duration) -> WatcherThread {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        let thread = thread::Builder::new()
+            .name("asset watcher".to_owned())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    self.poll();
+                    thread::park_timeout(interval);
+                }
+            })
+            .unwrap();
+
+        WatcherThread { stop, thread: Some(thread) }
     }
+}
+
+/// Stops the watcher when dropped.
+pub struct WatcherThread {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for WatcherThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
 
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
 
-    fn load_complete(file_str: String, box_any: ManuallyDrop<Box<dyn Any>>, wait_on: bool){
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let cache_read = ASSET_LOADER.cache.read().unwrap();
-        let asset = cache_read.get(&file_str).unwrap();
-    
-        unsafe{
-            *asset.data.write().unwrap() = ManuallyDrop::into_inner(box_any);
+    /// Parses the file as a number, counts every read.
+    struct Number;
 
-            if !wait_on{
-                ASSET_LOADER.load_func.write().unwrap().remove(&file_str);
+    static READS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+    impl Loader for Number {
+        type T = u32;
+
+        fn load(file_str: &str) -> Result<u32, AssetError> {
+            READS.lock().unwrap().push(file_str.to_owned());
+            let text = fs::read_to_string(file_str)?;
+            if text == "panic" {
+                panic!("bad number");
             }
+            text.parse().map_err(|_| AssetError::Invalid(text))
+        }
+
+        fn save(file_str: &str, asset: &u32) -> Result<(), AssetError> {
+            Ok(fs::write(file_str, asset.to_string())?)
+        }
+
+        fn get_full_path(file_str: &str) -> String {
+            file_str.to_owned()
         }
-        
     }
 
-    fn is_loading(file_str: &str) -> bool{
-        let mut is_mutated_read = ASSET_LOADER.is_mutated.read().unwrap();
-        
-        let mut is_mutated = is_mutated_read.get(file_str);
+    struct Text;
+
+    impl Loader for Text {
+        type T = String;
 
-        let mut in_progress = &false;
+        fn load(file_str: &str) -> Result<String, AssetError> {
+            Ok(fs::read_to_string(file_str)?)
+        }
 
-        if is_mutated.is_some(){
-            in_progress = is_mutated.unwrap();
+        fn get_full_path(file_str: &str) -> String {
+            file_str.to_owned()
         }
-        *in_progress
     }
 
-    fn set_is_loading(file_str: &str){
-        let mut is_mutated_read = ASSET_LOADER.is_mutated.write().unwrap();
-        is_mutated_read.insert(file_str.to_owned(), true);
+    fn temp_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("voxelengine-asset-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn reads(path: &str) -> usize {
+        READS.lock().unwrap().iter().filter(|read| *read == path).count()
     }
 
-    fn set_default_cache_value(file_str: &str){
-        let mut is_mutated_write = ASSET_LOADER.is_mutated.read().unwrap();
-        {
-            let mut data =  ASSET_LOADER.cache.write().unwrap();
-            data.insert(file_str.to_owned(),Asset::default());
+    /// The load job keeps the entry alive until it finished.
+    fn wait_unloaded(path: &str) {
+        for _ in 0..1000 {
+            if !AssetLoader::is_loaded::<Number>(path) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
         }
+        panic!("{} is still loaded", path);
+    }
+
+    #[test]
+    fn loaded_assets_are_cached() {
+        let path = temp_file("cached", "7");
+
+        let handle = AssetLoader::load::<Number>(&path);
+        assert_eq!(*handle.wait().unwrap(), 7);
 
+        let again = AssetLoader::load::<Number>(&path);
+        assert!(handle.ptr_eq(&again));
+        assert_eq!(*AssetLoader::get::<Number>(&path).unwrap(), 7);
+        assert_eq!(AssetLoader::try_get::<Number>(&path).as_deref(), Some(&7));
+        assert_eq!(reads(&path), 1);
+
+        // the same path can't be loaded as another type
+        assert!(matches!(AssetLoader::load::<Text>(&path).wait(), Err(AssetError::TypeMismatch)));
     }
 
-    
-}
+    #[test]
+    fn the_last_handle_unloads_the_asset() {
+        let path = temp_file("unload", "1");
+        assert!(!AssetLoader::is_loaded::<Number>(&path));
+        assert!(AssetLoader::try_get::<Number>(&path).is_none());
+
+        let handle = AssetLoader::load::<Number>(&path);
+        let clone = handle.clone();
+        handle.wait().unwrap();
+        drop(handle);
+        assert!(AssetLoader::is_loaded::<Number>(&path));
+        assert_eq!(*clone.try_get().unwrap(), 1);
+
+        drop(clone);
+        wait_unloaded(&path);
+
+        // loading it again reads the file again
+        fs::write(&path, "2").unwrap();
+        assert_eq!(*AssetLoader::get::<Number>(&path).unwrap(), 2);
+        assert_eq!(reads(&path), 2);
+    }
 
+    #[test]
+    fn states_follow_the_load() {
+        let path = temp_file("states", "3");
+        let handle = AssetLoader::load::<Number>(&path);
+        assert!(matches!(handle.state(), AssetState::Loading | AssetState::Ready(_)));
+        handle.wait().unwrap();
+        assert!(!handle.is_loading());
+        assert!(matches!(handle.state(), AssetState::Ready(value) if *value == 3));
+
+        let missing = AssetLoader::load::<Number>(&format!("{}-missing", path));
+        assert!(matches!(missing.wait(), Err(AssetError::Io(_))));
+        assert!(matches!(missing.state(), AssetState::Failed(_)));
+        assert!(missing.try_get().is_none());
+
+        let invalid = AssetLoader::load::<Number>(&temp_file("states-invalid", "three"));
+        assert!(matches!(invalid.wait(), Err(AssetError::Invalid(_))));
+
+        let panicking = AssetLoader::load::<Number>(&temp_file("states-panic", "panic"));
+        assert!(matches!(panicking.wait(), Err(AssetError::Panicked(message)) if message == "bad number"));
+    }
 
+    #[test]
+    fn failed_reloads_keep_the_old_value() {
+        let path = temp_file("reload", "4");
+        let handle = AssetLoader::load::<Number>(&path);
+        handle.wait().unwrap();
 
+        fs::write(&path, "5").unwrap();
+        let reloaded = AssetLoader::hot_reload::<Number>(&path, true).unwrap();
+        assert!(reloaded.ptr_eq(&handle));
+        assert_eq!(*handle.try_get().unwrap(), 5);
 
+        fs::write(&path, "five").unwrap();
+        assert!(matches!(AssetLoader::hot_reload::<Number>(&path, true), Err(AssetError::Invalid(_))));
+        assert_eq!(*handle.try_get().unwrap(), 5);
 
+        assert!(matches!(AssetLoader::hot_reload::<Text>(&path, true), Err(AssetError::TypeMismatch)));
+    }
 
+    #[test]
+    fn the_watcher_reloads_changed_files() {
+        let dir = std::env::temp_dir().join(format!("voxelengine-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("number").to_str().unwrap().to_owned();
+        fs::write(&path, "1").unwrap();
+
+        let handle = AssetLoader::load::<Number>(&path);
+        handle.wait().unwrap();
+        let events = AssetLoader::subscribe();
+        let mut watcher = AssetWatcher::new([&dir]);
+        // the first poll only looks at the files
+        assert!(watcher.poll().is_empty());
+
+        // a different size, the mtime might not have changed yet
+        fs::write(&path, "22").unwrap();
+        let polled = watcher.poll();
+        assert_eq!(polled.len(), 1);
+        assert_eq!(polled[0].path, path);
+        assert!(polled[0].result.is_ok());
+        assert!(events.try_iter().any(|event| event.path == path && event.result.is_ok()));
+        assert_eq!(*handle.try_get().unwrap(), 22);
+        assert!(watcher.poll().is_empty());
+
+        // a deleted file is not reloaded, the handles keep the value
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll().is_empty());
+        assert!(!events.try_iter().any(|event| event.path == path));
+        assert_eq!(*handle.try_get().unwrap(), 22);
+    }
 
+    #[test]
+    fn saves_patch_the_file_in_place() {
+        let path = temp_file("patch", "0123456789");
+        AssetLoader::save(&path, 2, 2, b"ab".to_vec());
+        AssetLoader::wait_for_saves();
+        assert_eq!(fs::read(&path).unwrap(), b"0123ab6789");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
 
+    #[test]
+    fn saves_past_the_end_grow_the_file() {
+        let path = temp_file("grow", "0123");
+        AssetLoader::save(&path, 3, 2, b"ab".to_vec());
+        AssetLoader::wait_for_saves();
+        assert_eq!(fs::read(&path).unwrap(), b"0123\0\0ab");
+    }
+
+    #[test]
+    fn saves_of_a_file_are_written_in_order() {
+        let path = temp_file("order", "0000");
+        AssetLoader::save(&path, 0, 1, b"aaa".to_vec());
+        AssetLoader::save(&path, 1, 1, b"b".to_vec());
+        AssetLoader::wait_for_saves();
+        assert_eq!(fs::read(&path).unwrap(), b"aba0");
+    }
 
+    #[test]
+    fn partial_elements_are_not_saved() {
+        let path = temp_file("partial", "0123");
+        AssetLoader::save(&path, 0, 3, b"abcd".to_vec());
+        AssetLoader::save(&path, 0, 0, vec![]);
+        AssetLoader::wait_for_saves();
+        assert_eq!(fs::read(&path).unwrap(), b"0123");
+    }
+
+    #[test]
+    fn saved_assets_are_not_mutated() {
+        let path = temp_file("mutated", "1");
+        let handle = AssetLoader::load::<Number>(&path);
+        handle.wait().unwrap();
+        assert!(!handle.is_mutated());
+
+        handle.set(5);
+        assert!(handle.is_mutated());
+        AssetLoader::save_asset::<Number>(&path);
+        AssetLoader::wait_for_saves();
+        assert!(!handle.is_mutated());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "5");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+}
@@ -308,6 +308,10 @@ impl Camera {
         self.pos
     }
 
+    pub fn get_front(&self) -> glm::Vec3 {
+        self.front
+    }
+
     pub fn get_gpu_camera(&mut self) -> GPUCamera {
         self.projection = glm::projection::perspective_vk(self.fovy, self.aspect, self.near, self.far);
 
@@ -0,0 +1,4 @@
+/// FNV-1a, unlike `DefaultHasher` it stays the same between rust versions.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3))
+}
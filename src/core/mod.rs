@@ -1,2 +1,5 @@
 pub mod camera;
-pub mod asset;
\ No newline at end of file
+pub mod asset;
+pub mod archive;
+pub mod vfs;
+pub mod hash;
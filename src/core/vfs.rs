@@ -0,0 +1,118 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+
+use super::archive::{self, Archive};
+
+/// Archives next to the executable with this extension are mounted on startup.
+pub const ARCHIVE_EXTENSION: &str = "vxpak";
+
+lazy_static! {
+    static ref VFS: Vfs = Vfs::new();
+}
+
+/// Reads asset files from the mounted archives first, then from loose files.
+/// Loose files are looked up relative to the working directory and then next to the executable.
+pub struct Vfs {
+    /// Mounted last is checked first.
+    archives: RwLock<Vec<Archive>>,
+    roots: Vec<PathBuf>,
+}
+
+impl Vfs {
+    fn new() -> Self {
+        let exe_dir = env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf));
+
+        let mut archives = vec![];
+        if let Some(Ok(entries)) = exe_dir.as_ref().map(fs::read_dir) {
+            let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).filter(|path| path.extension().is_some_and(|ext| ext == ARCHIVE_EXTENSION)).collect();
+            paths.sort();
+
+            for path in paths {
+                match Archive::open(&path) {
+                    Ok(archive) => archives.push(archive),
+                    Err(e) => log::error!("Failed to mount archive {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        let mut roots = vec![PathBuf::new()];
+        roots.extend(exe_dir);
+
+        Self { archives: RwLock::new(archives), roots }
+    }
+
+    /// Files in the archive hide the loose files and the archives mounted before it.
+    pub fn mount(path: impl Into<PathBuf>) -> io::Result<()> {
+        let archive = Archive::open(path)?;
+        VFS.archives.write().unwrap().push(archive);
+        Ok(())
+    }
+
+    /// Removes the archive that was mounted from `path`, false if there is none.
+    pub fn unmount(path: impl AsRef<Path>) -> bool {
+        let mut archives = VFS.archives.write().unwrap();
+        let count = archives.len();
+        archives.retain(|archive| archive.path() != path.as_ref());
+        archives.len() != count
+    }
+
+    /// A file in an archive that does not match its hash is an error, it does not fall back to the loose file.
+    pub fn read(path: &str) -> io::Result<Vec<u8>> {
+        let archive_path = archive::normalize(path);
+        for archive in VFS.archives.read().unwrap().iter().rev() {
+            if let Some(data) = archive.read(&archive_path) {
+                return data;
+            }
+        }
+
+        Self::read_loose(path)
+    }
+
+    fn read_loose(path: &str) -> io::Result<Vec<u8>> {
+        let mut not_found = None;
+        for root in &VFS.roots {
+            match fs::read(root.join(path)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => not_found = not_found.or(Some(e)),
+                result => return result,
+            }
+        }
+        Err(not_found.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+    }
+
+    /// Paths of the files directly inside `dir`, from the archives and the loose files, sorted.
+    pub fn files_in(dir: &str) -> Vec<String> {
+        let mut prefix = archive::normalize(dir);
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        let mut files: Vec<String> = VFS
+            .archives
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|archive| archive.files().filter_map(|file| file.strip_prefix(&prefix)).filter(|name| !name.contains('/')).map(str::to_owned).collect::<Vec<_>>())
+            .collect();
+
+        for root in &VFS.roots {
+            let Ok(entries) = fs::read_dir(root.join(dir)) else {
+                continue;
+            };
+            files.extend(entries.flatten().filter(|entry| entry.path().is_file()).filter_map(|entry| entry.file_name().into_string().ok()));
+        }
+
+        files.sort();
+        files.dedup();
+        files.into_iter().map(|name| format!("{}{}", prefix, name)).collect()
+    }
+
+    pub fn exists(path: &str) -> bool {
+        let archive_path = archive::normalize(path);
+        VFS.archives.read().unwrap().iter().any(|archive| archive.contains(&archive_path)) || VFS.roots.iter().any(|root| root.join(path).is_file())
+    }
+}
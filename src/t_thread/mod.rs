@@ -1,152 +1,701 @@
 use lazy_static::lazy_static;
 use std::{
-    cell::UnsafeCell,
-    collections::HashMap,
-    sync::{mpsc, Arc, Condvar, Mutex},
+    any::Any,
+    cell::Cell,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::Duration,
 };
 
 lazy_static! {
-    pub static ref THREAD_POOL: Mutex<ThreadPool> = Mutex::new(ThreadPool::new(20));
+    pub static ref THREAD_POOL: ThreadPool = ThreadPool::new(thread::available_parallelism().map(|n| n.get()).unwrap_or(4));
 }
 
+thread_local! {
+    static IS_WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Workers always take the job with the highest priority, jobs with the same priority run in order.
+#[derive(Clone, Copy, Hash, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+pub enum Priority {
+    /// Work nobody waits on, like generating chunks far away.
+    Background,
+    #[default]
+    Normal,
+    High,
+    /// Work the next frame waits on, like remeshing an edit under the player.
+    Critical,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 4] = [Priority::Background, Priority::Normal, Priority::High, Priority::Critical];
+}
+
+/// Names a queued job so it can be bumped or cancelled, e.g. `JobKey::new(("mesh", chunk_x, chunk_z))`.
+#[derive(Clone, Copy, Hash, Debug, PartialEq, Eq)]
+pub struct JobKey(u64);
+
+impl JobKey {
+    pub fn new(value: impl Hash) -> Self {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+/// Group of jobs that can be waited on together.
+#[derive(Clone, Copy, Hash, Debug, PartialEq, PartialOrd, Eq)]
+pub struct Stage {
+    name: &'static str,
+}
+
+impl Stage {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// How a job gets scheduled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JobDesc {
+    pub priority: Priority,
+    pub stage: Option<Stage>,
+    pub key: Option<JobKey>,
+}
+
+impl JobDesc {
+    pub fn new(priority: Priority) -> Self {
+        Self { priority, stage: None, key: None }
+    }
+
+    pub fn stage(mut self, stage: Stage) -> Self {
+        self.stage = Some(stage);
+        self
+    }
+
+    pub fn key(mut self, key: JobKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+}
+
+/// Panic payload of a job that was cancelled before it ran.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+struct Entry {
+    key: Option<JobKey>,
+    job: Job,
+}
+
+/// Jobs waiting for a worker, and how many were submitted but did not finish yet.
+struct Queue {
+    /// One queue per `Priority`
+    jobs: Mutex<[VecDeque<Entry>; 4]>,
+    available: Condvar,
+
+    unfinished: Mutex<usize>,
+    all_finished: Condvar,
+
+    shutdown: AtomicBool,
+}
+
+impl Queue {
+    fn push(&self, priority: Priority, key: Option<JobKey>, job: Job) {
+        self.jobs.lock().unwrap()[priority as usize].push_back(Entry { key, job });
+        self.available.notify_one();
+    }
+
+    fn pop_highest(jobs: &mut [VecDeque<Entry>; 4]) -> Option<Job> {
+        jobs.iter_mut().rev().find_map(|queue| queue.pop_front()).map(|entry| entry.job)
+    }
+
+    fn try_pop(&self) -> Option<Job> {
+        Self::pop_highest(&mut self.jobs.lock().unwrap())
+    }
+
+    /// Blocks until there is a job, None when the pool shuts down.
+    fn pop(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = Self::pop_highest(&mut jobs) {
+                return Some(job);
+            }
+            if self.shutdown.load(Ordering::Acquire) {
+                return None;
+            }
+            jobs = self.available.wait(jobs).unwrap();
+        }
+    }
+
+    /// Takes the queued jobs with the key out of the queue.
+    fn remove(&self, key: JobKey) -> Vec<(Priority, Entry)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut removed = vec![];
+
+        for priority in Priority::ALL {
+            let queue = &mut jobs[priority as usize];
+            let mut i = 0;
+            while i < queue.len() {
+                if queue[i].key == Some(key) {
+                    removed.push((priority, queue.remove(i).unwrap()));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    fn started(&self) {
+        *self.unfinished.lock().unwrap() += 1;
+    }
+
+    fn finished(&self) {
+        let mut unfinished = self.unfinished.lock().unwrap();
+        *unfinished -= 1;
+        if *unfinished == 0 {
+            self.all_finished.notify_all();
+        }
+    }
+}
+
+/// Fixed amount of workers that share one priority queue.
+/// Jobs never take a worker down, a panic is caught and handed to whoever joins the job.
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
-    job_counter: Arc<(Mutex<usize>, Condvar)>,
+    queue: Arc<Queue>,
 
-    stages: StageHandles,
+    stages: Mutex<HashMap<Stage, Arc<StageCounter>>>,
+}
 
-    join_handles: Vec<mpsc::Receiver<()>>,
-    free_join_handles: Vec<usize>,
+struct StageCounter {
+    count: Mutex<usize>,
+    done: Condvar,
 }
 
-pub struct JoinHandle {
-    reciever: mpsc::Receiver<()>,
+/// Counts a job of a stage until it is dropped, ran or not.
+struct StageGuard {
+    stage: Stage,
+    counter: Arc<StageCounter>,
 }
 
-pub struct StageHandles {
-    recievers: HashMap<Stage, Arc<(Mutex<usize>, Condvar)>>,
+impl StageGuard {
+    fn new(stage: Stage) -> Self {
+        let mut stages = THREAD_POOL.stages.lock().unwrap();
+        let counter = stages.entry(stage).or_insert_with(|| Arc::new(StageCounter { count: Mutex::new(0), done: Condvar::new() })).clone();
+        *counter.count.lock().unwrap() += 1;
+        Self { stage, counter }
+    }
 }
-#[derive(Hash, Debug, PartialEq, PartialOrd, Eq)]
-pub struct Stage {
-    id: u32,
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        // counts only change with the map locked, so a new job never counts on a counter that was removed
+        let mut stages = THREAD_POOL.stages.lock().unwrap();
+        let mut count = self.counter.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            stages.remove(&self.stage);
+            self.counter.done.notify_all();
+        }
+    }
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+enum JobResult<T> {
+    Pending,
+    Done(thread::Result<T>),
+    Taken,
+}
 
-impl ThreadPool {
-    fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+struct JobState<T> {
+    /// Result and the callbacks that run once it is there.
+    result: Mutex<(JobResult<T>, Vec<Job>)>,
+    done: Condvar,
+    priority: Priority,
+}
 
-        let (sender, receiver) = mpsc::channel();
+impl<T: Send + 'static> JobState<T> {
+    fn new(priority: Priority) -> Arc<Self> {
+        Arc::new(Self { result: Mutex::new((JobResult::Pending, vec![])), done: Condvar::new(), priority })
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
-        let job_counter = Arc::new((Mutex::new(0), Condvar::new()));
+    /// Stores the result and runs the callbacks that waited on it.
+    fn finish(&self, result: thread::Result<T>) {
+        let callbacks = {
+            let mut state = self.result.lock().unwrap();
+            state.0 = JobResult::Done(result);
+            std::mem::take(&mut state.1)
+        };
+        self.done.notify_all();
+
+        for callback in callbacks {
+            callback();
+        }
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    fn is_finished(&self) -> bool {
+        !matches!(self.result.lock().unwrap().0, JobResult::Pending)
+    }
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver), job_counter.clone()));
+    fn take(&self) -> thread::Result<T> {
+        let mut state = self.result.lock().unwrap();
+        match std::mem::replace(&mut state.0, JobResult::Taken) {
+            JobResult::Done(result) => result,
+            _ => panic!("job result taken before it finished"),
         }
+    }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-            job_counter,
-            stages: StageHandles { recievers: HashMap::new() },
-            join_handles: vec![],
-            free_join_handles: vec![],
+    /// Runs `callback` once this job finished, right away if it already is.
+    /// Runs on the thread that finishes the job, so it has to be short.
+    fn on_finished(&self, callback: Job) {
+        let mut state = self.result.lock().unwrap();
+        if matches!(state.0, JobResult::Pending) {
+            state.1.push(callback);
+        } else {
+            drop(state);
+            callback();
         }
     }
+}
 
-    pub fn join_tasks() {
-        let thread_pool = THREAD_POOL.lock().unwrap();
-        let mut counter = (thread_pool.job_counter.0.lock().unwrap()).clone();
-        if counter != 0 {
-            for i in 0..counter {
-                thread_pool.job_counter.1.wait(thread_pool.job_counter.0.lock().unwrap()).unwrap();
-            }
+/// Finishes the job, as cancelled if it is dropped before it ran.
+struct Completion<T: Send + 'static> {
+    state: Option<Arc<JobState<T>>>,
+}
+
+impl<T: Send + 'static> Completion<T> {
+    fn complete(mut self, result: thread::Result<T>) {
+        let state = self.state.take().unwrap();
+        state.finish(result);
+        THREAD_POOL.queue.finished();
+    }
+}
+
+impl<T: Send + 'static> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.finish(Err(Box::new(Cancelled)));
+            THREAD_POOL.queue.finished();
         }
     }
+}
 
-    pub fn wait_on_stage<F>(stage: Stage) {
-        let thread_pool = THREAD_POOL.lock().unwrap();
+/// Result of a job, dropping it detaches the job.
+#[must_use = "dropping the handle detaches the job, use join to get the result"]
+pub struct JobHandle<T> {
+    state: Arc<JobState<T>>,
+}
 
-        let stage_val = thread_pool.stages.recievers.get(&stage).unwrap();
+impl<T: Send + 'static> JobHandle<T> {
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    /// Waits for the result, resumes the panic of the job if it panicked.
+    /// A cancelled job panics with `Cancelled`.
+    pub fn join(self) -> T {
+        match self.try_join() {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
 
-        let mut count = stage_val.0.lock().unwrap().clone();
-        if count > 0 {
-            for i in 0..count {
-                *stage_val.1.wait(stage_val.0.lock().unwrap()).unwrap();
+    /// Waits for the result, the panic of the job is returned as the error.
+    pub fn try_join(self) -> thread::Result<T> {
+        if IS_WORKER.with(|w| w.get()) {
+            // a worker waiting on a job could block the pool, run other jobs until it is done
+            while !self.state.is_finished() {
+                match THREAD_POOL.queue.try_pop() {
+                    Some(job) => job(),
+                    None => {
+                        let state = self.state.result.lock().unwrap();
+                        if matches!(state.0, JobResult::Pending) {
+                            let _ = self.state.done.wait_timeout(state, Duration::from_millis(1)).unwrap();
+                        }
+                    }
+                }
             }
+        } else {
+            let state = self.state.result.lock().unwrap();
+            let _state = self.state.done.wait_while(state, |s| matches!(s.0, JobResult::Pending)).unwrap();
         }
+        self.state.take()
     }
 
-    pub fn execute_stage<F>(f: F, stage: Stage)
+    /// Runs `f` with the result once this job finished, with the same priority.
+    /// If this job panicked `f` does not run and the panic is passed on.
+    pub fn then<F, U>(self, f: F) -> JobHandle<U>
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce(T) -> U + Send + 'static,
+        U: Send + 'static,
     {
-        let mut thread_pool = THREAD_POOL.lock().unwrap();
+        let previous = self.state.clone();
+        let priority = self.state.priority;
 
-        let stage_value = thread_pool.stages.recievers.entry(stage).or_insert(Arc::new((Mutex::new(0 as usize), Condvar::new()))).clone();
+        let (job, handle) = ThreadPool::wrap(JobDesc::new(priority), move || match previous.take() {
+            Ok(value) => f(value),
+            Err(payload) => panic::resume_unwind(payload),
+        });
+        self.state.on_finished(Box::new(move || THREAD_POOL.queue.push(priority, None, job)));
+        handle
+    }
+}
 
-        // increase number of tasks for this stage
-        *stage_value.0.lock().unwrap() += 1;
+/// Something a job can wait on.
+pub trait Dependency {
+    /// Runs `callback` once the dependency finished, it has to be short.
+    fn on_finished(&self, callback: Box<dyn FnOnce() + Send + 'static>);
+}
 
-        let mut job_counter = thread_pool.job_counter.clone();
-        *job_counter.0.lock().unwrap() += 1;
-        let job = Box::new(move || {
-            f();
-            *stage_value.0.lock().unwrap() -= 1;
-            stage_value.1.notify_one();
+impl<T: Send + 'static> Dependency for JobHandle<T> {
+    fn on_finished(&self, callback: Box<dyn FnOnce() + Send + 'static>) {
+        self.state.on_finished(callback);
+    }
+}
+
+impl ThreadPool {
+    fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(Default::default()),
+            available: Condvar::new(),
+            unfinished: Mutex::new(0),
+            all_finished: Condvar::new(),
+            shutdown: AtomicBool::new(false),
         });
 
-        // Send the task to the workers
-        thread_pool.sender.as_ref().unwrap().send(job).unwrap();
+        let workers = (0..size).map(|id| Worker::new(id, queue.clone())).collect();
+
+        ThreadPool { workers, queue, stages: Mutex::new(HashMap::new()) }
     }
 
-    pub fn execute<F>(f: F) -> usize
+    pub fn worker_count() -> usize {
+        THREAD_POOL.workers.len()
+    }
+
+    /// Wraps `f` so it stores its result, or its panic, in a new handle.
+    /// The job counts for its stage from now on, until it ran or got dropped.
+    fn wrap<F, T>(desc: JobDesc, f: F) -> (Job, JobHandle<T>)
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let mut thread_pool = THREAD_POOL.lock().unwrap();
+        let state = JobState::new(desc.priority);
+        let handle = JobHandle { state: state.clone() };
 
-        let (done_sender, reciever) = mpsc::channel();
+        let stage = desc.stage.map(StageGuard::new);
 
-        // increase amount of jobs by 1
-        let mut job_counter = thread_pool.job_counter.clone();
-        *job_counter.0.lock().unwrap() += 1;
+        THREAD_POOL.queue.started();
+        let completion = Completion { state: Some(state) };
 
         let job = Box::new(move || {
-            f();
-            done_sender.send(()).unwrap();
-            job_counter.1.notify_one();
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            completion.complete(result);
+            drop(stage);
         });
+        (job, handle)
+    }
+
+    /// Waits until every job that was submitted finished.
+    pub fn join_tasks() {
+        let queue = &THREAD_POOL.queue;
+        let unfinished = queue.unfinished.lock().unwrap();
+        let _unfinished = queue.all_finished.wait_while(unfinished, |count| *count > 0).unwrap();
+    }
+
+    /// Waits until every job of the stage finished or got cancelled.
+    pub fn wait_on_stage(stage: Stage) {
+        let Some(counter) = THREAD_POOL.stages.lock().unwrap().get(&stage).cloned() else {
+            return;
+        };
 
-        thread_pool.sender.as_ref().unwrap().send(job).unwrap();
+        let count = counter.count.lock().unwrap();
+        let _count = counter.done.wait_while(count, |count| *count > 0).unwrap();
+    }
 
-        let index;
-        if thread_pool.free_join_handles.len() > 0 {
-            index = thread_pool.free_join_handles.pop().unwrap();
-            thread_pool.join_handles[index] = reciever;
+    /// Jobs of the stage that did not finish yet.
+    pub fn stage_pending(stage: Stage) -> usize {
+        match THREAD_POOL.stages.lock().unwrap().get(&stage) {
+            Some(counter) => *counter.count.lock().unwrap(),
+            None => 0,
+        }
+    }
+
+    pub fn execute_stage<F, T>(f: F, stage: Stage) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::execute_with(JobDesc::default().stage(stage), f)
+    }
+
+    pub fn execute<F, T>(f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::execute_with(JobDesc::default(), f)
+    }
+
+    pub fn execute_with<F, T>(desc: JobDesc, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = Self::wrap(desc, f);
+        THREAD_POOL.queue.push(desc.priority, desc.key, job);
+        handle
+    }
+
+    /// Runs `f` once every dependency finished, also when one of them panicked.
+    pub fn execute_after<F, T>(dependencies: &[&dyn Dependency], f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::execute_after_with(JobDesc::default(), dependencies, f)
+    }
+
+    /// The job is only queued, and can only be bumped or cancelled, once the dependencies finished.
+    pub fn execute_after_with<F, T>(desc: JobDesc, dependencies: &[&dyn Dependency], f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = Self::wrap(desc, f);
+        if dependencies.is_empty() {
+            THREAD_POOL.queue.push(desc.priority, desc.key, job);
+            return handle;
+        }
+
+        let remaining = Arc::new(AtomicUsize::new(dependencies.len()));
+        let job = Arc::new(Mutex::new(Some(job)));
+
+        for dependency in dependencies {
+            let remaining = remaining.clone();
+            let job = job.clone();
+
+            dependency.on_finished(Box::new(move || {
+                // the last dependency queues the job
+                if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    let job = job.lock().unwrap().take().unwrap();
+                    THREAD_POOL.queue.push(desc.priority, desc.key, job);
+                }
+            }));
+        }
+        handle
+    }
+
+    /// Moves the queued jobs with the key to another priority, returns false if none are queued.
+    /// Jobs that already run are not affected.
+    pub fn bump(key: JobKey, priority: Priority) -> bool {
+        let removed = THREAD_POOL.queue.remove(key);
+        let found = !removed.is_empty();
+
+        for (_, entry) in removed {
+            THREAD_POOL.queue.push(priority, entry.key, entry.job);
+        }
+        found
+    }
+
+    /// Removes the queued jobs with the key, their handles return `Cancelled`.
+    /// Returns how many were removed, jobs that already run are not affected.
+    pub fn cancel(key: JobKey) -> usize {
+        // dropped outside of the queue lock, dropping finishes the job and runs its callbacks
+        let removed = THREAD_POOL.queue.remove(key);
+        removed.len()
+    }
+}
+
+/// Jobs of a scope that did not finish yet, and the first panic of one of them.
+struct ScopeState {
+    pending: Mutex<usize>,
+    done: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn finished(&self, result: thread::Result<()>) {
+        if let Err(payload) = result {
+            self.panic.lock().unwrap().get_or_insert(payload);
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        if IS_WORKER.with(|w| w.get()) {
+            // same as joining a job, the worker keeps the pool busy while it waits
+            while *self.pending.lock().unwrap() > 0 {
+                match THREAD_POOL.queue.try_pop() {
+                    Some(job) => job(),
+                    None => {
+                        let pending = self.pending.lock().unwrap();
+                        if *pending > 0 {
+                            let _ = self.done.wait_timeout(pending, Duration::from_millis(1)).unwrap();
+                        }
+                    }
+                }
+            }
         } else {
-            thread_pool.join_handles.push(reciever);
-            index = thread_pool.join_handles.len() - 1;
+            let pending = self.pending.lock().unwrap();
+            let _pending = self.done.wait_while(pending, |pending| *pending > 0).unwrap();
         }
-        index
+    }
+}
+
+/// Jobs in a scope can borrow anything that outlives the scope, see `ThreadPool::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    state: Arc<ScopeState>,
+    priority: Priority,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Runs `f` on the pool, the scope waits for it before it returns.
+    /// A panic is resumed by the scope once every job finished.
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+
+        let state = self.state.clone();
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            state.finished(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+
+        // SAFETY: `ThreadPool::scope` does not return before `pending` is back to zero, which only happens after the job
+        // ran, so nothing it borrows for 'scope can be dropped while it still runs. Queued jobs are never dropped unrun
+        // because they have no key to cancel them with.
+        let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        THREAD_POOL.queue.push(self.priority, None, job);
+    }
+}
+
+impl ThreadPool {
+    /// Jobs started in `f` can borrow from the caller, the scope waits for all of them before it returns.
+    ///
+    /// ```ignore
+    /// let mut sums = [0; 2];
+    /// let (left, right) = sums.split_at_mut(1);
+    /// ThreadPool::scope(|s| {
+    ///     s.execute(|| left[0] = data[..half].iter().sum());
+    ///     s.execute(|| right[0] = data[half..].iter().sum());
+    /// });
+    /// ```
+    pub fn scope<'env, F, R>(f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        // the caller is blocked on it, so it goes before the regular jobs
+        let scope = Scope {
+            state: Arc::new(ScopeState { pending: Mutex::new(0), done: Condvar::new(), panic: Mutex::new(None) }),
+            priority: Priority::High,
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // the jobs have to finish even if `f` panics, they might borrow from its stack
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+
+        if let Some(payload) = scope.state.panic.lock().unwrap().take() {
+            panic::resume_unwind(payload);
+        }
+        match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Items per job, a few jobs per worker so uneven work still spreads out.
+    fn batch_size(len: usize) -> usize {
+        len.div_ceil(Self::worker_count() * 4).max(1)
+    }
+
+    /// Calls `f` with the index and item of every item, spread over the pool.
+    pub fn parallel_for<T, F>(items: &[T], f: F)
+    where
+        T: Sync,
+        F: Fn(usize, &T) + Sync,
+    {
+        let batch = Self::batch_size(items.len());
+        let f = &f;
+
+        Self::scope(|s| {
+            for (i, chunk) in items.chunks(batch).enumerate() {
+                s.execute(move || {
+                    for (j, item) in chunk.iter().enumerate() {
+                        f(i * batch + j, item);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Same as `parallel_for`, every item can be changed.
+    pub fn parallel_for_mut<T, F>(items: &mut [T], f: F)
+    where
+        T: Send,
+        F: Fn(usize, &mut T) + Sync,
+    {
+        let batch = Self::batch_size(items.len());
+
+        Self::parallel_chunks_mut(items, batch, |i, chunk| {
+            for (j, item) in chunk.iter_mut().enumerate() {
+                f(i * batch + j, item);
+            }
+        });
+    }
+
+    /// Splits `items` into chunks of `chunk_size`, the last one can be smaller.
+    /// `f` gets the index of the chunk, the chunk starts at `index * chunk_size`.
+    pub fn parallel_chunks_mut<T, F>(items: &mut [T], chunk_size: usize, f: F)
+    where
+        T: Send,
+        F: Fn(usize, &mut [T]) + Sync,
+    {
+        let f = &f;
+
+        Self::scope(|s| {
+            for (i, chunk) in items.chunks_mut(chunk_size).enumerate() {
+                s.execute(move || f(i, chunk));
+            }
+        });
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.queue.shutdown.store(true, Ordering::Release);
+        self.queue.available.notify_all();
 
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+            log::info!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                let _ = thread.join();
             }
         }
     }
@@ -158,56 +707,254 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, job_counter: Arc<(Mutex<usize>, Condvar)>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
+    fn new(id: usize, queue: Arc<Queue>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("worker {}", id))
+            .spawn(move || {
+                IS_WORKER.with(|w| w.set(true));
+
+                while let Some(job) = queue.pop() {
                     job();
-                    *job_counter.0.lock().unwrap() -= 1;
-                    job_counter.1.notify_one();
                 }
-                Err(_) => {
-                    break;
-                }
-            }
-        });
+            })
+            .unwrap();
 
         Worker { id, thread: Some(thread) }
     }
 }
 
-pub struct Ptr<T> {
-    pub data: *const T,
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
 
-impl<T> Clone for Ptr<T> {
-    fn clone(&self) -> Self {
-        Self { data: self.data }
+    use super::*;
+
+    #[test]
+    fn many_small_jobs_finish() {
+        let handles: Vec<_> = (0..10_000u64).map(|i| ThreadPool::execute(move || i * 2)).collect();
+        let sum: u64 = handles.into_iter().map(JobHandle::join).sum();
+        assert_eq!(sum, 10_000 * 9_999);
     }
-}
 
-impl<T> Ptr<T> {
-    pub fn new(data: *const T) -> Self {
-        Self { data }
+    #[test]
+    fn execute_after_waits_for_the_dependencies() {
+        let (sender, receiver) = mpsc::channel();
+
+        let (first_sender, second_sender) = (sender.clone(), sender.clone());
+        let first = ThreadPool::execute(move || {
+            thread::sleep(Duration::from_millis(30));
+            first_sender.send("first").unwrap();
+        });
+        let second = ThreadPool::execute(move || {
+            thread::sleep(Duration::from_millis(10));
+            second_sender.send("second").unwrap();
+        });
+        let after = ThreadPool::execute_after(&[&first, &second], move || sender.send("after").unwrap());
+
+        after.join();
+        let order: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[2], "after");
+        first.join();
+        second.join();
     }
-}
 
-pub struct MutPtr<T> {
-    pub data: *mut T,
-}
+    #[test]
+    fn then_runs_with_the_result() {
+        let handle = ThreadPool::execute(|| 20).then(|value| value + 1).then(|value| value * 2);
+        assert_eq!(handle.join(), 42);
+    }
+
+    #[test]
+    fn join_resumes_the_panic() {
+        let handle = ThreadPool::execute(|| -> u32 { panic!("job failed") });
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| handle.join())).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+
+        // a panicking dependency does not run `then`, the panic is passed on
+        let handle = ThreadPool::execute(|| -> u32 { panic!("first") }).then(|value| value + 1);
+        assert!(handle.try_join().is_err());
 
-impl<T> Clone for MutPtr<T> {
-    fn clone(&self) -> Self {
-        Self { data: self.data }
+        // the worker survived
+        assert_eq!(ThreadPool::execute(|| 1).join(), 1);
     }
-}
 
-impl<T> MutPtr<T> {
-    pub fn new(data: *mut T) -> Self {
-        Self { data }
+    #[test]
+    fn dropped_job_is_cancelled() {
+        let (job, handle) = ThreadPool::wrap(JobDesc::default(), || 5);
+        drop(job);
+
+        let payload = handle.try_join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<Cancelled>(), Some(&Cancelled));
+    }
+
+    #[test]
+    fn scoped_jobs_borrow_stack_data() {
+        let data: Vec<u64> = (1..=1000).collect();
+        let mut sums = [0; 2];
+        let (left, right) = sums.split_at_mut(1);
+        ThreadPool::scope(|s| {
+            s.execute(|| left[0] = data[..500].iter().sum());
+            s.execute(|| right[0] = data[500..].iter().sum());
+        });
+        assert_eq!(sums, [125_250, 375_250]);
+
+        let mut squares: Vec<u64> = (0..5000).collect();
+        ThreadPool::parallel_for_mut(&mut squares, |i, value| *value *= i as u64);
+        assert!(squares.iter().enumerate().all(|(i, value)| *value == (i * i) as u64));
+
+        let visited = AtomicUsize::new(0);
+        ThreadPool::parallel_for(&data, |i, value| {
+            assert_eq!(*value, i as u64 + 1);
+            visited.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(visited.into_inner(), data.len());
+    }
+
+    #[test]
+    fn scope_joins_before_returning() {
+        let finished = AtomicUsize::new(0);
+        ThreadPool::scope(|s| {
+            for i in 0..8 {
+                let finished = &finished;
+                s.execute(move || {
+                    thread::sleep(Duration::from_millis(5 * (i % 3 + 1)));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        // nothing is left running that could still touch `finished` after it is dropped
+        assert_eq!(finished.load(Ordering::SeqCst), 8);
+
+        // also when a job panics, the others are waited for before the panic is resumed
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            ThreadPool::scope(|s| {
+                s.execute(|| panic!("scoped job failed"));
+                for _ in 0..4 {
+                    s.execute(|| {
+                        thread::sleep(Duration::from_millis(20));
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            })
+        }));
+        assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"scoped job failed"));
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+    }
+
+    /// Tests that block the workers run one after another, they would wait on each other's blockers.
+    static BLOCKING: Mutex<()> = Mutex::new(());
+
+    /// Keeps every worker busy until its sender is dropped, so jobs stay queued.
+    fn block_workers() -> Vec<mpsc::Sender<()>> {
+        let started = Arc::new(AtomicUsize::new(0));
+        let senders = (0..ThreadPool::worker_count())
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel::<()>();
+                let started = started.clone();
+                let _ = ThreadPool::execute_with(JobDesc::new(Priority::Critical), move || {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    let _ = receiver.recv();
+                });
+                sender
+            })
+            .collect();
+
+        while started.load(Ordering::SeqCst) < ThreadPool::worker_count() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        senders
+    }
+
+    fn record(order: &Arc<Mutex<Vec<&'static str>>>, name: &'static str) -> impl FnOnce() + Send + 'static {
+        let order = order.clone();
+        move || order.lock().unwrap().push(name)
+    }
+
+    #[test]
+    fn higher_priorities_run_first() {
+        let _blocking = BLOCKING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut blockers = block_workers();
+
+        let order = Arc::new(Mutex::new(vec![]));
+        let low = ThreadPool::execute_with(JobDesc::new(Priority::Background), record(&order, "low"));
+        let high = ThreadPool::execute_with(JobDesc::new(Priority::High), record(&order, "high"));
+
+        // a single worker takes the queued jobs one after another
+        blockers.pop();
+        low.join();
+        high.join();
+        assert_eq!(*order.lock().unwrap(), ["high", "low"]);
     }
-}
 
-unsafe impl<T> Send for MutPtr<T> {}
-unsafe impl<T> Send for Ptr<T> {}
+    #[test]
+    fn bumped_jobs_move_ahead() {
+        let _blocking = BLOCKING.lock().unwrap_or_else(|e| e.into_inner());
+        let mut blockers = block_workers();
+
+        let key = JobKey::new(("bumped", 1));
+        let order = Arc::new(Mutex::new(vec![]));
+        let low = ThreadPool::execute_with(JobDesc::new(Priority::Background).key(key), record(&order, "low"));
+        let normal = ThreadPool::execute_with(JobDesc::new(Priority::Normal), record(&order, "normal"));
+        assert!(ThreadPool::bump(key, Priority::High));
+        assert!(!ThreadPool::bump(JobKey::new(("bumped", 2)), Priority::High));
+
+        blockers.pop();
+        low.join();
+        normal.join();
+        assert_eq!(*order.lock().unwrap(), ["low", "normal"]);
+    }
+
+    #[test]
+    fn cancelled_jobs_never_run() {
+        let _blocking = BLOCKING.lock().unwrap_or_else(|e| e.into_inner());
+        let blockers = block_workers();
+
+        let key = JobKey::new(("cancelled", 1));
+        let ran = Arc::new(AtomicBool::new(false));
+        let cancelled = {
+            let ran = ran.clone();
+            ThreadPool::execute_with(JobDesc::new(Priority::Normal).key(key), move || ran.store(true, Ordering::SeqCst))
+        };
+        let kept = ThreadPool::execute_with(JobDesc::new(Priority::Background), || 1);
+        assert_eq!(ThreadPool::cancel(key), 1);
+        assert_eq!(ThreadPool::cancel(key), 0);
+
+        let payload = cancelled.try_join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<Cancelled>(), Some(&Cancelled));
+
+        drop(blockers);
+        // the queue is empty once the job after it ran
+        assert_eq!(kept.join(), 1);
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stages_count_their_pending_jobs() {
+        let _blocking = BLOCKING.lock().unwrap_or_else(|e| e.into_inner());
+        let blockers = block_workers();
+
+        let stage = Stage::new("pending test");
+        let key = JobKey::new(("pending", 1));
+        let ran = Arc::new(AtomicUsize::new(0));
+        for i in 0..4 {
+            let ran = ran.clone();
+            let desc = JobDesc::new(Priority::Normal).stage(stage);
+            let desc = if i == 0 { desc.key(key) } else { desc };
+            let _ = ThreadPool::execute_with(desc, move || ran.fetch_add(1, Ordering::SeqCst));
+        }
+        assert_eq!(ThreadPool::stage_pending(stage), 4);
+
+        // a cancelled job no longer counts
+        assert_eq!(ThreadPool::cancel(key), 1);
+        assert_eq!(ThreadPool::stage_pending(stage), 3);
+
+        drop(blockers);
+        ThreadPool::wait_on_stage(stage);
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+        assert_eq!(ThreadPool::stage_pending(stage), 0);
+        // the counter of a finished stage is not kept around
+        assert!(!THREAD_POOL.stages.lock().unwrap().contains_key(&stage));
+    }
+}
@@ -0,0 +1,407 @@
+use crate::vulkan::mesh::Face;
+
+use super::{
+    block::{BlockType, Opacity},
+    light::{Light, LightMap},
+    storage::BlockStorage,
+    CHUNK_HEIGHT, CHUNK_LENGTH,
+};
+
+const HEIGHT_MASK: u128 = (1 << CHUNK_HEIGHT) - 1;
+
+/// One bit per voxel, every axis has its own set of columns so neighbors along that axis are a single shift away.
+///
+/// The chunk is taller than 64 voxels, so the y columns are u128.
+#[derive(Clone)]
+struct Columns {
+    /// Bits along x, one column per (y, z).
+    x: Vec<u64>,
+    /// Bits along y, one column per (x, z).
+    y: Vec<u128>,
+    /// Bits along z, one column per (x, y).
+    z: Vec<u64>,
+}
+
+impl Columns {
+    fn new() -> Self {
+        Self { x: vec![0; CHUNK_LENGTH * CHUNK_HEIGHT], y: vec![0; CHUNK_LENGTH * CHUNK_LENGTH], z: vec![0; CHUNK_LENGTH * CHUNK_HEIGHT] }
+    }
+
+    fn set(&mut self, x: usize, y: usize, z: usize, value: bool) {
+        let x_column = &mut self.x[BinaryGrid::x_column(y, z)];
+        let y_column = &mut self.y[BinaryGrid::y_column(x, z)];
+        let z_column = &mut self.z[BinaryGrid::z_column(x, y)];
+
+        if value {
+            *x_column |= 1 << x;
+            *y_column |= 1 << y;
+            *z_column |= 1 << z;
+        } else {
+            *x_column &= !(1 << x);
+            *y_column &= !(1 << y);
+            *z_column &= !(1 << z);
+        }
+    }
+}
+
+/// Column occupancy of a chunk.
+/// Every voxel that is not air is filled, only the opaque ones hide the faces next to them.
+#[derive(Clone)]
+pub struct BinaryGrid {
+    filled: Columns,
+    opaque: Columns,
+}
+
+impl BinaryGrid {
+    pub fn new() -> Self {
+        Self { filled: Columns::new(), opaque: Columns::new() }
+    }
+
+    pub fn from_blocks(blocks: &BlockStorage) -> Self {
+        let mut grid = Self::new();
+
+        // nothing to do for a chunk that is only air
+        if blocks.palette().iter().all(|block| *block == BlockType::AIR) {
+            return grid;
+        }
+
+        for y in 0..CHUNK_HEIGHT {
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    let block = blocks.get(x, y, z);
+                    if block != BlockType::AIR {
+                        grid.set(x, y, z, block);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    const fn x_column(y: usize, z: usize) -> usize {
+        z + y * CHUNK_LENGTH
+    }
+
+    const fn y_column(x: usize, z: usize) -> usize {
+        x + z * CHUNK_LENGTH
+    }
+
+    const fn z_column(x: usize, y: usize) -> usize {
+        x + y * CHUNK_LENGTH
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block: BlockType) {
+        self.filled.set(x, y, z, block != BlockType::AIR);
+        self.opaque.set(x, y, z, block != BlockType::AIR && block.opacity() == Opacity::Opaque);
+    }
+
+    /// Not air.
+    pub fn is_solid(&self, x: usize, y: usize, z: usize) -> bool {
+        (self.filled.y[Self::y_column(x, z)] >> y) & 1 == 1
+    }
+
+    pub fn is_opaque(&self, x: usize, y: usize, z: usize) -> bool {
+        (self.opaque.y[Self::y_column(x, z)] >> y) & 1 == 1
+    }
+
+    /// Computes the visible faces of every voxel that is not air.
+    /// A face is visible when the voxel next to it is not opaque, missing neighbor chunks and voxels outside of the height count as air.
+    /// Faces between two translucent blocks of the same type are hidden by `FaceMasks::cull_translucent`.
+    ///
+    /// * `right` - chunk at +x, `left` at -x, `front` at +z and `back` at -z.
+    pub fn cull_faces(&self, right: Option<&BinaryGrid>, left: Option<&BinaryGrid>, front: Option<&BinaryGrid>, back: Option<&BinaryGrid>) -> FaceMasks {
+        let mut faces = FaceMasks::new();
+
+        for (i, (column, opaque)) in self.filled.x.iter().copied().zip(self.opaque.x.iter().copied()).enumerate() {
+            let right_first = right.map_or(0, |r| r.opaque.x[i] & 1);
+            let left_last = left.map_or(0, |l| l.opaque.x[i] >> (CHUNK_LENGTH - 1));
+
+            faces.right[i] = column & !((opaque >> 1) | (right_first << (CHUNK_LENGTH - 1)));
+            faces.left[i] = column & !((opaque << 1) | left_last);
+        }
+
+        for (i, (column, opaque)) in self.filled.y.iter().copied().zip(self.opaque.y.iter().copied()).enumerate() {
+            faces.top[i] = column & !(opaque >> 1) & HEIGHT_MASK;
+            faces.bottom[i] = column & !(opaque << 1) & HEIGHT_MASK;
+        }
+
+        for (i, (column, opaque)) in self.filled.z.iter().copied().zip(self.opaque.z.iter().copied()).enumerate() {
+            let front_first = front.map_or(0, |f| f.opaque.z[i] & 1);
+            let back_last = back.map_or(0, |b| b.opaque.z[i] >> (CHUNK_LENGTH - 1));
+
+            faces.front[i] = column & !((opaque >> 1) | (front_first << (CHUNK_LENGTH - 1)));
+            faces.back[i] = column & !((opaque << 1) | back_last);
+        }
+
+        faces
+    }
+}
+
+impl Default for BinaryGrid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Visible faces of a chunk, laid out like the columns of `BinaryGrid`.
+pub struct FaceMasks {
+    right: Vec<u64>,
+    left: Vec<u64>,
+    top: Vec<u128>,
+    bottom: Vec<u128>,
+    front: Vec<u64>,
+    back: Vec<u64>,
+}
+
+impl FaceMasks {
+    fn new() -> Self {
+        Self {
+            right: vec![0; CHUNK_LENGTH * CHUNK_HEIGHT],
+            left: vec![0; CHUNK_LENGTH * CHUNK_HEIGHT],
+            top: vec![0; CHUNK_LENGTH * CHUNK_LENGTH],
+            bottom: vec![0; CHUNK_LENGTH * CHUNK_LENGTH],
+            front: vec![0; CHUNK_LENGTH * CHUNK_HEIGHT],
+            back: vec![0; CHUNK_LENGTH * CHUNK_HEIGHT],
+        }
+    }
+
+    /// Hides the faces between two translucent blocks of the same type, e.g. inside a lake.
+    ///
+    /// * `borders` - blocks of the right, left, front and back chunk that touch this one, see `Border::new`.
+    pub fn cull_translucent(&mut self, blocks: &BlockStorage, borders: [Option<&Border>; 4]) {
+        let translucent = |block: BlockType| block != BlockType::AIR && block.opacity() == Opacity::Translucent;
+        if !blocks.palette().iter().any(|block| translucent(*block)) {
+            return;
+        }
+
+        let [right, left, front, back] = borders;
+        let last = CHUNK_LENGTH - 1;
+
+        for y in 0..CHUNK_HEIGHT {
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    let block = blocks.get(x, y, z);
+                    if !translucent(block) {
+                        continue;
+                    }
+
+                    for face in Face::ALL {
+                        let neighbor = match face {
+                            Face::Right if x == last => right.map(|b| b.get(y, z)),
+                            Face::Right => Some(blocks.get(x + 1, y, z)),
+                            Face::Left if x == 0 => left.map(|b| b.get(y, z)),
+                            Face::Left => Some(blocks.get(x - 1, y, z)),
+                            Face::Top if y == CHUNK_HEIGHT - 1 => None,
+                            Face::Top => Some(blocks.get(x, y + 1, z)),
+                            Face::Bottom if y == 0 => None,
+                            Face::Bottom => Some(blocks.get(x, y - 1, z)),
+                            Face::Front if z == last => front.map(|b| b.get(y, x)),
+                            Face::Front => Some(blocks.get(x, y, z + 1)),
+                            Face::Back if z == 0 => back.map(|b| b.get(y, x)),
+                            Face::Back => Some(blocks.get(x, y, z - 1)),
+                        };
+
+                        if neighbor == Some(block) {
+                            self.hide(face, x, y, z);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn hide(&mut self, face: Face, x: usize, y: usize, z: usize) {
+        match face {
+            Face::Right => self.right[BinaryGrid::x_column(y, z)] &= !(1 << x),
+            Face::Left => self.left[BinaryGrid::x_column(y, z)] &= !(1 << x),
+            Face::Top => self.top[BinaryGrid::y_column(x, z)] &= !(1 << y),
+            Face::Bottom => self.bottom[BinaryGrid::y_column(x, z)] &= !(1 << y),
+            Face::Front => self.front[BinaryGrid::z_column(x, y)] &= !(1 << z),
+            Face::Back => self.back[BinaryGrid::z_column(x, y)] &= !(1 << z),
+        }
+    }
+
+    pub fn is_visible(&self, face: Face, x: usize, y: usize, z: usize) -> bool {
+        match face {
+            Face::Right => (self.right[BinaryGrid::x_column(y, z)] >> x) & 1 == 1,
+            Face::Left => (self.left[BinaryGrid::x_column(y, z)] >> x) & 1 == 1,
+            Face::Top => (self.top[BinaryGrid::y_column(x, z)] >> y) & 1 == 1,
+            Face::Bottom => (self.bottom[BinaryGrid::y_column(x, z)] >> y) & 1 == 1,
+            Face::Front => (self.front[BinaryGrid::z_column(x, y)] >> z) & 1 == 1,
+            Face::Back => (self.back[BinaryGrid::z_column(x, y)] >> z) & 1 == 1,
+        }
+    }
+
+    /// Visible voxels of a y column, with the x and z faces folded in.
+    pub fn visible_column(&self, x: usize, z: usize) -> u128 {
+        let mut column = self.top[BinaryGrid::y_column(x, z)] | self.bottom[BinaryGrid::y_column(x, z)];
+
+        for y in 0..CHUNK_HEIGHT {
+            let x_bits = self.right[BinaryGrid::x_column(y, z)] | self.left[BinaryGrid::x_column(y, z)];
+            let z_bits = self.front[BinaryGrid::z_column(x, y)] | self.back[BinaryGrid::z_column(x, y)];
+
+            column |= ((((x_bits >> x) | (z_bits >> z)) & 1) as u128) << y;
+        }
+        column
+    }
+}
+
+/// Layer of blocks and light of a neighbor chunk that touches the chunk being meshed, (y, column) ordered.
+#[derive(Clone)]
+pub struct Border {
+    blocks: Vec<BlockType>,
+    light: Vec<Light>,
+}
+
+impl Border {
+    /// * `side` - side of the meshed chunk the neighbor is on, e.g. `Face::Right` takes the neighbor's x = 0 layer.
+    pub fn new(neighbor: &BlockStorage, light: &LightMap, side: Face) -> Self {
+        let last = CHUNK_LENGTH - 1;
+        let mut border = Self { blocks: Vec::with_capacity(CHUNK_HEIGHT * CHUNK_LENGTH), light: Vec::with_capacity(CHUNK_HEIGHT * CHUNK_LENGTH) };
+
+        for y in 0..CHUNK_HEIGHT {
+            for i in 0..CHUNK_LENGTH {
+                let (x, z) = match side {
+                    Face::Right => (0, i),
+                    Face::Left => (last, i),
+                    Face::Front => (i, 0),
+                    Face::Back => (i, last),
+                    Face::Top | Face::Bottom => panic!("chunks have no neighbors along y"),
+                };
+                border.blocks.push(neighbor.get(x, y, z));
+                border.light.push(light.get(x, y, z));
+            }
+        }
+        border
+    }
+
+    /// `i` is z for the right and left border, x for the front and back one.
+    pub fn get(&self, y: usize, i: usize) -> BlockType {
+        self.blocks[i + y * CHUNK_LENGTH]
+    }
+
+    /// Same indices as `get`.
+    pub fn light(&self, y: usize, i: usize) -> Light {
+        self.light[i + y * CHUNK_LENGTH]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::block::TerrainBlocks;
+
+    /// Random mix of air, opaque, cutout and translucent blocks, the same for the same seed.
+    fn random_blocks(mut seed: u64) -> BlockStorage {
+        let palette = [BlockType::AIR, BlockType::AIR, TerrainBlocks::global().stone, TerrainBlocks::global().dirt, BlockType::by_name("water").unwrap(), BlockType::by_name("torch").unwrap()];
+        let mut blocks = BlockStorage::new();
+        for i in 0..CHUNK_LENGTH * CHUNK_LENGTH * CHUNK_HEIGHT {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            blocks.set_index(i, palette[(seed >> 33) as usize % palette.len()]);
+        }
+        blocks
+    }
+
+    /// Block next to the voxel, None outside of the chunk and its right and back neighbor.
+    fn naive_neighbor(blocks: &BlockStorage, right: &BlockStorage, back: &BlockStorage, face: Face, x: usize, y: usize, z: usize) -> Option<BlockType> {
+        let last = CHUNK_LENGTH - 1;
+        match face {
+            Face::Right if x == last => Some(right.get(0, y, z)),
+            Face::Right => Some(blocks.get(x + 1, y, z)),
+            Face::Left if x == 0 => None,
+            Face::Left => Some(blocks.get(x - 1, y, z)),
+            Face::Top if y == CHUNK_HEIGHT - 1 => None,
+            Face::Top => Some(blocks.get(x, y + 1, z)),
+            Face::Bottom if y == 0 => None,
+            Face::Bottom => Some(blocks.get(x, y - 1, z)),
+            Face::Front if z == last => None,
+            Face::Front => Some(blocks.get(x, y, z + 1)),
+            Face::Back if z == 0 => Some(back.get(x, y, last)),
+            Face::Back => Some(blocks.get(x, y, z - 1)),
+        }
+    }
+
+    #[test]
+    fn culling_matches_naive_per_voxel_culling() {
+        let blocks = random_blocks(1);
+        let right = random_blocks(2);
+        let back = random_blocks(3);
+        let light = LightMap::new();
+
+        let right_grid = BinaryGrid::from_blocks(&right);
+        let back_grid = BinaryGrid::from_blocks(&back);
+        let right_border = Border::new(&right, &light, Face::Right);
+        let back_border = Border::new(&back, &light, Face::Back);
+
+        let mut faces = BinaryGrid::from_blocks(&blocks).cull_faces(Some(&right_grid), None, None, Some(&back_grid));
+        faces.cull_translucent(&blocks, [Some(&right_border), None, None, Some(&back_border)]);
+
+        for y in 0..CHUNK_HEIGHT {
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    let block = blocks.get(x, y, z);
+                    for face in Face::ALL {
+                        let expected = block != BlockType::AIR
+                            && match naive_neighbor(&blocks, &right, &back, face, x, y, z) {
+                                None => true,
+                                Some(neighbor) => neighbor.opacity() != Opacity::Opaque && !(neighbor == block && block.opacity() == Opacity::Translucent),
+                            };
+                        assert_eq!(faces.is_visible(face, x, y, z), expected, "{:?} face of ({}, {}, {})", face, x, y, z);
+                    }
+                }
+            }
+        }
+    }
+
+    fn cull(blocks: &BlockStorage) -> FaceMasks {
+        let mut faces = BinaryGrid::from_blocks(blocks).cull_faces(None, None, None, None);
+        faces.cull_translucent(blocks, [None; 4]);
+        faces
+    }
+
+    #[test]
+    fn cutout_and_translucent_neighbors_keep_their_faces() {
+        let stone = TerrainBlocks::global().stone;
+        let water = BlockType::by_name("water").unwrap();
+        let torch = BlockType::by_name("torch").unwrap();
+
+        // stone | torch | torch | water | water | stone, along x
+        let mut blocks = BlockStorage::new();
+        for (x, block) in [stone, torch, torch, water, water, stone].into_iter().enumerate() {
+            blocks.set(x + 1, 5, 5, block);
+        }
+        let faces = cull(&blocks);
+
+        // nothing but opaque blocks hides a face
+        assert!(faces.is_visible(Face::Right, 1, 5, 5));
+        assert!(faces.is_visible(Face::Right, 2, 5, 5) && faces.is_visible(Face::Left, 3, 5, 5));
+        assert!(faces.is_visible(Face::Right, 3, 5, 5) && faces.is_visible(Face::Left, 4, 5, 5));
+        assert!(faces.is_visible(Face::Left, 6, 5, 5));
+        // same translucent block on both sides
+        assert!(!faces.is_visible(Face::Right, 4, 5, 5) && !faces.is_visible(Face::Left, 5, 5, 5));
+        // opaque neighbor
+        assert!(!faces.is_visible(Face::Left, 2, 5, 5));
+        assert!(!faces.is_visible(Face::Right, 5, 5, 5));
+    }
+
+    #[test]
+    fn translucent_faces_are_hidden_across_the_chunk_border() {
+        let water = BlockType::by_name("water").unwrap();
+        let last = CHUNK_LENGTH - 1;
+
+        let mut blocks = BlockStorage::new();
+        blocks.set(last, 5, 5, water);
+        blocks.set(last, 5, 6, water);
+        let mut right = BlockStorage::new();
+        right.set(0, 5, 5, water);
+        right.set(0, 5, 6, TerrainBlocks::global().stone);
+
+        let border = Border::new(&right, &LightMap::new(), Face::Right);
+        let mut faces = BinaryGrid::from_blocks(&blocks).cull_faces(Some(&BinaryGrid::from_blocks(&right)), None, None, None);
+        assert!(faces.is_visible(Face::Right, last, 5, 5));
+        assert!(!faces.is_visible(Face::Right, last, 5, 6));
+
+        faces.cull_translucent(&blocks, [Some(&border), None, None, None]);
+        assert!(!faces.is_visible(Face::Right, last, 5, 5));
+    }
+}
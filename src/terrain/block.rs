@@ -1,29 +1,446 @@
+use std::collections::HashMap;
+
 use glm::{Vec3, Vec4};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::core::{asset::AssetError, vfs::Vfs};
+
+use super::fluid::MAX_LEVEL;
+
+/// Id of a block in the `BlockRegistry`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[repr(transparent)]
+pub struct BlockType(u32);
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-#[repr(C, align(4))]
-pub enum BlockType {
-    Air,
-    Dirt,
-    Grass,
-    Stone,
-    AcaciaL,
-    Sand,
-}
 impl BlockType {
-    pub const fn variants() -> usize {
-        6
+    /// Always id 0, the registry has to define it.
+    pub const AIR: BlockType = BlockType(0);
+
+    pub const fn new(id: u32) -> Self {
+        Self(id)
     }
+
     pub fn as_raw(&self) -> u32 {
-        *self as u32
+        self.0
+    }
+
+    /// None if the registry has no block with the id.
+    pub fn from_raw(value: u32) -> Option<BlockType> {
+        BlockRegistry::global().get(BlockType(value)).map(|info| info.id())
+    }
+
+    pub fn by_name(name: &str) -> Option<BlockType> {
+        BlockRegistry::global().by_name(name)
+    }
+
+    pub fn info(&self) -> Option<&'static BlockInfo> {
+        BlockRegistry::global().get(*self)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.info().map_or("unknown", |info| info.name.as_str())
+    }
+
+    /// Blocks that are not in the registry are solid.
+    pub fn is_solid(&self) -> bool {
+        self.info().is_none_or(|info| info.solid)
+    }
+
+    /// Air is always translucent, blocks that are not in the registry are opaque.
+    pub fn opacity(&self) -> Opacity {
+        if *self == Self::AIR {
+            return Opacity::Translucent;
+        }
+        self.info().map_or(Opacity::Opaque, |info| info.opacity)
+    }
+
+    /// Blocks behind it stay visible.
+    pub fn is_transparent(&self) -> bool {
+        self.opacity() != Opacity::Opaque
+    }
+
+    /// Light level from 0 to 15.
+    pub fn light_emission(&self) -> u8 {
+        self.info().map_or(0, |info| info.light)
+    }
+
+    /// Fluid and level of a source or flowing fluid block.
+    pub fn fluid(&self) -> Option<Flow> {
+        self.info().and_then(|info| info.flow)
+    }
+}
+
+/// How a block hides the faces of its neighbors.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Opacity {
+    /// Hides every face next to it.
+    #[default]
+    Opaque,
+    /// Fully see-through holes, e.g. leaves. Meshed with the opaque blocks, every face next to it is kept.
+    Cutout,
+    /// Blended, e.g. glass and water. Meshed separately, faces between two of the same block are hidden.
+    Translucent,
+}
+
+/// Atlas tile of every face, a face without its own tile uses `side` (for right, left, front and back) and then `all`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FaceTextures {
+    pub all: Option<String>,
+    pub side: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    pub right: Option<String>,
+    pub left: Option<String>,
+    pub front: Option<String>,
+    pub back: Option<String>,
+}
+
+impl FaceTextures {
+    pub fn is_empty(&self) -> bool {
+        self.faces().iter().all(Option::is_none)
+    }
+
+    /// right -> left -> top -> bot -> front -> back, like `GPUTexture::face_indices`.
+    pub fn faces(&self) -> [Option<&str>; 6] {
+        let all = self.all.as_deref();
+        let side = self.side.as_deref().or(all);
+
+        [
+            self.right.as_deref().or(side),
+            self.left.as_deref().or(side),
+            self.top.as_deref().or(all),
+            self.bottom.as_deref().or(all),
+            self.front.as_deref().or(side),
+            self.back.as_deref().or(side),
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct MaterialColors {
+    pub ambient: [f32; 3],
+    pub diffuse: [f32; 3],
+    pub specular: [f32; 3],
+}
+
+impl Default for MaterialColors {
+    /// Same as `GPUTexture::default`
+    fn default() -> Self {
+        Self { ambient: [0.1; 3], diffuse: [0.5; 3], specular: [0.4; 3] }
+    }
+}
+
+/// One block of the registry file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BlockInfo {
+    pub name: String,
+    id: u32,
+    pub textures: FaceTextures,
+    /// Can be stood on and collided with.
+    pub solid: bool,
+    pub opacity: Opacity,
+    /// Light level from 0 to 15.
+    pub light: u8,
+    pub material: MaterialColors,
+    /// Makes the block a fluid source, the registry adds its flowing levels.
+    pub fluid: Option<FluidInfo>,
+    /// Set by the registry for the source and flowing levels of a fluid.
+    #[serde(skip)]
+    pub flow: Option<Flow>,
+}
+
+impl BlockInfo {
+    pub fn id(&self) -> BlockType {
+        BlockType(self.id)
+    }
+}
+
+impl Default for BlockInfo {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            id: 0,
+            textures: Default::default(),
+            solid: true,
+            opacity: Opacity::Opaque,
+            light: 0,
+            material: Default::default(),
+            fluid: None,
+            flow: None,
+        }
+    }
+}
+
+/// How a fluid spreads, see `terrain::fluid`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FluidInfo {
+    /// Id of level 1, the levels up to `fluid::MAX_LEVEL` take the ids after it.
+    pub flowing: u32,
+    /// A cell between two sources becomes a source too, e.g. water. Finite fluids only ever have the sources that were placed.
+    #[serde(default)]
+    pub infinite: bool,
+    /// Ticks between two updates of a cell, higher is slower.
+    #[serde(default = "FluidInfo::default_tick_rate")]
+    pub tick_rate: u32,
+    /// Levels lost per block flowed sideways, higher stays closer to the source.
+    #[serde(default = "FluidInfo::default_falloff")]
+    pub falloff: u8,
+}
+
+impl FluidInfo {
+    fn default_tick_rate() -> u32 {
+        5
+    }
+
+    fn default_falloff() -> u8 {
+        1
+    }
+}
+
+/// Level of a fluid block, 0 is the source and `fluid::MAX_LEVEL` flowed the farthest.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Flow {
+    pub source: BlockType,
+    pub level: u8,
+}
+
+/// Blocks the terrain generator places, looked up by name when the registry is loaded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TerrainBlocks {
+    pub dirt: BlockType,
+    pub grass: BlockType,
+    pub stone: BlockType,
+    pub acacia_log: BlockType,
+    pub sand: BlockType,
+}
+
+impl TerrainBlocks {
+    /// The global registry always has them, it falls back to the default one otherwise.
+    pub fn global() -> TerrainBlocks {
+        BlockRegistry::global().terrain().unwrap()
+    }
+
+    fn resolve(names: &HashMap<String, BlockType>) -> Option<Self> {
+        Some(Self {
+            dirt: *names.get("dirt")?,
+            grass: *names.get("grass")?,
+            stone: *names.get("stone")?,
+            acacia_log: *names.get("acacia_log")?,
+            sand: *names.get("sand")?,
+        })
+    }
+}
+
+/// Tiles of a pre-made atlas by (column, row).
+#[derive(Clone, Debug, Deserialize)]
+struct AtlasGrid {
+    width: u32,
+    tiles: HashMap<String, [u32; 2]>,
+}
+
+#[derive(Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    atlas: Option<AtlasGrid>,
+    blocks: Vec<BlockInfo>,
+}
+
+pub const BLOCK_REGISTRY_PATH: &str = "assets/blocks.json";
+
+/// Used when `BLOCK_REGISTRY_PATH` can not be read.
+const DEFAULT_REGISTRY: &str = include_str!("../../assets/blocks.json");
+
+lazy_static! {
+    static ref BLOCK_REGISTRY: BlockRegistry = BlockRegistry::load_global();
+}
+
+/// Every block the world can contain, loaded from a json file so adding a block needs no code:
+/// ```json
+/// {
+///     "atlas": { "width": 29, "tiles": { "grass_top": [17, 14], "grass_side": [16, 10], "dirt": [16, 8] } },
+///     "blocks": [
+///         { "name": "air", "id": 0, "solid": false },
+///         { "name": "glass", "id": 6, "textures": { "all": "glass" }, "opacity": "translucent" },
+///         { "name": "water", "id": 7, "textures": { "all": "water" }, "opacity": "translucent", "fluid": { "flowing": 8, "infinite": true } },
+///         { "name": "grass", "id": 2, "textures": { "side": "grass_side", "top": "grass_top", "bottom": "dirt" }, "light": 0,
+///           "material": { "ambient": [0.1, 0.1, 0.1], "diffuse": [0.5, 0.5, 0.5], "specular": [0.4, 0.4, 0.4] } }
+///     ]
+/// }
+/// ```
+/// The atlas is optional, it maps tile names to a layer of the pre-made atlas.
+/// A fluid takes the ids from `flowing` on for its flowing levels, water above uses 8 to 14.
+/// Every block but air needs a texture for every face.
+pub struct BlockRegistry {
+    /// Indexed by id, ids do not have to be contiguous.
+    blocks: Vec<Option<BlockInfo>>,
+    names: HashMap<String, BlockType>,
+    atlas: Option<AtlasGrid>,
+    /// None if the file does not have every block of `TerrainBlocks`.
+    terrain: Option<TerrainBlocks>,
+}
+
+impl BlockRegistry {
+    pub fn global() -> &'static BlockRegistry {
+        &BLOCK_REGISTRY
     }
 
-    pub fn bit_mask(&self) -> u64 {
-        let value = self.as_raw();
+    fn load_global() -> Self {
+        let registry = Self::load(BLOCK_REGISTRY_PATH).and_then(|registry| match registry.terrain {
+            Some(_) => Ok(registry),
+            None => Err(AssetError::Invalid("the blocks of the terrain generator are missing".to_owned())),
+        });
 
-        1 << value
+        registry.unwrap_or_else(|e| {
+            log::error!("Failed to load block registry {}: {}, using the default one", BLOCK_REGISTRY_PATH, e);
+            Self::from_json(DEFAULT_REGISTRY).unwrap()
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, AssetError> {
+        let data = Vfs::read(path)?;
+        Self::from_json(std::str::from_utf8(&data).map_err(|e| AssetError::Invalid(e.to_string()))?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, AssetError> {
+        let file: RegistryFile = serde_json::from_str(json).map_err(|e| AssetError::Invalid(e.to_string()))?;
+
+        let mut blocks: Vec<Option<BlockInfo>> = vec![];
+        let mut names = HashMap::new();
+        for block in Self::with_flowing(file.blocks)? {
+            if block.name.is_empty() {
+                return Err(AssetError::Invalid(format!("block {} has no name", block.id)));
+            }
+            if block.light > 15 {
+                return Err(AssetError::Invalid(format!("light of {} is above 15", block.name)));
+            }
+            if block.id != 0 && block.textures.faces().contains(&None) {
+                return Err(AssetError::Invalid(format!("{} has no texture for every face", block.name)));
+            }
+            if names.insert(block.name.clone(), block.id()).is_some() {
+                return Err(AssetError::Invalid(format!("block {} is defined twice", block.name)));
+            }
+
+            let id = block.id as usize;
+            if blocks.len() <= id {
+                blocks.resize(id + 1, None);
+            }
+            if let Some(other) = &blocks[id] {
+                return Err(AssetError::Invalid(format!("{} and {} have the same id {}", other.name, block.name, id)));
+            }
+            blocks[id] = Some(block);
+        }
+
+        match blocks.first() {
+            Some(Some(air)) if !air.solid && air.textures.is_empty() => {}
+            _ => return Err(AssetError::Invalid("id 0 has to be a block without textures that is not solid".to_owned())),
+        }
+
+        let terrain = TerrainBlocks::resolve(&names);
+        Ok(Self { blocks, names, atlas: file.atlas, terrain })
+    }
+
+    /// Adds the flowing levels after every fluid source, named `<source>_flowing_<level>`.
+    fn with_flowing(blocks: Vec<BlockInfo>) -> Result<Vec<BlockInfo>, AssetError> {
+        let mut all = Vec::with_capacity(blocks.len());
+        for mut block in blocks {
+            let Some(fluid) = block.fluid.clone() else {
+                all.push(block);
+                continue;
+            };
+            if block.id == 0 {
+                return Err(AssetError::Invalid(format!("{} can not be a fluid, id 0 is air", block.name)));
+            }
+            if fluid.tick_rate == 0 || fluid.falloff == 0 {
+                return Err(AssetError::Invalid(format!("tick rate and falloff of {} have to be above 0", block.name)));
+            }
+            if fluid.flowing == 0 || fluid.flowing.checked_add(MAX_LEVEL as u32).is_none() {
+                return Err(AssetError::Invalid(format!("flowing id {} of {} is out of range", fluid.flowing, block.name)));
+            }
+
+            let source = block.id();
+            block.solid = false;
+            block.flow = Some(Flow { source, level: 0 });
+
+            for level in 1..=MAX_LEVEL {
+                let mut flowing = block.clone();
+                flowing.name = format!("{}_flowing_{}", block.name, level);
+                flowing.id = fluid.flowing + level as u32 - 1;
+                flowing.fluid = None;
+                flowing.flow = Some(Flow { source, level });
+                all.push(flowing);
+            }
+            all.push(block);
+        }
+        Ok(all)
+    }
+
+    pub fn get(&self, block: BlockType) -> Option<&BlockInfo> {
+        self.blocks.get(block.0 as usize)?.as_ref()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<BlockType> {
+        self.names.get(name).copied()
+    }
+
+    pub fn terrain(&self) -> Option<TerrainBlocks> {
+        self.terrain
+    }
+
+    /// Block of `source` at `level`, the source itself at level 0.
+    pub fn fluid_block(&self, source: BlockType, level: u8) -> Option<BlockType> {
+        let fluid = self.get(source)?.fluid.as_ref()?;
+        match level {
+            0 => Some(source),
+            1..=MAX_LEVEL => Some(BlockType(fluid.flowing + level as u32 - 1)),
+            _ => None,
+        }
+    }
+
+    /// Settings of the fluid `block` is a level of.
+    pub fn fluid_info(&self, block: BlockType) -> Option<&FluidInfo> {
+        self.get(self.get(block)?.flow?.source)?.fluid.as_ref()
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockInfo> {
+        self.blocks.iter().flatten()
+    }
+
+    /// Length of the tables indexed by id, the highest id + 1.
+    pub fn table_len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Layer of every tile of the pre-made atlas, empty if the file has no atlas.
+    pub fn atlas_layers(&self) -> HashMap<String, u32> {
+        let Some(atlas) = &self.atlas else {
+            return HashMap::new();
+        };
+        atlas.tiles.iter().map(|(name, [column, row])| (name.clone(), row * atlas.width + column)).collect()
+    }
+
+    /// Material of every id, the tiles are looked up in `layers`.
+    pub fn gpu_textures(&self, layers: &HashMap<String, u32>) -> Result<Vec<GPUTexture>, AssetError> {
+        let mut textures = vec![GPUTexture::default(); self.table_len()];
+
+        for block in self.blocks().filter(|block| !block.textures.is_empty()) {
+            let mut face_indices = [0; 6];
+            for (index, tile) in face_indices.iter_mut().zip(block.textures.faces()) {
+                let tile = tile.ok_or_else(|| AssetError::Invalid(format!("{} has no texture for every face", block.name)))?;
+                *index = *layers.get(tile).ok_or_else(|| AssetError::Invalid(format!("{} uses the unknown tile {}", block.name, tile)))?;
+            }
+
+            let MaterialColors { ambient, diffuse, specular } = block.material;
+            textures[block.id as usize] = GPUTexture::new(ambient.into(), diffuse.into(), specular.into(), face_indices);
+        }
+        Ok(textures)
     }
 }
+
 #[repr(C, align(16))]
 #[derive(Clone, Copy)]
 pub struct GPUBlock {
@@ -41,7 +458,7 @@ impl GPUBlock {
     }
 
     pub fn from_position(position: Vec3) -> Self {
-        Self { position, texture_index: BlockType::Air, scale: Vec3::one() }
+        Self { position, texture_index: BlockType::AIR, scale: Vec3::one() }
     }
 
     pub fn block_type(&self) -> BlockType {
@@ -89,43 +506,59 @@ impl Default for GPUTexture {
 pub struct Materials {}
 
 impl Materials {
-    pub fn get_all() -> Vec<GPUTexture> {
-        let atlas_width = 29;
-
-        let mut gpu_textures = [GPUTexture::default(); BlockType::variants()];
-        let dirt_index = 8 * atlas_width + 16;
-
-        let grass_side_index = 10 * atlas_width + 16;
-        let grass_top_index = 14 * atlas_width + 16 + 1;
-        let sand_index = 11 * atlas_width + 15;
+    /// Resolves the tiles with the layers of an `AtlasBuilder` instead of the pre-made atlas.
+    pub fn from_layers(layers: &HashMap<String, u32>) -> Result<Vec<GPUTexture>, AssetError> {
+        BlockRegistry::global().gpu_textures(layers)
+    }
 
-        let stone_index = 12;
+    /// `GPUTexture` of every block in the `BlockRegistry`, indexed by id, with the pre-made atlas.
+    pub fn get_all() -> Vec<GPUTexture> {
+        let registry = BlockRegistry::global();
 
-        let acacia_top = 2;
-        let acacia_side = 1;
+        registry.gpu_textures(&registry.atlas_layers()).unwrap_or_else(|e| {
+            log::error!("Failed to resolve block textures: {}", e);
+            vec![GPUTexture::default(); registry.table_len()]
+        })
+    }
+}
 
-        // right-> left -> top -> bot -> front -> back
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let dirt_block = [dirt_index; 6];
+    const AIR: &str = r#"{ "name": "air", "id": 0, "solid": false }"#;
 
-        let mut grass_block = [grass_side_index; 6];
-        grass_block[2] = grass_top_index;
-        grass_block[3] = dirt_index;
+    fn registry(blocks: &[&str]) -> Result<BlockRegistry, AssetError> {
+        BlockRegistry::from_json(&format!(r#"{{ "blocks": [{}, {}] }}"#, AIR, blocks.join(", ")))
+    }
 
-        let stone_block = [stone_index; 6];
+    #[test]
+    fn terrain_blocks_are_looked_up_by_name() {
+        let names = ["sand", "stone", "grass", "acacia_log", "dirt"];
+        let blocks: Vec<String> = names.iter().enumerate().map(|(i, name)| format!(r#"{{ "name": "{}", "id": {}, "textures": {{ "all": "x" }} }}"#, name, i + 10)).collect();
+        let blocks: Vec<&str> = blocks.iter().map(String::as_str).collect();
 
-        let mut acacia_block = [acacia_side; 6];
-        acacia_block[2] = acacia_top;
-        acacia_block[3] = acacia_top;
+        let terrain = registry(&blocks).unwrap().terrain().unwrap();
+        assert_eq!(terrain.sand, BlockType::new(10));
+        assert_eq!(terrain.dirt, BlockType::new(14));
 
-        let sand_block = [sand_index; 6];
+        // other registries load without them
+        assert!(registry(&blocks[1..]).unwrap().terrain().is_none());
+        assert_eq!(BlockRegistry::global().by_name("grass"), Some(TerrainBlocks::global().grass));
+    }
 
-        gpu_textures[BlockType::Dirt as usize] = GPUTexture::from_face_indices(dirt_block);
-        gpu_textures[BlockType::Grass as usize] = GPUTexture::from_face_indices(grass_block);
-        gpu_textures[BlockType::Stone as usize] = GPUTexture::from_face_indices(stone_block);
-        gpu_textures[BlockType::AcaciaL as usize] = GPUTexture::from_face_indices(acacia_block);
-        gpu_textures[BlockType::Sand as usize] = GPUTexture::from_face_indices(sand_block);
+    #[test]
+    fn blocks_without_textures_are_rejected() {
+        assert!(registry(&[r#"{ "name": "glass", "id": 1, "opacity": "translucent" }"#]).is_err());
+        assert!(registry(&[r#"{ "name": "log", "id": 1, "textures": { "side": "log" } }"#]).is_err());
+        assert!(registry(&[r#"{ "name": "log", "id": 1, "textures": { "side": "log", "top": "log_top", "bottom": "log_top" } }"#]).is_ok());
+        assert!(registry(&[r#"{ "name": "water", "id": 1, "fluid": { "flowing": 2 } }"#]).is_err());
+    }
 
-        gpu_textures.to_vec()
+    #[test]
+    fn every_default_block_has_a_tile() {
+        let registry = BlockRegistry::from_json(DEFAULT_REGISTRY).unwrap();
+        assert!(registry.terrain().is_some());
+        assert!(registry.gpu_textures(&registry.atlas_layers()).is_ok());
     }
 }
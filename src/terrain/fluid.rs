@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use glm::IVec3;
+
+use super::block::{BlockRegistry, BlockType, Flow};
+
+/// Highest flowing level, a fluid does not spread sideways past it.
+pub const MAX_LEVEL: u8 = 7;
+
+/// Right, left, front and back, in the order a fluid spreads to them.
+const SIDES: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+type Cell = (i32, i32, i32);
+
+/// Fluid cells waiting for their next update.
+/// Due cells are returned in position order, so the same edits at the same ticks always flow the same way.
+#[derive(Default)]
+pub struct FluidTicks {
+    tick: u64,
+    queue: BTreeMap<u64, BTreeSet<Cell>>,
+    /// Tick every queued cell is due at.
+    due: HashMap<Cell, u64>,
+}
+
+impl FluidTicks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ticks advanced so far.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Cells waiting for an update.
+    pub fn active(&self) -> usize {
+        self.due.len()
+    }
+
+    /// Updates the cell in `delay` ticks, at least the next one. A cell that is already queued keeps the earlier tick.
+    pub fn schedule(&mut self, pos: IVec3, delay: u32) {
+        let cell = (pos.x, pos.y, pos.z);
+        let tick = self.tick + delay.max(1) as u64;
+
+        if let Some(&due) = self.due.get(&cell) {
+            if due <= tick {
+                return;
+            }
+            if let Some(cells) = self.queue.get_mut(&due) {
+                cells.remove(&cell);
+            }
+        }
+
+        self.due.insert(cell, tick);
+        self.queue.entry(tick).or_default().insert(cell);
+    }
+
+    /// Moves to the next tick and returns the cells that are due.
+    pub fn advance(&mut self) -> Vec<IVec3> {
+        self.tick += 1;
+
+        let Some(cells) = self.queue.remove(&self.tick) else {
+            return vec![];
+        };
+        cells
+            .into_iter()
+            .map(|cell| {
+                self.due.remove(&cell);
+                IVec3::new(cell.0, cell.1, cell.2)
+            })
+            .collect()
+    }
+}
+
+/// Blocks that change when the fluid at `pos` updates.
+///
+/// A flowing cell takes its level from the fluid feeding it: 1 below a fluid, otherwise the lowest level of its sides plus the falloff.
+/// Without any it dries up, an infinite fluid between two sources on the ground becomes a source.
+/// Then the fluid flows down, and only when it can not, sideways with its level plus the falloff.
+///
+/// * `block_at` - None for voxels that are not loaded, fluids do not flow into them.
+pub fn update(pos: IVec3, block_at: impl Fn(IVec3) -> Option<BlockType>) -> Vec<(IVec3, BlockType)> {
+    let registry = BlockRegistry::global();
+    let Some(block) = block_at(pos) else {
+        return vec![];
+    };
+    let (Some(Flow { source, mut level }), Some(fluid)) = (block.fluid(), registry.fluid_info(block)) else {
+        return vec![];
+    };
+
+    let fluid_block = |level: u8| registry.fluid_block(source, level).unwrap();
+    let flow_at = |pos: IVec3| block_at(pos).and_then(|block| block.fluid()).filter(|flow| flow.source == source);
+    let below = |pos: IVec3| pos - IVec3::new(0, 1, 0);
+    // falling fluid does not spread sideways
+    let falls = |pos: IVec3| block_at(below(pos)) == Some(BlockType::AIR) || flow_at(below(pos)).is_some_and(|flow| flow.level > 0);
+    let sides = SIDES.map(|(x, z)| pos + IVec3::new(x, 0, z));
+
+    let mut changes = vec![];
+
+    if level > 0 {
+        let sources = sides.iter().filter(|side| flow_at(**side).is_some_and(|flow| flow.level == 0)).count();
+
+        let expected = if fluid.infinite && sources >= 2 && !falls(pos) {
+            Some(0)
+        } else if flow_at(pos + IVec3::new(0, 1, 0)).is_some() {
+            Some(1)
+        } else {
+            sides.iter().filter(|side| !falls(**side)).filter_map(|side| flow_at(*side)).map(|flow| flow.level + fluid.falloff).filter(|level| *level <= MAX_LEVEL).min()
+        };
+
+        match expected {
+            None => return vec![(pos, BlockType::AIR)],
+            Some(expected) if expected != level => {
+                level = expected;
+                changes.push((pos, fluid_block(level)));
+            }
+            Some(_) => {}
+        }
+    }
+
+    if falls(pos) {
+        if flow_at(below(pos)).is_none_or(|flow| flow.level > 1) {
+            changes.push((below(pos), fluid_block(1)));
+        }
+        return changes;
+    }
+
+    let next = level + fluid.falloff;
+    if next > MAX_LEVEL {
+        return changes;
+    }
+    for side in sides {
+        let replace = match block_at(side) {
+            Some(BlockType::AIR) => true,
+            Some(_) => flow_at(side).is_some_and(|flow| flow.level > next),
+            None => false,
+        };
+        if replace {
+            changes.push((side, fluid_block(next)));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::block::TerrainBlocks;
+
+    /// Stone floor at y = 0 from -10 to 10, air above it, the rest is not loaded.
+    struct Pool {
+        blocks: HashMap<Cell, BlockType>,
+        ticks: FluidTicks,
+    }
+
+    impl Pool {
+        fn new() -> Self {
+            let mut blocks = HashMap::new();
+            for z in -10..=10 {
+                for x in -10..=10 {
+                    blocks.insert((x, 0, z), TerrainBlocks::global().stone);
+                    for y in 1..4 {
+                        blocks.insert((x, y, z), BlockType::AIR);
+                    }
+                }
+            }
+            Self { blocks, ticks: FluidTicks::new() }
+        }
+
+        fn get(&self, pos: IVec3) -> Option<BlockType> {
+            self.blocks.get(&(pos.x, pos.y, pos.z)).copied()
+        }
+
+        /// Like `World::set_block`, the fluids at and next to the voxel are queued.
+        fn set(&mut self, pos: IVec3, block: BlockType) {
+            self.blocks.insert((pos.x, pos.y, pos.z), block);
+
+            for (x, y, z) in [(0, 0, 0), (1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)] {
+                let neighbor = pos + IVec3::new(x, y, z);
+                if let Some(fluid) = self.get(neighbor).and_then(|block| BlockRegistry::global().fluid_info(block)) {
+                    self.ticks.schedule(neighbor, fluid.tick_rate);
+                }
+            }
+        }
+
+        /// Ticks until every fluid settled.
+        fn settle(&mut self) {
+            for _ in 0..10_000 {
+                if self.ticks.active() == 0 {
+                    return;
+                }
+                for pos in self.ticks.advance() {
+                    for (pos, block) in update(pos, |pos| self.get(pos)) {
+                        if self.get(pos).is_some_and(|old| old != block) {
+                            self.set(pos, block);
+                        }
+                    }
+                }
+            }
+            panic!("the fluids never settled");
+        }
+
+        fn level(&self, x: i32, z: i32) -> Option<u8> {
+            self.get(IVec3::new(x, 1, z)).and_then(|block| block.fluid()).map(|flow| flow.level)
+        }
+    }
+
+    fn block(name: &str) -> BlockType {
+        BlockType::by_name(name).unwrap()
+    }
+
+    #[test]
+    fn water_spreads_one_level_per_block() {
+        let mut pool = Pool::new();
+        pool.set(IVec3::new(0, 1, 0), block("water"));
+        pool.settle();
+
+        for z in -10..=10_i32 {
+            for x in -10..=10_i32 {
+                let distance = x.abs() + z.abs();
+                let expected = (distance <= MAX_LEVEL as i32).then_some(distance as u8);
+                assert_eq!(pool.level(x, z), expected, "({}, {})", x, z);
+            }
+        }
+        assert_eq!(pool.get(IVec3::new(0, 2, 0)), Some(BlockType::AIR));
+    }
+
+    #[test]
+    fn the_same_edits_flow_the_same_way() {
+        let run = || {
+            let mut pool = Pool::new();
+            pool.set(IVec3::new(-3, 1, 2), block("water"));
+            pool.set(IVec3::new(4, 3, -1), block("lava"));
+            pool.set(IVec3::new(0, 1, 0), TerrainBlocks::global().stone);
+            pool.settle();
+            (pool.ticks.tick(), pool.blocks.into_iter().collect::<BTreeMap<_, _>>())
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn infinite_fluids_refill_between_two_sources() {
+        let mut pool = Pool::new();
+        pool.set(IVec3::new(-1, 1, 0), block("water"));
+        pool.set(IVec3::new(1, 1, 0), block("water"));
+        pool.settle();
+        assert_eq!(pool.level(0, 0), Some(0));
+
+        // lava is finite, the cell between stays flowing
+        let mut pool = Pool::new();
+        pool.set(IVec3::new(-1, 1, 0), block("lava"));
+        pool.set(IVec3::new(1, 1, 0), block("lava"));
+        pool.settle();
+        assert_eq!(pool.level(0, 0), Some(BlockRegistry::global().fluid_info(block("lava")).unwrap().falloff));
+    }
+
+    #[test]
+    fn flowing_fluid_dries_up_without_a_source() {
+        let mut pool = Pool::new();
+        pool.set(IVec3::new(0, 1, 0), block("water"));
+        pool.settle();
+
+        pool.set(IVec3::new(0, 1, 0), BlockType::AIR);
+        pool.settle();
+        assert!(pool.blocks.values().all(|block| block.fluid().is_none()));
+    }
+}
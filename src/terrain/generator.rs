@@ -0,0 +1,418 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block::{BlockType, TerrainBlocks},
+    noise::{Fractal, SimplexNoise},
+    storage::BlockStorage,
+    Biome, CHUNK_HEIGHT, CHUNK_LENGTH,
+};
+
+/// Fills a chunk with blocks.
+/// Has to be deterministic, the same chunk coordinate always gives the same chunk.
+pub trait TerrainGenerator: Send + Sync {
+    fn generate(&self, chunk_x: i32, chunk_z: i32) -> BlockStorage;
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeneratorConfig {
+    pub seed: u64,
+    pub octaves: u32,
+    pub persistence: f64,
+    /// Height in voxels the terrain can go above or below the sea level.
+    pub amplitude: f64,
+    pub sea_level: u32,
+    /// Frequency of the height noise, in voxels.
+    pub frequency: f64,
+    /// Frequency of the temperature and moisture noise, lower gives bigger biomes.
+    pub biome_frequency: f64,
+    /// Distance in voxels the height of two biomes is blended over, 0 gives a cliff at every biome border.
+    pub biome_blend: u32,
+    /// Amount of dirt (or sand) between the surface and the stone.
+    pub soil_depth: u32,
+    pub caves: CaveConfig,
+}
+
+/// 3D noise pass that runs after the heightmap.
+/// Only uses the chunk itself, so chunks can still be generated in parallel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaveConfig {
+    pub enabled: bool,
+    /// Layers at the bottom that are never carved, at least the bottom layer stays solid.
+    pub floor: u32,
+    /// Caves stop this many voxels below the surface.
+    pub surface_margin: u32,
+
+    /// Big open caverns where the 3D noise is above the threshold.
+    pub cheese_frequency: f64,
+    pub cheese_threshold: f64,
+
+    /// Long tunnels where two 3D noises are both close to zero.
+    pub spaghetti_frequency: f64,
+    pub spaghetti_width: f64,
+
+    /// Density mode, the voxels around the surface are decided by 3D noise instead of the heightmap.
+    /// Gives overhangs and arches on steep terrain.
+    pub overhangs: bool,
+    /// How many voxels above and below the heightmap the density is used.
+    pub overhang_range: u32,
+    pub overhang_frequency: f64,
+    /// Voxels of height difference the noise can overrule, bigger gives wilder cliffs.
+    pub overhang_strength: f64,
+}
+
+impl Default for CaveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            floor: 4,
+            surface_margin: 4,
+            cheese_frequency: 0.03,
+            cheese_threshold: 0.55,
+            spaghetti_frequency: 0.02,
+            spaghetti_width: 0.06,
+            overhangs: true,
+            overhang_range: 12,
+            overhang_frequency: 0.04,
+            overhang_strength: 8.0,
+        }
+    }
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 12004690,
+            octaves: 4,
+            persistence: 0.5,
+            amplitude: 20.0,
+            sea_level: (CHUNK_HEIGHT as f32 * 0.6) as u32,
+            frequency: 0.01,
+            biome_frequency: 0.002,
+            biome_blend: 16,
+            soil_depth: 3,
+            caves: CaveConfig::default(),
+        }
+    }
+}
+
+impl Biome {
+    /// Picks the biome from temperature and moisture, both in [-1, 1].
+    pub fn from_climate(temperature: f64, moisture: f64) -> Biome {
+        if temperature > 0.25 && moisture < 0.0 {
+            Biome::Desert
+        } else if temperature < -0.3 {
+            Biome::Mountain
+        } else if moisture > 0.15 {
+            Biome::Forest
+        } else {
+            Biome::FlatLand
+        }
+    }
+
+    pub fn surface_block(&self, blocks: &TerrainBlocks) -> BlockType {
+        match self {
+            Biome::FlatLand | Biome::Forest => blocks.grass,
+            Biome::Desert => blocks.sand,
+            Biome::Mountain => blocks.stone,
+        }
+    }
+
+    pub fn soil_block(&self, blocks: &TerrainBlocks) -> BlockType {
+        match self {
+            Biome::Desert => blocks.sand,
+            Biome::Mountain => blocks.stone,
+            Biome::FlatLand | Biome::Forest => blocks.dirt,
+        }
+    }
+
+    /// How much of the config amplitude the biome uses.
+    pub fn height_scale(&self) -> f64 {
+        match self {
+            Biome::FlatLand => 0.25,
+            Biome::Desert => 0.5,
+            Biome::Forest => 1.0,
+            Biome::Mountain => 2.0,
+        }
+    }
+}
+
+/// Distance in voxels between the climate samples that are blended.
+const BLEND_STEP: usize = 4;
+
+/// Height scales of the biomes on a grid around a chunk, aligned to world coordinates so neighbor chunks blend the same samples.
+struct ScaleGrid {
+    /// World position of the first sample.
+    start: (f64, f64),
+    /// Samples along x and z.
+    length: usize,
+    scales: Vec<f64>,
+}
+
+impl ScaleGrid {
+    /// Height scale at a world position, every sample closer than `radius` is weighted by its distance along x and z.
+    fn blend(&self, world_x: f64, world_z: f64, radius: f64) -> f64 {
+        // a smaller radius could fall between the samples
+        let radius = radius.max(BLEND_STEP as f64);
+        let (mut total, mut weights) = (0.0, 0.0);
+
+        for j in 0..self.length {
+            let weight_z = 1.0 - (self.start.1 + (j * BLEND_STEP) as f64 - world_z).abs() / radius;
+            if weight_z <= 0.0 {
+                continue;
+            }
+            for i in 0..self.length {
+                let weight_x = 1.0 - (self.start.0 + (i * BLEND_STEP) as f64 - world_x).abs() / radius;
+                if weight_x <= 0.0 {
+                    continue;
+                }
+                total += self.scales[i + j * self.length] * weight_x * weight_z;
+                weights += weight_x * weight_z;
+            }
+        }
+        total / weights
+    }
+}
+
+/// Heightmap generator, one biome per column.
+/// The height scale is blended between the biomes around a column, see `GeneratorConfig::biome_blend`.
+pub struct DefaultGenerator {
+    config: GeneratorConfig,
+    terrain_blocks: TerrainBlocks,
+
+    height_noise: SimplexNoise,
+    temperature_noise: SimplexNoise,
+    moisture_noise: SimplexNoise,
+
+    cheese_noise: SimplexNoise,
+    spaghetti_noise: [SimplexNoise; 2],
+    density_noise: SimplexNoise,
+}
+
+impl DefaultGenerator {
+    pub fn new(config: GeneratorConfig) -> Self {
+        Self {
+            height_noise: SimplexNoise::new(config.seed),
+            temperature_noise: SimplexNoise::new(config.seed.wrapping_add(1)),
+            moisture_noise: SimplexNoise::new(config.seed.wrapping_add(2)),
+            cheese_noise: SimplexNoise::new(config.seed.wrapping_add(3)),
+            spaghetti_noise: [SimplexNoise::new(config.seed.wrapping_add(4)), SimplexNoise::new(config.seed.wrapping_add(5))],
+            density_noise: SimplexNoise::new(config.seed.wrapping_add(6)),
+            terrain_blocks: TerrainBlocks::global(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &GeneratorConfig {
+        &self.config
+    }
+
+    fn biome(&self, world_x: f64, world_z: f64, fractal: &Fractal) -> Biome {
+        let temperature = self.temperature_noise.fbm_2d(world_x, world_z, fractal);
+        let moisture = self.moisture_noise.fbm_2d(world_x, world_z, fractal);
+        Biome::from_climate(temperature, moisture)
+    }
+
+    /// Biome height scales on the `BLEND_STEP` grid that covers the chunk and the blend distance around it.
+    fn scale_grid(&self, x_start: f64, z_start: f64, fractal: &Fractal) -> ScaleGrid {
+        let margin = (self.config.biome_blend as usize).div_ceil(BLEND_STEP) * BLEND_STEP;
+        let length = (CHUNK_LENGTH + 2 * margin) / BLEND_STEP + 1;
+        let start = (x_start - margin as f64, z_start - margin as f64);
+
+        let mut scales = Vec::with_capacity(length * length);
+        for j in 0..length {
+            for i in 0..length {
+                let biome = self.biome(start.0 + (i * BLEND_STEP) as f64, start.1 + (j * BLEND_STEP) as f64, fractal);
+                scales.push(biome.height_scale());
+            }
+        }
+        ScaleGrid { start, length, scales }
+    }
+
+    /// Density of a voxel near the surface, solid when above zero.
+    fn density(&self, world: [f64; 3], height: usize) -> f64 {
+        let caves = &self.config.caves;
+        let fractal = Fractal::new(2, caves.overhang_frequency, 0.5);
+
+        let noise = self.density_noise.fbm_3d(world[0], world[1], world[2], &fractal);
+        height as f64 - world[1] + noise * caves.overhang_strength
+    }
+
+    fn is_cave(&self, world: [f64; 3]) -> bool {
+        let caves = &self.config.caves;
+        let [x, y, z] = world;
+
+        let cheese = self.cheese_noise.fbm_3d(x, y, z, &Fractal::new(2, caves.cheese_frequency, 0.5));
+        if cheese > caves.cheese_threshold {
+            return true;
+        }
+
+        // squash y, tunnels mostly run sideways
+        let frequency = caves.spaghetti_frequency;
+        let first = self.spaghetti_noise[0].noise_3d(x * frequency, y * frequency * 2.0, z * frequency);
+        let second = self.spaghetti_noise[1].noise_3d(x * frequency, y * frequency * 2.0, z * frequency);
+
+        first.abs() < caves.spaghetti_width && second.abs() < caves.spaghetti_width
+    }
+
+    /// Carves caves out of the column, nothing below `floor` or too close to the surface is touched.
+    fn carve_column(&self, blocks: &mut BlockStorage, x: usize, z: usize, world_x: f64, world_z: f64, height: usize) {
+        let caves = &self.config.caves;
+
+        let floor = (caves.floor as usize).max(1);
+        let top = height.saturating_sub(caves.surface_margin as usize);
+
+        for y in floor..top {
+            if self.is_cave([world_x, y as f64, world_z]) {
+                blocks.set(x, y, z, BlockType::AIR);
+            }
+        }
+    }
+}
+
+impl TerrainGenerator for DefaultGenerator {
+    fn generate(&self, chunk_x: i32, chunk_z: i32) -> BlockStorage {
+        let config = &self.config;
+
+        let height_fractal = Fractal::new(config.octaves, config.frequency, config.persistence);
+        let biome_fractal = Fractal::new(2, config.biome_frequency, 0.5);
+
+        let x_start = chunk_x as f64 * CHUNK_LENGTH as f64;
+        let z_start = chunk_z as f64 * CHUNK_LENGTH as f64;
+
+        let mut blocks = BlockStorage::new();
+        let scale_grid = (config.biome_blend > 0).then(|| self.scale_grid(x_start, z_start, &biome_fractal));
+
+        for z in 0..CHUNK_LENGTH {
+            for x in 0..CHUNK_LENGTH {
+                let (world_x, world_z) = (x as f64 + x_start, z as f64 + z_start);
+                let biome = self.biome(world_x, world_z, &biome_fractal);
+                let height_scale = match &scale_grid {
+                    Some(grid) => grid.blend(world_x, world_z, config.biome_blend as f64),
+                    None => biome.height_scale(),
+                };
+
+                let noise = self.height_noise.fbm_2d(world_x, world_z, &height_fractal);
+                let height = config.sea_level as f64 + noise * config.amplitude * height_scale;
+                let height = (height.round() as usize).clamp(1, CHUNK_HEIGHT - 1);
+
+                let soil_start = height.saturating_sub(config.soil_depth as usize);
+
+                let caves = &config.caves;
+                let overhangs = caves.enabled && caves.overhangs;
+
+                let (density_start, density_end) = match overhangs {
+                    true => (height.saturating_sub(caves.overhang_range as usize).max(1), (height + caves.overhang_range as usize).min(CHUNK_HEIGHT - 1)),
+                    false => (height + 1, height),
+                };
+
+                // top down, so the top voxel of an overhang gets the surface block and the ones below it soil
+                let mut covered = false;
+                for y in (0..=height.max(density_end)).rev() {
+                    if y >= density_start && y <= density_end && self.density([world_x, y as f64, world_z], height) <= 0.0 {
+                        covered = false;
+                        continue;
+                    }
+
+                    let block = if y >= height && !covered {
+                        biome.surface_block(&self.terrain_blocks)
+                    } else if y >= soil_start {
+                        biome.soil_block(&self.terrain_blocks)
+                    } else {
+                        self.terrain_blocks.stone
+                    };
+                    blocks.set(x, y, z, block);
+                    covered = true;
+                }
+
+                if caves.enabled {
+                    self.carve_column(&mut blocks, x, z, world_x, world_z, height);
+                }
+            }
+        }
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_the_same_chunk() {
+        let first = DefaultGenerator::new(GeneratorConfig::default());
+        let second = DefaultGenerator::new(GeneratorConfig::default());
+        let other = DefaultGenerator::new(GeneratorConfig { seed: 7, ..GeneratorConfig::default() });
+
+        for (x, z) in [(0, 0), (-3, 5)] {
+            let hash = first.generate(x, z).content_hash();
+            assert_eq!(hash, second.generate(x, z).content_hash());
+            assert_ne!(hash, other.generate(x, z).content_hash());
+        }
+    }
+
+    #[test]
+    fn surface_blocks_are_never_covered() {
+        let generator = DefaultGenerator::new(GeneratorConfig { caves: CaveConfig { overhang_strength: 24.0, ..CaveConfig::default() }, ..GeneratorConfig::default() });
+        let blocks = generator.generate(1, 2);
+        let mut grass = 0;
+
+        for z in 0..CHUNK_LENGTH {
+            for x in 0..CHUNK_LENGTH {
+                for y in 0..CHUNK_HEIGHT - 1 {
+                    if blocks.get(x, y, z) == TerrainBlocks::global().grass {
+                        grass += 1;
+                        assert_eq!(blocks.get(x, y + 1, z), BlockType::AIR, "grass under a block at ({}, {}, {})", x, y, z);
+                    }
+                }
+            }
+        }
+        assert!(grass > 0);
+    }
+
+    #[test]
+    fn height_scale_blends_across_biome_borders() {
+        let grid = ScaleGrid { start: (0.0, 0.0), length: 9, scales: (0..81).map(|i| if i % 9 < 4 { Biome::FlatLand.height_scale() } else { Biome::Mountain.height_scale() }).collect() };
+
+        let radius = 16.0;
+        let mut last = grid.blend(0.0, 16.0, radius);
+        for x in 1..=32 {
+            let scale = grid.blend(x as f64, 16.0, radius);
+            // the scale changes by 1.75 over the 32 voxels around the border, never all at once
+            assert!((scale - last).abs() < 0.15, "scale jumps from {} to {} at x = {}", last, scale, x);
+            last = scale;
+        }
+        assert!(grid.blend(0.0, 16.0, radius) < 0.5 && grid.blend(32.0, 16.0, radius) > 1.5);
+    }
+
+    #[test]
+    fn caves_stay_between_the_floor_and_the_surface() {
+        let solid = DefaultGenerator::new(GeneratorConfig { caves: CaveConfig { enabled: false, ..CaveConfig::default() }, ..GeneratorConfig::default() });
+
+        for floor in [0, 6] {
+            // the same heightmap as `solid`, with far more caves than usual
+            let caves = CaveConfig { floor, surface_margin: 5, cheese_threshold: 0.0, overhangs: false, ..CaveConfig::default() };
+            let generator = DefaultGenerator::new(GeneratorConfig { caves: caves.clone(), ..GeneratorConfig::default() });
+
+            let (before, after) = (solid.generate(-2, 1), generator.generate(-2, 1));
+            assert!(after == generator.generate(-2, 1));
+            let mut carved = 0;
+
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    let height = (0..CHUNK_HEIGHT).rev().find(|&y| before.get(x, y, z) != BlockType::AIR).unwrap();
+                    for y in 0..=height {
+                        if after.get(x, y, z) != BlockType::AIR {
+                            continue;
+                        }
+                        assert!(y >= (floor as usize).max(1), "carved below the floor at ({}, {}, {})", x, y, z);
+                        assert!(y + caves.surface_margin as usize <= height, "carved near the surface at ({}, {}, {})", x, y, z);
+                        carved += 1;
+                    }
+                }
+            }
+            assert!(carved > 0);
+        }
+    }
+}
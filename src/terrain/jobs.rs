@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+};
+
+use crate::t_thread::{JobDesc, JobKey, Priority, ThreadPool};
+
+use super::{
+    binary::{BinaryGrid, Border},
+    light::LightMap,
+    storage::BlockStorage,
+    Chunk, ChunkLoader, ChunkMesh, GreedyMesh,
+};
+
+pub enum ChunkResult {
+    /// Generated or read from disk, lit but not meshed.
+    Loaded(Box<Chunk>),
+    Meshed { chunk_x: i32, chunk_z: i32, mesh: ChunkMesh },
+    /// The load panicked.
+    LoadFailed { chunk_x: i32, chunk_z: i32 },
+    /// The mesh panicked, the chunk keeps its old mesh.
+    MeshFailed { chunk_x: i32, chunk_z: i32 },
+}
+
+/// Copy of what meshing a chunk needs, so the job does not borrow the world.
+pub struct MeshInput {
+    pub blocks: BlockStorage,
+    pub light: LightMap,
+    pub grid: BinaryGrid,
+    /// right, left, front, back
+    pub neighbors: [Option<BinaryGrid>; 4],
+    /// Same order as `neighbors`, for the culling between translucent blocks and the light on the border.
+    pub borders: [Option<Border>; 4],
+}
+
+struct Running {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Chunk loading and meshing on the `ThreadPool`.
+/// Results come back through a channel, a result is only handed out if its job is still the latest one for the chunk.
+pub struct ChunkJobs {
+    sender: mpsc::Sender<(u64, ChunkResult)>,
+    receiver: mpsc::Receiver<(u64, ChunkResult)>,
+
+    next_id: u64,
+    loading: HashMap<(i32, i32), Running>,
+    meshing: HashMap<(i32, i32), Running>,
+}
+
+impl ChunkJobs {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self { sender, receiver, next_id: 0, loading: HashMap::new(), meshing: HashMap::new() }
+    }
+
+    fn start(&mut self) -> (u64, Arc<AtomicBool>, mpsc::Sender<(u64, ChunkResult)>) {
+        self.next_id += 1;
+        (self.next_id, Arc::new(AtomicBool::new(false)), self.sender.clone())
+    }
+
+    fn load_key(chunk_x: i32, chunk_z: i32) -> JobKey {
+        JobKey::new(("chunk load", chunk_x, chunk_z))
+    }
+
+    fn mesh_key(chunk_x: i32, chunk_z: i32) -> JobKey {
+        JobKey::new(("chunk mesh", chunk_x, chunk_z))
+    }
+
+    /// Bumps the priority if the chunk is already loading.
+    pub fn load(&mut self, loader: &Arc<ChunkLoader>, chunk_x: i32, chunk_z: i32, priority: Priority) {
+        if self.is_loading(chunk_x, chunk_z) {
+            ThreadPool::bump(Self::load_key(chunk_x, chunk_z), priority);
+            return;
+        }
+
+        let (id, cancelled, sender) = self.start();
+        self.loading.insert((chunk_x, chunk_z), Running { id, cancelled: cancelled.clone() });
+
+        let loader = loader.clone();
+        let desc = JobDesc::new(priority).key(Self::load_key(chunk_x, chunk_z));
+        let _ = ThreadPool::execute_with(desc, move || {
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            // a job that never answers would keep `recv` waiting forever
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| loader.load(chunk_x, chunk_z))) {
+                Ok(chunk) => ChunkResult::Loaded(Box::new(chunk)),
+                Err(_) => ChunkResult::LoadFailed { chunk_x, chunk_z },
+            };
+            // the world might be gone already
+            let _ = sender.send((id, result));
+        });
+    }
+
+    /// Replaces the mesh job that is still running for the chunk.
+    pub fn mesh(&mut self, chunk_x: i32, chunk_z: i32, input: MeshInput, priority: Priority) {
+        self.cancel_mesh(chunk_x, chunk_z);
+
+        let (id, cancelled, sender) = self.start();
+        self.meshing.insert((chunk_x, chunk_z), Running { id, cancelled: cancelled.clone() });
+
+        let desc = JobDesc::new(priority).key(Self::mesh_key(chunk_x, chunk_z));
+        let _ = ThreadPool::execute_with(desc, move || {
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            let mesh = panic::catch_unwind(AssertUnwindSafe(|| {
+                let [right, left, front, back] = &input.neighbors;
+                let borders = input.borders.each_ref().map(Option::as_ref);
+                let mut faces = input.grid.cull_faces(right.as_ref(), left.as_ref(), front.as_ref(), back.as_ref());
+                faces.cull_translucent(&input.blocks, borders);
+
+                let origin = BlockStorage::world_position(chunk_x, chunk_z, 0, 0, 0);
+                GreedyMesh::create_greedy(&input.blocks, &input.light, &faces, borders, origin)
+            }));
+            let result = match mesh {
+                Ok(mesh) => ChunkResult::Meshed { chunk_x, chunk_z, mesh },
+                Err(_) => ChunkResult::MeshFailed { chunk_x, chunk_z },
+            };
+            let _ = sender.send((id, result));
+        });
+    }
+
+    pub fn is_loading(&self, chunk_x: i32, chunk_z: i32) -> bool {
+        self.loading.contains_key(&(chunk_x, chunk_z))
+    }
+
+    pub fn loading_count(&self) -> usize {
+        self.loading.len()
+    }
+
+    /// Jobs that did not hand out their result yet.
+    pub fn running(&self) -> usize {
+        self.loading.len() + self.meshing.len()
+    }
+
+    /// Cancels the loads of the chunks `keep` returns false for.
+    pub fn retain_loads(&mut self, keep: impl Fn(i32, i32) -> bool) {
+        self.loading.retain(|&(x, z), running| {
+            let keep = keep(x, z);
+            if !keep {
+                running.cancelled.store(true, Ordering::Relaxed);
+                ThreadPool::cancel(Self::load_key(x, z));
+            }
+            keep
+        });
+    }
+
+    /// The result of the running job is thrown away, the chunk is meshed somewhere else.
+    pub fn cancel_mesh(&mut self, chunk_x: i32, chunk_z: i32) {
+        if let Some(running) = self.meshing.remove(&(chunk_x, chunk_z)) {
+            running.cancelled.store(true, Ordering::Relaxed);
+            ThreadPool::cancel(Self::mesh_key(chunk_x, chunk_z));
+        }
+    }
+
+    /// Next finished result, without blocking.
+    pub fn try_recv(&mut self) -> Option<ChunkResult> {
+        while let Ok((id, result)) = self.receiver.try_recv() {
+            if self.finish(id, &result) {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    /// Waits for the next result, None if nothing is running.
+    pub fn recv(&mut self) -> Option<ChunkResult> {
+        while self.running() > 0 {
+            let (id, result) = self.receiver.recv().ok()?;
+            if self.finish(id, &result) {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    /// Removes the job, false if it was cancelled or replaced.
+    fn finish(&mut self, id: u64, result: &ChunkResult) -> bool {
+        let (jobs, key) = match result {
+            ChunkResult::Loaded(chunk) => (&mut self.loading, (chunk.chunk_x, chunk.chunk_z)),
+            ChunkResult::LoadFailed { chunk_x, chunk_z } => (&mut self.loading, (*chunk_x, *chunk_z)),
+            ChunkResult::Meshed { chunk_x, chunk_z, .. } | ChunkResult::MeshFailed { chunk_x, chunk_z } => (&mut self.meshing, (*chunk_x, *chunk_z)),
+        };
+
+        match jobs.get(&key) {
+            Some(running) if running.id == id => {
+                jobs.remove(&key);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for ChunkJobs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::generator::TerrainGenerator;
+
+    /// Panics for every chunk with a negative x.
+    struct Fragile;
+
+    impl TerrainGenerator for Fragile {
+        fn generate(&self, chunk_x: i32, _chunk_z: i32) -> BlockStorage {
+            assert!(chunk_x >= 0, "broken chunk");
+            BlockStorage::new()
+        }
+    }
+
+    #[test]
+    fn panicking_load_does_not_block() {
+        let loader = Arc::new(ChunkLoader::new(Arc::new(Fragile), None));
+        let mut jobs = ChunkJobs::new();
+        jobs.load(&loader, -1, 0, Priority::High);
+        jobs.load(&loader, 1, 0, Priority::High);
+
+        let mut results = vec![];
+        while let Some(result) = jobs.recv() {
+            results.push(match result {
+                ChunkResult::Loaded(chunk) => {
+                    // meshed once by the world, after the neighbors are known
+                    assert!(chunk.quads.is_empty() && chunk.translucent_quads.is_empty());
+                    ("loaded", chunk.chunk_x)
+                }
+                ChunkResult::LoadFailed { chunk_x, .. } => ("failed", chunk_x),
+                _ => unreachable!(),
+            });
+        }
+        results.sort();
+        assert_eq!(results, [("failed", -1), ("loaded", 1)]);
+        assert_eq!(jobs.running(), 0);
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::VecDeque;
+
+use glm::IVec3;
+
+use super::{
+    block::BlockType,
+    storage::{BlockStorage, CHUNK_VOLUME},
+    CHUNK_HEIGHT, CHUNK_LENGTH,
+};
+
+/// Light of the open sky and the brightest blocks.
+pub const MAX_LIGHT: u8 = 15;
+
+/// Offsets to the 6 neighbors of a voxel, true for the one below.
+const NEIGHBORS: [((i32, i32, i32), bool); 6] = [((1, 0, 0), false), ((-1, 0, 0), false), ((0, 1, 0), false), ((0, -1, 0), true), ((0, 0, 1), false), ((0, 0, -1), false)];
+
+/// Sky light in the high and block light in the low 4 bits, each from 0 to `MAX_LIGHT`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct Light(u8);
+
+impl Light {
+    /// Open sky without block light, used above the world and next to chunks that are not loaded.
+    pub const SKY: Light = Light(MAX_LIGHT << 4);
+
+    pub const fn new(sky: u8, block: u8) -> Self {
+        Self((sky << 4) | (block & 0xf))
+    }
+
+    pub const fn sky(&self) -> u8 {
+        self.0 >> 4
+    }
+
+    pub const fn block(&self) -> u8 {
+        self.0 & 0xf
+    }
+
+    /// Packed the same way as the `light` of `VertexBlock`.
+    pub const fn as_raw(&self) -> u8 {
+        self.0
+    }
+
+    fn get(&self, channel: Channel) -> u8 {
+        match channel {
+            Channel::Sky => self.sky(),
+            Channel::Block => self.block(),
+        }
+    }
+
+    fn with(self, channel: Channel, level: u8) -> Self {
+        match channel {
+            Channel::Sky => Self::new(level, self.block()),
+            Channel::Block => Self::new(self.sky(), level),
+        }
+    }
+}
+
+/// Sky and block light spread on their own.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Channel {
+    Sky,
+    Block,
+}
+
+/// Light of every voxel of a chunk, same layout as `BlockStorage`.
+#[derive(Clone)]
+pub struct LightMap {
+    light: Vec<Light>,
+}
+
+impl LightMap {
+    /// Every voxel is dark.
+    pub fn new() -> Self {
+        Self { light: vec![Light::default(); CHUNK_VOLUME] }
+    }
+
+    /// Lights the chunk on its own, `World` lets the light of the neighbors in once it is loaded.
+    pub fn from_blocks(blocks: &BlockStorage) -> Self {
+        let mut map = Self::new();
+
+        // nothing blocks the sky and nothing glows
+        if blocks.palette().iter().all(|block| block.is_transparent() && block.light_emission() == 0) {
+            map.light.fill(Light::SKY);
+            return map;
+        }
+
+        // the sky shines straight down until the first opaque block
+        for z in 0..CHUNK_LENGTH {
+            for x in 0..CHUNK_LENGTH {
+                for y in (0..CHUNK_HEIGHT).rev() {
+                    if !blocks.get(x, y, z).is_transparent() {
+                        break;
+                    }
+                    map.set(x, y, z, Light::SKY);
+                }
+            }
+        }
+
+        let mut sky = VecDeque::new();
+        let mut glowing = VecDeque::new();
+        for y in 0..CHUNK_HEIGHT {
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    let emission = blocks.get(x, y, z).light_emission();
+                    if emission > 0 {
+                        map.set(x, y, z, map.get(x, y, z).with(Channel::Block, emission));
+                        glowing.push_back(Self::position(x, y, z));
+                    }
+
+                    // only the edges of the sky columns can light anything sideways
+                    if map.get(x, y, z).sky() == MAX_LIGHT {
+                        let dark = |x: usize, z: usize| blocks.get(x, y, z).is_transparent() && map.get(x, y, z).sky() == 0;
+                        if (x > 0 && dark(x - 1, z)) || (x < CHUNK_LENGTH - 1 && dark(x + 1, z)) || (z > 0 && dark(x, z - 1)) || (z < CHUNK_LENGTH - 1 && dark(x, z + 1)) {
+                            sky.push_back(Self::position(x, y, z));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut volume = ChunkVolume { blocks, light: &mut map };
+        spread_channel(&mut volume, Channel::Sky, sky);
+        spread_channel(&mut volume, Channel::Block, glowing);
+        map
+    }
+
+    fn position(x: usize, y: usize, z: usize) -> IVec3 {
+        IVec3::new(x as i32, y as i32, z as i32)
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> Light {
+        self.light[BlockStorage::index(x, y, z)]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, light: Light) {
+        self.light[BlockStorage::index(x, y, z)] = light;
+    }
+
+    /// Neither the block of the voxel nor the voxels of the chunk around it give it its light, it came from a neighbor chunk.
+    pub fn is_lit_from_outside(&self, blocks: &BlockStorage, x: usize, y: usize, z: usize) -> bool {
+        let pos = Self::position(x, y, z);
+        let light = self.get(x, y, z);
+        let block = blocks.get(x, y, z);
+
+        [Channel::Sky, Channel::Block].into_iter().any(|channel| {
+            let level = light.get(channel);
+            if level == 0 || source_level(channel, pos, block) >= level {
+                return false;
+            }
+
+            let lit_by = |((dx, dy, dz), _): ((i32, i32, i32), bool)| {
+                let Some((x, y, z)) = ChunkVolume::local(pos + IVec3::new(dx, dy, dz)) else {
+                    return false;
+                };
+                let neighbor = self.get(x, y, z).get(channel);
+                // full sky light comes down from above without getting darker
+                neighbor > level || (channel == Channel::Sky && dy == 1 && neighbor == MAX_LIGHT)
+            };
+            !NEIGHBORS.into_iter().any(lit_by)
+        })
+    }
+}
+
+impl Default for LightMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Voxels light spreads through, e.g. the loaded world or a single chunk.
+pub trait LightVolume {
+    /// None outside of the volume, light does not spread there.
+    fn block(&self, pos: IVec3) -> Option<BlockType>;
+    /// Dark outside of the volume.
+    fn light(&self, pos: IVec3) -> Light;
+    fn set_light(&mut self, pos: IVec3, light: Light);
+}
+
+/// A single chunk in local coordinates.
+struct ChunkVolume<'a> {
+    blocks: &'a BlockStorage,
+    light: &'a mut LightMap,
+}
+
+impl ChunkVolume<'_> {
+    fn local(pos: IVec3) -> Option<(usize, usize, usize)> {
+        let length = CHUNK_LENGTH as i32;
+        let inside = (0..length).contains(&pos.x) && (0..CHUNK_HEIGHT as i32).contains(&pos.y) && (0..length).contains(&pos.z);
+        inside.then_some((pos.x as usize, pos.y as usize, pos.z as usize))
+    }
+}
+
+impl LightVolume for ChunkVolume<'_> {
+    fn block(&self, pos: IVec3) -> Option<BlockType> {
+        Self::local(pos).map(|(x, y, z)| self.blocks.get(x, y, z))
+    }
+
+    fn light(&self, pos: IVec3) -> Light {
+        Self::local(pos).map_or(Light::default(), |(x, y, z)| self.light.get(x, y, z))
+    }
+
+    fn set_light(&mut self, pos: IVec3, light: Light) {
+        if let Some((x, y, z)) = Self::local(pos) {
+            self.light.set(x, y, z, light);
+        }
+    }
+}
+
+/// Spreads the light of `seeds` into the darker voxels around them, e.g. from the border of a chunk into its neighbor.
+pub fn spread(volume: &mut impl LightVolume, seeds: impl IntoIterator<Item = IVec3>) {
+    let seeds: VecDeque<IVec3> = seeds.into_iter().collect();
+    spread_channel(volume, Channel::Sky, seeds.clone());
+    spread_channel(volume, Channel::Block, seeds);
+}
+
+/// Updates the light around a voxel after its block changed.
+/// The light that came through or from the old block is removed first, then the remaining light around it and its own emission fill the hole again.
+pub fn relight(volume: &mut impl LightVolume, pos: IVec3) {
+    relight_all(volume, [pos]);
+}
+
+/// Same as `relight` for every voxel that changed, e.g. in one fluid tick.
+/// The old light of all of them is removed before any light is spread again, so the voxels around them are only lit once.
+pub fn relight_all(volume: &mut impl LightVolume, changed: impl IntoIterator<Item = IVec3>) {
+    let changed: Vec<(IVec3, BlockType)> = changed.into_iter().filter_map(|pos| volume.block(pos).map(|block| (pos, block))).collect();
+
+    for channel in [Channel::Sky, Channel::Block] {
+        let mut refill = VecDeque::new();
+
+        for &(pos, _) in &changed {
+            let old = volume.light(pos).get(channel);
+            if old > 0 {
+                volume.set_light(pos, volume.light(pos).with(channel, 0));
+                remove_channel(volume, channel, pos, old, &mut refill);
+            }
+        }
+
+        for &(pos, block) in &changed {
+            let source = source_level(channel, pos, block);
+            if source > 0 {
+                volume.set_light(pos, volume.light(pos).with(channel, source));
+                refill.push_back(pos);
+            }
+            refill.extend(NEIGHBORS.map(|((x, y, z), _)| pos + IVec3::new(x, y, z)));
+        }
+        spread_channel(volume, channel, refill);
+    }
+}
+
+/// Light a voxel has on its own, the top of the world is open sky.
+fn source_level(channel: Channel, pos: IVec3, block: BlockType) -> u8 {
+    match channel {
+        Channel::Sky if pos.y == CHUNK_HEIGHT as i32 - 1 && block.is_transparent() => MAX_LIGHT,
+        Channel::Sky => 0,
+        Channel::Block => block.light_emission(),
+    }
+}
+
+/// Breadth first, every voxel passes its level - 1 on, full sky light goes straight down without getting darker.
+fn spread_channel(volume: &mut impl LightVolume, channel: Channel, mut queue: VecDeque<IVec3>) {
+    while let Some(pos) = queue.pop_front() {
+        let level = volume.light(pos).get(channel);
+        if level == 0 {
+            continue;
+        }
+
+        for ((x, y, z), down) in NEIGHBORS {
+            let neighbor = pos + IVec3::new(x, y, z);
+            if !volume.block(neighbor).is_some_and(|block| block.is_transparent()) {
+                continue;
+            }
+
+            let next = if channel == Channel::Sky && down && level == MAX_LIGHT { MAX_LIGHT } else { level - 1 };
+            let light = volume.light(neighbor);
+            if next > light.get(channel) {
+                volume.set_light(neighbor, light.with(channel, next));
+                queue.push_back(neighbor);
+            }
+        }
+    }
+}
+
+/// Darkens every voxel that got its light from `start`, which had `level` before.
+/// Brighter voxels at the edge of the darkened area and sources inside of it are added to `refill`.
+fn remove_channel(volume: &mut impl LightVolume, channel: Channel, start: IVec3, level: u8, refill: &mut VecDeque<IVec3>) {
+    let mut queue = VecDeque::from([(start, level)]);
+
+    while let Some((pos, level)) = queue.pop_front() {
+        for ((x, y, z), down) in NEIGHBORS {
+            let neighbor = pos + IVec3::new(x, y, z);
+            let Some(block) = volume.block(neighbor) else {
+                continue;
+            };
+
+            let light = volume.light(neighbor);
+            let neighbor_level = light.get(channel);
+            if neighbor_level == 0 {
+                continue;
+            }
+
+            let lit_by_pos = neighbor_level < level || (channel == Channel::Sky && down && level == MAX_LIGHT && neighbor_level == MAX_LIGHT);
+            if !lit_by_pos {
+                refill.push_back(neighbor);
+                continue;
+            }
+
+            volume.set_light(neighbor, light.with(channel, 0));
+            queue.push_back((neighbor, neighbor_level));
+
+            let source = source_level(channel, neighbor, block);
+            if source > 0 {
+                volume.set_light(neighbor, light.with(channel, source));
+                refill.push_back(neighbor);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::block::TerrainBlocks;
+
+    fn lava() -> BlockType {
+        BlockType::by_name("lava").unwrap()
+    }
+
+    /// Stone floor at y = 0 under a stone roof at y = 20, so only the blocks light the space between them.
+    fn cave() -> BlockStorage {
+        let stone = TerrainBlocks::global().stone;
+        let mut blocks = BlockStorage::new();
+        for z in 0..CHUNK_LENGTH {
+            for x in 0..CHUNK_LENGTH {
+                blocks.set(x, 0, z, stone);
+                blocks.set(x, 20, z, stone);
+            }
+        }
+        blocks
+    }
+
+    fn all_light(light: &LightMap) -> Vec<Light> {
+        (0..CHUNK_VOLUME).map(|i| light.light[i]).collect()
+    }
+
+    #[test]
+    fn batched_relights_match_single_ones() {
+        let mut blocks = cave();
+        blocks.set(10, 1, 10, lava());
+        let mut single = LightMap::from_blocks(&blocks);
+        let mut batched = single.clone();
+
+        // a lava front moves on, the old cells dry up
+        let changes = [((10, 1, 10), BlockType::AIR), ((11, 1, 10), lava()), ((12, 1, 10), lava()), ((12, 1, 11), lava()), ((5, 3, 5), TerrainBlocks::global().stone)];
+        for ((x, y, z), block) in changes {
+            blocks.set(x, y, z, block);
+        }
+        let positions = changes.map(|((x, y, z), _)| LightMap::position(x, y, z));
+
+        for pos in positions {
+            relight(&mut ChunkVolume { blocks: &blocks, light: &mut single }, pos);
+        }
+        relight_all(&mut ChunkVolume { blocks: &blocks, light: &mut batched }, positions);
+
+        assert!(all_light(&single) == all_light(&batched));
+        assert!(all_light(&batched) == all_light(&LightMap::from_blocks(&blocks)));
+    }
+
+    fn torch() -> BlockType {
+        BlockType::by_name("torch").unwrap()
+    }
+
+    fn place(blocks: &mut BlockStorage, light: &mut LightMap, (x, y, z): (usize, usize, usize), block: BlockType) {
+        blocks.set(x, y, z, block);
+        relight(&mut ChunkVolume { blocks, light }, LightMap::position(x, y, z));
+    }
+
+    #[test]
+    fn light_falls_off_by_one_per_voxel() {
+        let mut blocks = cave();
+        blocks.set(10, 1, 10, torch());
+        let light = LightMap::from_blocks(&blocks);
+
+        assert_eq!(light.get(10, 1, 10), Light::new(0, 14));
+        assert_eq!(light.get(11, 1, 10), Light::new(0, 13));
+        assert_eq!(light.get(10, 4, 10), Light::new(0, 11));
+        assert_eq!(light.get(13, 3, 8), Light::new(0, 7));
+        // the floor and the roof stay dark
+        assert_eq!(light.get(10, 0, 10), Light::default());
+        assert_eq!(light.get(10, 20, 10), Light::default());
+
+        // the sky shines down to the roof without getting darker, then stops
+        assert_eq!(light.get(0, CHUNK_HEIGHT - 1, 0), Light::SKY);
+        assert_eq!(light.get(30, 21, 30), Light::SKY);
+        assert_eq!(light.get(30, 19, 30).sky(), 0);
+    }
+
+    #[test]
+    fn sky_light_spreads_sideways_under_an_overhang() {
+        let stone = TerrainBlocks::global().stone;
+        let mut blocks = BlockStorage::new();
+        for z in 0..CHUNK_LENGTH {
+            for x in 0..CHUNK_LENGTH {
+                blocks.set(x, 0, z, stone);
+                if x >= 10 {
+                    blocks.set(x, 5, z, stone);
+                }
+            }
+        }
+        let light = LightMap::from_blocks(&blocks);
+
+        assert_eq!(light.get(9, 1, 3).sky(), MAX_LIGHT);
+        assert_eq!(light.get(10, 1, 3).sky(), MAX_LIGHT - 1);
+        assert_eq!(light.get(14, 4, 3).sky(), MAX_LIGHT - 5);
+    }
+
+    #[test]
+    fn placing_and_removing_blocks_relights() {
+        let stone = TerrainBlocks::global().stone;
+        let mut blocks = cave();
+        let mut light = LightMap::from_blocks(&blocks);
+
+        place(&mut blocks, &mut light, (10, 1, 10), torch());
+        assert_eq!(light.get(12, 1, 10).block(), 12);
+        assert!(all_light(&light) == all_light(&LightMap::from_blocks(&blocks)));
+
+        // a wall next to the torch, the light goes around it
+        place(&mut blocks, &mut light, (11, 1, 10), stone);
+        assert_eq!(light.get(11, 1, 10), Light::default());
+        assert_eq!(light.get(12, 1, 10).block(), 10);
+        assert!(all_light(&light) == all_light(&LightMap::from_blocks(&blocks)));
+
+        place(&mut blocks, &mut light, (10, 1, 10), BlockType::AIR);
+        assert!(all_light(&light).iter().take(BlockStorage::index(0, 20, 0)).all(|light| *light == Light::default()));
+        assert!(all_light(&light) == all_light(&LightMap::from_blocks(&blocks)));
+
+        // a hole in the roof lets the sky in, closing it makes the cave dark again
+        place(&mut blocks, &mut light, (30, 20, 30), BlockType::AIR);
+        assert_eq!(light.get(30, 1, 30), Light::SKY);
+        assert_eq!(light.get(31, 1, 30).sky(), MAX_LIGHT - 1);
+        assert!(all_light(&light) == all_light(&LightMap::from_blocks(&blocks)));
+
+        place(&mut blocks, &mut light, (30, 20, 30), stone);
+        assert!(all_light(&light) == all_light(&LightMap::from_blocks(&blocks)));
+    }
+
+    #[test]
+    fn light_from_outside_is_found() {
+        let mut blocks = cave();
+        blocks.set(10, 1, 10, torch());
+        let mut light = LightMap::from_blocks(&blocks);
+        assert!(!light.is_lit_from_outside(&blocks, 12, 1, 10));
+        assert!(!light.is_lit_from_outside(&blocks, 30, 30, 30));
+
+        // lit by a neighbor chunk
+        light.set(0, 5, 5, Light::new(0, 9));
+        assert!(light.is_lit_from_outside(&blocks, 0, 5, 5));
+        // passed on to the next voxel, which is still lit from inside
+        light.set(1, 5, 5, Light::new(0, 8));
+        assert!(!light.is_lit_from_outside(&blocks, 1, 5, 5));
+    }
+}
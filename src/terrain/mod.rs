@@ -1,24 +1,35 @@
-use ash::vk::ObjectType;
-use block::{BlockType, GPUBlock};
-use glm::{Mat4, Vec3};
-use libnoise::{Generator, Source};
+use std::{collections::HashSet, sync::Arc};
+
+use binary::{BinaryGrid, Border, FaceMasks};
+use block::{BlockRegistry, BlockType, GPUBlock, Opacity};
+use glm::{IVec3, Vec2, Vec3};
+use fluid::FluidTicks;
+use generator::{DefaultGenerator, GeneratorConfig, TerrainGenerator};
+use jobs::{ChunkJobs, ChunkResult, MeshInput};
+use light::{Light, LightMap, LightVolume};
 use octree::Octree;
+use raycast::RayHit;
+use region::{RegionStore, SaveQueue};
+use storage::{BlockStorage, CHUNK_VOLUME};
 
-use crate::vulkan::mesh::{Face, Vertex, VertexBlock};
+use crate::{
+    t_thread::{Priority, ThreadPool},
+    vulkan::mesh::{Face, VertexBlock},
+};
 
+pub mod binary;
 pub mod block;
+pub mod fluid;
+pub mod generator;
+pub mod jobs;
+pub mod light;
+pub mod noise;
 pub mod octree;
+pub mod raycast;
+pub mod region;
+pub mod storage;
 
-struct Range {
-    start: f32,
-    end: f32,
-}
-
-// over 0.9 is surface
-const SURFACE_LEVEL: Range = Range { start: 0.9, end: 1.0 };
-const STONE_LEVEL: Range = Range { start: 0.9, end: 0.0 };
-const BASE_HEIGHT: u32 = 10;
-
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Biome {
     FlatLand,
     Desert,
@@ -28,116 +39,279 @@ pub enum Biome {
 
 
 
+/// Mesh of a chunk, translucent blocks are drawn after the opaque ones.
+#[derive(Clone, Default)]
+pub struct ChunkMesh {
+    /// Opaque and cutout blocks.
+    pub opaque: Vec<VertexBlock>,
+    /// Has to be sorted back to front before it is drawn, see `GreedyMesh::sort_back_to_front`.
+    pub translucent: Vec<VertexBlock>,
+}
+
+/// Block, light and ambient occlusion of a visible face, only faces with the same key are merged into a quad.
+type FaceKey = (BlockType, Light, [u8; 4]);
+
 pub struct GreedyMesh;
 
 impl GreedyMesh {
-    pub fn create_greedy(mut grid: Vec<GPUBlock>) -> Vec<VertexBlock> {
-        let mut quads = vec![];
-        for y in 0..1 {
-            quads.extend(Self::create_quads(&mut grid, y));
+    const DIMENSIONS: [usize; 3] = [CHUNK_LENGTH, CHUNK_HEIGHT, CHUNK_LENGTH];
+
+    /// Meshes the whole chunk.
+    /// Faces of the same `BlockType` that are visible are merged into as few quads as possible.
+    ///
+    /// Faces in a different light or with a different ambient occlusion are not merged, every quad gets the light of the voxels in front of it.
+    ///
+    /// * `faces` - visible faces from `BinaryGrid::cull_faces` and `FaceMasks::cull_translucent`.
+    /// * `borders` - right, left, front and back neighbor, for the light in front of the faces on the chunk border.
+    /// * `origin` - world position of the chunk's (0, 0, 0) voxel.
+    pub fn create_greedy(blocks: &BlockStorage, light: &LightMap, faces: &FaceMasks, borders: [Option<&Border>; 4], origin: Vec3) -> ChunkMesh {
+        let mut mesh = ChunkMesh::default();
+        for face in Face::ALL {
+            Self::create_quads(blocks, light, faces, borders, face, origin, &mut mesh);
         }
-        quads
+        mesh
     }
 
-    fn create_quads(grid: &mut Vec<GPUBlock>, y: usize) -> Vec<VertexBlock> {
-        let mut x_start = 0;
-        let mut z_start = 0;
+    /// Light of the voxel a face looks into, missing neighbors count as open sky.
+    fn face_light(light: &LightMap, borders: [Option<&Border>; 4], face: Face, x: usize, y: usize, z: usize) -> Light {
+        let [right, left, front, back] = borders;
+        let last = CHUNK_LENGTH - 1;
+
+        match face {
+            Face::Right if x == last => right.map_or(Light::SKY, |b| b.light(y, z)),
+            Face::Right => light.get(x + 1, y, z),
+            Face::Left if x == 0 => left.map_or(Light::SKY, |b| b.light(y, z)),
+            Face::Left => light.get(x - 1, y, z),
+            Face::Top if y == CHUNK_HEIGHT - 1 => Light::SKY,
+            Face::Top => light.get(x, y + 1, z),
+            Face::Bottom if y == 0 => Light::default(),
+            Face::Bottom => light.get(x, y - 1, z),
+            Face::Front if z == last => front.map_or(Light::SKY, |b| b.light(y, x)),
+            Face::Front => light.get(x, y, z + 1),
+            Face::Back if z == 0 => back.map_or(Light::SKY, |b| b.light(y, x)),
+            Face::Back => light.get(x, y, z - 1),
+        }
+    }
 
-        let y_offset = y * CHUNK_AREA_LENGTH;
+    /// Sorts the quads from the farthest to the nearest, so blending them draws the nearest one last.
+    pub fn sort_back_to_front(vertices: &mut Vec<VertexBlock>, eye: Vec3) {
+        let distance = |quad: &[VertexBlock]| {
+            let center = quad.iter().fold(Vec3::zero(), |sum, vertex| sum + vertex.pos) / quad.len() as f32;
+            (center - eye).mag_sq()
+        };
 
-        // find a non empty place
-        'outer: for z in 0..CHUNK_LENGTH {
-            let z_offset = z * CHUNK_LENGTH;
-            for x in 0..CHUNK_LENGTH {
-                let block = grid[x + z_offset + y_offset];
+        let mut quads: Vec<(f32, &[VertexBlock])> = vertices.chunks_exact(6).map(|quad| (distance(quad), quad)).collect();
+        quads.sort_by(|a, b| b.0.total_cmp(&a.0));
 
-                if block.block_type() != BlockType::Air {
-                    x_start = x;
-                    z_start = z;
-                    break 'outer;
-                }
-            }
-        }
+        *vertices = quads.into_iter().flat_map(|(_, quad)| quad.iter().copied()).collect();
+    }
 
-        let mut width = 0;
-        let mut length = 0;
+    /// Classic voxel ambient occlusion of a face corner from the three voxels in front of the face that touch it.
+    /// 3 is not occluded, 0 is a corner between two blocks, where the diagonal block does not matter anymore.
+    pub fn vertex_ao(side1: bool, side2: bool, corner: bool) -> u8 {
+        if side1 && side2 {
+            return 0;
+        }
+        3 - (side1 as u8 + side2 as u8 + corner as u8)
+    }
 
-        let mut steps_z = 0;
-        let mut quads = vec![];
+    /// Occlusion of the 4 corners of a voxel face, indexed by u + 2 * v of the corner, see `vertex_ao`.
+    fn face_ao(blocks: &BlockStorage, borders: [Option<&Border>; 4], face: Face, pos: [usize; 3]) -> [u8; 4] {
+        let axis = face.axis();
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+
+        // the layer of voxels the face looks into
+        let mut layer = pos.map(|p| p as i32);
+        layer[axis] += if face.is_positive() { 1 } else { -1 };
+
+        let opaque = |du: i32, dv: i32| {
+            let mut neighbor = layer;
+            neighbor[u] += du;
+            neighbor[v] += dv;
+            Self::is_opaque(blocks, borders, neighbor)
+        };
 
-        'outer: loop {
-            if (width + x_start + 1) < CHUNK_LENGTH {
-                let block = &mut grid[width + y_offset];
+        [(-1, -1), (1, -1), (-1, 1), (1, 1)].map(|(du, dv)| Self::vertex_ao(opaque(du, 0), opaque(0, dv), opaque(du, dv)))
+    }
 
-                if block.block_type() != BlockType::Air {
-                    width += 1;
-                    block.texture_index = BlockType::Air;
-                }
-            } else {
-                // check in z direction now
+    /// Voxels of the neighbors come from their borders, the diagonal chunks and outside of the height count as air.
+    fn is_opaque(blocks: &BlockStorage, borders: [Option<&Border>; 4], [x, y, z]: [i32; 3]) -> bool {
+        let [right, left, front, back] = borders;
+        let length = CHUNK_LENGTH as i32;
+        if !(0..CHUNK_HEIGHT as i32).contains(&y) {
+            return false;
+        }
 
-                loop {
-                    if (length + z_start + 1) < CHUNK_LENGTH {
-                        let z_offset = (length + z_start + 1) * CHUNK_LENGTH;
+        let inside = |i: i32| (0..length).contains(&i);
+        let block = match (inside(x), inside(z)) {
+            (true, true) => Some(blocks.get(x as usize, y as usize, z as usize)),
+            (false, true) if x == length => right.map(|b| b.get(y as usize, z as usize)),
+            (false, true) if x == -1 => left.map(|b| b.get(y as usize, z as usize)),
+            (true, false) if z == length => front.map(|b| b.get(y as usize, x as usize)),
+            (true, false) if z == -1 => back.map(|b| b.get(y as usize, x as usize)),
+            _ => None,
+        };
+        block.is_some_and(|block| !block.is_transparent())
+    }
 
-                        let mut extend = true;
-                        for w in 0..width {
-                            let new_block = grid[z_offset + w + x_start].block_type();
+    /// Sweeps every slice along the face axis, builds a mask of the visible faces and merges it into rectangles.
+    fn create_quads(blocks: &BlockStorage, light: &LightMap, faces: &FaceMasks, borders: [Option<&Border>; 4], face: Face, origin: Vec3, mesh: &mut ChunkMesh) {
+        let axis = face.axis();
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+
+        let width = Self::DIMENSIONS[u];
+        let height = Self::DIMENSIONS[v];
+
+        let mut mask: Vec<Option<FaceKey>> = vec![None; width * height];
+
+        for slice in 0..Self::DIMENSIONS[axis] {
+            for j in 0..height {
+                for i in 0..width {
+                    let mut pos = [0; 3];
+                    pos[axis] = slice;
+                    pos[u] = i;
+                    pos[v] = j;
+
+                    mask[i + j * width] = match faces.is_visible(face, pos[0], pos[1], pos[2]) {
+                        true => Some((
+                            blocks.get(pos[0], pos[1], pos[2]),
+                            Self::face_light(light, borders, face, pos[0], pos[1], pos[2]),
+                            Self::face_ao(blocks, borders, face, pos),
+                        )),
+                        false => None,
+                    };
+                }
+            }
 
-                            if new_block == BlockType::Air {
-                                extend = false;
-                                break;
-                            }
-                        }
+            for j in 0..height {
+                let mut i = 0;
+                while i < width {
+                    let Some(key) = mask[i + j * width] else {
+                        i += 1;
+                        continue;
+                    };
+
+                    let mut quad_width = 1;
+                    while i + quad_width < width && mask[i + quad_width + j * width] == Some(key) {
+                        quad_width += 1;
+                    }
 
-                        if extend {
-                            length += 1;
-                            for w in 0..width {
-                                let new_block = &mut grid[z_offset + w + x_start];
-                                new_block.texture_index = BlockType::Air;
+                    let mut quad_height = 1;
+                    'grow: while j + quad_height < height {
+                        let row = (j + quad_height) * width;
+                        for k in i..i + quad_width {
+                            if mask[k + row] != Some(key) {
+                                break 'grow;
                             }
-                        } else {
-                            //
-
-                            // create quad
-                            quads.extend(VertexBlock::new_quad(
-                                Vec3::new(x_start as f32, y as f32 - 1.0, z_start as f32),
-                                Vec3::new(width as f32, 1.0, length as f32),
-                            ));
-
-                            x_start = x_start + length;
-                            z_start = z_start + 1;
-                            width = 0;
-                            length = 0;
-                            break;
                         }
-                    } else {
-                        quads.extend(VertexBlock::new_quad(
-                            Vec3::new(x_start as f32, y as f32 - 1.0, z_start as f32),
-                            Vec3::new(width as f32, 1.0, length as f32),
-                        ));
+                        quad_height += 1;
+                    }
 
-                        break 'outer;
+                    for row in j..j + quad_height {
+                        mask[i + row * width..i + quad_width + row * width].fill(None);
                     }
+
+                    let mut corner = [0; 3];
+                    corner[axis] = slice + face.is_positive() as usize;
+                    corner[u] = i;
+                    corner[v] = j;
+
+                    let mut size = [0; 3];
+                    size[u] = quad_width;
+                    size[v] = quad_height;
+
+                    let (block, light, ao) = key;
+                    let vertices = match block.opacity() {
+                        Opacity::Translucent => &mut mesh.translucent,
+                        Opacity::Opaque | Opacity::Cutout => &mut mesh.opaque,
+                    };
+                    Self::push_quad(vertices, face, (block, light, ao), origin, corner, size);
+
+                    i += quad_width;
                 }
             }
         }
+    }
+
+    /// Pushes two clockwise triangles, same winding as `VertexBlock::get_mesh`.
+    /// The uv is the size of the quad so the texture repeats once per voxel.
+    /// The quad is split along the diagonal with more occlusion, otherwise the occlusion of a single corner is stretched unevenly over it.
+    fn push_quad(vertices: &mut Vec<VertexBlock>, face: Face, (block, light, ao): FaceKey, origin: Vec3, corner: [usize; 3], size: [usize; 3]) {
+        let axis = face.axis();
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+
+        let mut offsets = [[0; 3]; 4];
+        offsets[1][u] = size[u];
+        offsets[2][u] = size[u];
+        offsets[2][v] = size[v];
+        offsets[3][v] = size[v];
+
+        // u x v points along the positive axis, counter clockwise seen from the outside
+        if !face.is_positive() {
+            offsets.swap(1, 3);
+        }
+
+        let norm = face.normal();
+        let to_vertex = |offset: [usize; 3]| {
+            let pos = Vec3::new((corner[0] + offset[0]) as f32, (corner[1] + offset[1]) as f32, (corner[2] + offset[2]) as f32);
+
+            let uv = match axis {
+                0 => Vec2::new(offset[2] as f32, (size[1] - offset[1]) as f32),
+                1 => Vec2::new(offset[0] as f32, offset[2] as f32),
+                _ => Vec2::new(offset[0] as f32, (size[1] - offset[1]) as f32),
+            };
+
+            let corner_ao = ao[(offset[u] > 0) as usize + 2 * (offset[v] > 0) as usize];
 
-        quads
+            VertexBlock::new(origin + pos * VOXEL_SCALE, norm, uv, face as u32).with_block_type(block.as_raw()).with_light(light.as_raw() as u32).with_ao(corner_ao as u32)
+        };
+
+        // offsets 0 and 2 are the corners 0 and 3 of `ao`
+        let indices = if ao[0] + ao[3] > ao[1] + ao[2] { [1, 0, 3, 1, 3, 2] } else { [0, 2, 1, 0, 3, 2] };
+        for i in indices {
+            vertices.push(to_vertex(offsets[i]));
+        }
     }
 }
 
 pub struct World {
     player_pos: Vec3,
     player_distance: usize,
+    /// Chunks are unloaded past this distance, the gap to `player_distance` stops them from reloading on a border.
+    unload_distance: usize,
 
+    loader: Arc<ChunkLoader>,
     root: Octree,
+    jobs: ChunkJobs,
+
+    /// Chunks that changed since they were last meshed.
+    dirty: HashSet<(i32, i32)>,
+    /// Chunks whose load panicked, they are not loaded again.
+    failed: HashSet<(i32, i32)>,
+    /// Fluid cells that flow on a later `tick`.
+    fluid_ticks: FluidTicks,
+    /// Chunks in view that are not loaded yet, the most important first.
+    load_queue: Vec<(i32, i32)>,
+    /// How many finished jobs `update` applies at most, keeps a frame from stalling when a lot of them finish at once.
+    pub results_per_update: usize,
+    /// Loads running on the thread pool at the same time, the rest waits in the queue so it can still be reordered.
+    pub max_loading: usize,
 }
 
 impl World {
     /// Player distance in chunk range
     pub fn new(player_pos: Vec3, player_distance: usize) -> Self {
-        let root = Octree::new(glm::Vec2::new(player_pos.x, player_pos.z), player_distance, 3);
+        let generator = Arc::new(DefaultGenerator::new(GeneratorConfig::default()));
+        Self::with_loader(player_pos, player_distance, ChunkLoader::new(generator, None))
+    }
+
+    /// Loads every chunk in view before returning, afterwards `update` loads them in the background.
+    pub fn with_loader(player_pos: Vec3, player_distance: usize, loader: ChunkLoader) -> Self {
+        let unload_distance = player_distance + Self::UNLOAD_MARGIN;
+        let root = Octree::new(glm::Vec2::new(player_pos.x, player_pos.z), player_distance, unload_distance, Octree::depth_for_view(unload_distance));
 
         // let chunk_start_x = (player_pos.x as f64 / CHUNK_LENGTH as f64) - 2 as f64;
         // let chunk_start_z = (player_pos.z as f64 / CHUNK_LENGTH as f64) - 2 as f64;
@@ -151,448 +325,743 @@ impl World {
 
         // let mut chunk_areas = vec![];
 
-        // chunk_areas.push(ChunkArea::new((chunk_area_x, chunk_area_z)));
+        // chunk_areas.push(ChunkArea::new((chunk_area_x, chunk_area_z), generator));
 
         // for z in 0..area_amount {
         //     for x in 0..area_amount {
-        //         chunk_areas.push(ChunkArea::new((chunk_area_x + x, chunk_area_z + z)));
+        //         chunk_areas.push(ChunkArea::new((chunk_area_x + x, chunk_area_z + z), generator));
         //     }
         // }
 
-        Self { player_pos, player_distance, root }
+        let mut world = Self {
+            player_pos,
+            player_distance,
+            unload_distance,
+            loader: Arc::new(loader),
+            root,
+            jobs: ChunkJobs::new(),
+            dirty: HashSet::new(),
+            failed: HashSet::new(),
+            fluid_ticks: FluidTicks::new(),
+            load_queue: vec![],
+            results_per_update: 8,
+            max_loading: 32,
+        };
+        world.wait_for_chunks();
+        world
     }
 
-    pub fn get_culled(&self, player_pos: Vec3) -> Vec<GPUBlock> {
-        // let mut objects = vec![];
-        // for area in &self.chunk_areas {
-        //     objects.extend(area.get_culled_objects().clone());
-        // }
+    /// Chunks between the load and unload distance.
+    pub const UNLOAD_MARGIN: usize = 2;
 
-        // objects
+    /// Rate `tick` is meant to be called at.
+    pub const TICKS_PER_SECOND: u32 = 20;
 
-        // let node = self.root.find_node(glm::Vec2::new(player_pos.x, player_pos.z));
-        // node.get_objects()
-        todo!();
+    /// Follows the player, nearest chunks are loaded first.
+    pub fn update(&mut self, player_pos: Vec3) {
+        self.update_in_view(player_pos, |_| true);
     }
-}
 
-const CHUNK_AREA_LENGTH: usize = 16;
+    /// Same as `update`, chunks in view are loaded before the ones behind the player.
+    /// `in_view` gets the center of a chunk, e.g. `|pos| frustum.is_inside(pos)`.
+    pub fn update_in_view(&mut self, player_pos: Vec3, in_view: impl Fn(Vec3) -> bool) {
+        self.schedule(player_pos, in_view);
 
-struct ChunkArea {
-    chunks: Vec<Chunk>,
-    // Area offset
-    pub offset: (i32, i32),
-}
+        for _ in 0..self.results_per_update {
+            let Some(result) = self.jobs.try_recv() else {
+                break;
+            };
+            self.apply(result);
+        }
 
-impl ChunkArea {
-    pub fn new(offset: (i32, i32)) -> ChunkArea {
-        let chunk_start_x = offset.0 * CHUNK_AREA_LENGTH as i32 - 1;
-        let chunk_start_z = offset.1 * CHUNK_AREA_LENGTH as i32 - 1;
+        self.schedule_meshes();
+    }
 
-        let mut chunks = vec![];
-        for z in 0..CHUNK_AREA_LENGTH as i32 + 2 {
-            for x in 0..CHUNK_AREA_LENGTH as i32 + 2 {
-                chunks.push(Chunk::new(chunk_start_x + x, chunk_start_z + z));
-            }
-        }
+    /// Blocks until every chunk in view is loaded and meshed.
+    pub fn wait_for_chunks(&mut self) {
+        loop {
+            self.schedule(self.player_pos, |_| true);
+            self.schedule_meshes();
 
-        for z in 1..CHUNK_AREA_LENGTH + 1 {
-            let z_offset = z * (CHUNK_AREA_LENGTH + 2);
-            for x in 1..CHUNK_AREA_LENGTH + 1 {
-                let block_offset = z_offset + x;
-
-                chunks[block_offset].culled_blocks = Chunk::occlusion_cull(
-                    &chunks[block_offset].all_blocks,
-                    &chunks[block_offset + 1],
-                    &chunks[block_offset - 1],
-                    &chunks[block_offset + CHUNK_AREA_LENGTH + 2],
-                    &chunks[block_offset - CHUNK_AREA_LENGTH + 2],
-                );
+            match self.jobs.recv() {
+                Some(result) => self.apply(result),
+                None if self.load_queue.is_empty() => break,
+                None => {}
             }
         }
-
-        Self { chunks, offset }
     }
-    pub fn get_culled_objects(&self) -> Vec<VertexBlock> {
-        let mut culled = vec![];
-        let mut ii = 0;
-        for z in 1..CHUNK_AREA_LENGTH + 1 {
-            let z_offset = z * (CHUNK_AREA_LENGTH + 2);
-            for x in 1..CHUNK_AREA_LENGTH + 1 {
-                let block_offset = z_offset + x;
-                ii = block_offset;
-                culled.extend(self.chunks[block_offset].quads.clone());
+
+    /// Writes every edited chunk, the loaded ones and the ones that are still being saved in the background.
+    /// Called when the world is dropped.
+    pub fn save_all(&mut self) {
+        // the loaded chunks are newer than an unloaded copy that is still waiting
+        self.loader.flush();
+
+        let edited: Vec<(i32, i32)> = self.root.chunks().into_iter().filter(|chunk| chunk.edited).map(|chunk| (chunk.chunk_x, chunk.chunk_z)).collect();
+        for (chunk_x, chunk_z) in edited {
+            let Some(chunk) = self.root.chunk_mut(chunk_x, chunk_z) else {
+                continue;
+            };
+            match self.loader.save(chunk) {
+                Ok(()) => chunk.edited = false,
+                Err(e) => log::error!("Failed to save chunk ({}, {}): {}", chunk_x, chunk_z, e),
             }
         }
-
-        culled
     }
-}
 
-/// Save the chunks into a uniform buffer with their model. Then the object will
-pub const CHUNK_LENGTH: usize = 64;
-pub const VOXEL_SCALE: f32 = 1.0;
-const CHUNK_HEIGHT: usize = 90;
+    /// Updates the wanted chunks, cancels the loads that left the unload distance and starts new ones.
+    fn schedule(&mut self, player_pos: Vec3, in_view: impl Fn(Vec3) -> bool) {
+        self.player_pos = player_pos;
+        let target = glm::Vec2::new(player_pos.x, player_pos.z);
 
-pub struct Chunk {
-    pub all_blocks: Vec<GPUBlock>,
-    pub quads: Vec<VertexBlock>,
-    pub culled_blocks: Vec<GPUBlock>,
-    pub binary_grid: Vec<u64>,
-}
+        let unload_distance = self.unload_distance as f32;
+        self.jobs.retain_loads(|x, z| Octree::chunk_distance(target, x, z) <= unload_distance);
 
-impl Chunk {
-    pub fn new(x: i32, z: i32) -> Self {
-        let all_blocks = Self::generate_chunk(x, z);
-        //  let all_blocks = Self::generate_chunk_test(x, z, BlockType::Air).all_blocks;
-        let all_blockss = all_blocks.clone();
-        Self {
-            all_blocks,
-            culled_blocks: vec![],
-            quads: GreedyMesh::create_greedy(all_blockss),
-            binary_grid: vec![],
+        let loaded = self.loaded_chunks();
+        let missing = self.root.update(target, &self.loader);
+        for (chunk_x, chunk_z) in loaded {
+            if self.root.chunk(chunk_x, chunk_z).is_none() {
+                self.unlight_borders(chunk_x, chunk_z);
+            }
         }
-    }
 
-    fn update_binary_mask(&mut self) {
-        let mut grid: Vec<u64> = Vec::with_capacity(64 * 64);
+        let (jobs, failed) = (&self.jobs, &self.failed);
+        let mut queue: Vec<_> = missing
+            .into_iter()
+            .filter(|&(x, z)| !jobs.is_loading(x, z) && !failed.contains(&(x, z)))
+            .map(|(x, z)| {
+                let center = BlockStorage::world_position(x, z, CHUNK_LENGTH / 2, CHUNK_HEIGHT / 2, CHUNK_LENGTH / 2);
+                (!in_view(center), Octree::chunk_distance(target, x, z), (x, z))
+            })
+            .collect();
+        queue.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        self.load_queue = queue.into_iter().map(|(_, _, chunk)| chunk).collect();
+
+        let count = self.max_loading.max(1).saturating_sub(self.jobs.loading_count()).min(self.load_queue.len());
+        for (x, z) in self.load_queue.drain(..count) {
+            let priority = Self::job_priority(target, x, z, self.player_distance);
+            self.jobs.load(&self.loader, x, z, priority);
+        }
     }
 
-    pub fn generate_chunk_test(chunk_x: i32, chunk_z: i32, block_type: BlockType) -> Chunk {
-        let x_start = chunk_x as f32 * CHUNK_LENGTH as f32;
-        let z_start = chunk_z as f32 * CHUNK_LENGTH as f32;
+    /// Chunks around the player first, the edge of the view last.
+    fn job_priority(target: glm::Vec2, chunk_x: i32, chunk_z: i32, player_distance: usize) -> Priority {
+        let distance = Octree::chunk_distance(target, chunk_x, chunk_z);
+        if distance < 1.0 {
+            Priority::High
+        } else if distance <= player_distance as f32 / 2.0 {
+            Priority::Normal
+        } else {
+            Priority::Background
+        }
+    }
 
-        let mut grid = [[0u32; 16]; 16];
-        let amplitude = 10.0;
-        let seed = 12004690;
-        let hill_effect = 1.0;
-        let generator = Source::simplex(seed).add(1.0).scale([0.01, 0.01]);
+    /// Sends the dirty chunks to the thread pool, their neighbors are copied for the culling.
+    fn schedule_meshes(&mut self) {
+        let target = glm::Vec2::new(self.player_pos.x, self.player_pos.z);
+
+        for (chunk_x, chunk_z) in std::mem::take(&mut self.dirty) {
+            let Some(chunk) = self.root.chunk(chunk_x, chunk_z) else {
+                continue;
+            };
+            let neighbors = self.neighbors(chunk_x, chunk_z);
+
+            let input = MeshInput {
+                blocks: chunk.blocks.clone(),
+                light: chunk.light.clone(),
+                grid: chunk.binary_grid.clone(),
+                neighbors: neighbors.map(|neighbor| neighbor.map(|c| c.binary_grid.clone())),
+                borders: Self::borders(neighbors),
+            };
+            // a missing mesh is a hole in the world, it goes before loading new chunks
+            let priority = match Self::job_priority(target, chunk_x, chunk_z, self.player_distance) {
+                Priority::High => Priority::Critical,
+                _ => Priority::High,
+            };
+            self.jobs.mesh(chunk_x, chunk_z, input, priority);
+        }
+    }
 
-        for x in 0..CHUNK_LENGTH {
-            for z in 0..CHUNK_LENGTH {
-                let nx = (x as f64 + x_start as f64) / 16.0;
-                let nz = (z as f64 + z_start as f64) / 16.0;
+    fn apply(&mut self, result: ChunkResult) {
+        match result {
+            ChunkResult::Loaded(chunk) => {
+                let (x, z) = (chunk.chunk_x, chunk.chunk_z);
 
-                let noise = generator.sample([nx + x_start as f64, nz + z_start as f64]);
-                let processed_noise =
-                    (((noise * hill_effect).round() / hill_effect) * amplitude).round() as u32 + (CHUNK_HEIGHT as u32 - amplitude as u32);
+                let target = glm::Vec2::new(self.player_pos.x, self.player_pos.z);
+                if Octree::chunk_distance(target, x, z) > self.unload_distance as f32 {
+                    self.loader.unload(*chunk);
+                    return;
+                }
+                if let Err(chunk) = self.root.insert(*chunk) {
+                    self.loader.unload(*chunk);
+                    return;
+                }
 
-                grid[x][z] = processed_noise;
+                // the borders of the neighbors were meshed without this chunk
+                self.dirty.extend([(x, z), (x + 1, z), (x - 1, z), (x, z + 1), (x, z - 1)]);
+                self.light_borders(x, z);
             }
-        }
-
-        let mut gpu_blocks = vec![];
-        for y in 0..CHUNK_HEIGHT {
-            for z in 0..CHUNK_LENGTH {
-                for x in 0..CHUNK_LENGTH {
-                    let height = grid[x][z];
-                    gpu_blocks.push(GPUBlock::new(Vec3::new(x as f32 + x_start, y as f32, z as f32 + z_start), block_type));
+            ChunkResult::Meshed { chunk_x, chunk_z, mesh } => {
+                if let Some(chunk) = self.root.chunk_mut(chunk_x, chunk_z) {
+                    chunk.quads = mesh.opaque;
+                    chunk.translucent_quads = mesh.translucent;
                 }
             }
+            ChunkResult::LoadFailed { chunk_x, chunk_z } => {
+                log::error!("Failed to load chunk ({}, {}), it is left out", chunk_x, chunk_z);
+                self.failed.insert((chunk_x, chunk_z));
+            }
+            ChunkResult::MeshFailed { chunk_x, chunk_z } => log::error!("Failed to mesh chunk ({}, {})", chunk_x, chunk_z),
         }
+    }
 
-        //  Self { all_blocks: gpu_blocks, culled_blocks: vec![], quads: vec![] };
-        todo!();
+    pub fn player_pos(&self) -> Vec3 {
+        self.player_pos
     }
 
-    pub fn generate_chunk(chunk_x: i32, chunk_z: i32) -> Vec<GPUBlock> {
-        let x_start = chunk_x as f32 * 16.0;
-        let z_start = chunk_z as f32 * 16.0;
-        let mut grid = [[0u32; CHUNK_LENGTH]; CHUNK_LENGTH];
-        let amplitude = 10.0;
-        let seed = 12004690;
-        let hill_effect = 15.0;
-        let generator = Source::simplex(seed).add(1.0).scale([0.2, 0.2]);
+    /// Chunks in view that still have to be loaded.
+    pub fn pending_loads(&self) -> &[(i32, i32)] {
+        &self.load_queue
+    }
 
-        let surface_start = (SURFACE_LEVEL.start * CHUNK_HEIGHT as f32).round() as u32;
+    pub fn loaded_chunks(&self) -> Vec<(i32, i32)> {
+        self.root.chunks().iter().map(|chunk| (chunk.chunk_x, chunk.chunk_z)).collect()
+    }
 
-        for x in 0..CHUNK_LENGTH {
-            for z in 0..CHUNK_LENGTH {
-                let nx = (x as f64 + x_start as f64) / CHUNK_LENGTH as f64;
-                let nz = (z as f64 + z_start as f64) / CHUNK_LENGTH as f64;
-                grid[x][z] = surface_start;
-                // grid[x][z] =
-                //     (((generator.sample([nx as f64, nz as f64]) * hill_effect).round() / hill_effect) * amplitude).round() as u32 + surface_start;
-            }
+    /// Chunk coordinate and local position of a voxel, None if it is above or below the world.
+    pub fn locate(pos: IVec3) -> Option<VoxelLocation> {
+        if pos.y < 0 || pos.y >= CHUNK_HEIGHT as i32 {
+            return None;
         }
 
-        let mut gpu_blocks = vec![];
-
-        let surface_end = (SURFACE_LEVEL.end * CHUNK_HEIGHT as f32).round();
+        let length = CHUNK_LENGTH as i32;
+        let chunk = (pos.x.div_euclid(length), pos.z.div_euclid(length));
+        let local = (pos.x.rem_euclid(length) as usize, pos.y as usize, pos.z.rem_euclid(length) as usize);
 
-        for y in 0..CHUNK_HEIGHT {
-            for z in 0..CHUNK_LENGTH {
-                for x in 0..CHUNK_LENGTH {
-                    let height = grid[x][z];
-                    if height < y as u32 {
-                        gpu_blocks.push(GPUBlock::new(Vec3::new(x as f32 + x_start, y as f32, z as f32 + z_start), BlockType::Air))
-                    } else {
-                        if y as u32 >= surface_start {
-                            gpu_blocks.push(GPUBlock::new(Vec3::new(x as f32 + x_start, y as f32, z as f32 + z_start), BlockType::Dirt));
-                        } else {
-                            gpu_blocks.push(GPUBlock::new(Vec3::new(x as f32 + x_start, y as f32, z as f32 + z_start), BlockType::Stone));
-                        }
-                    }
-                }
-            }
-        }
-        gpu_blocks
+        Some((chunk, local))
     }
 
-    pub fn occlusion_cull(objects: &Vec<GPUBlock>, right: &Chunk, left: &Chunk, front: &Chunk, back: &Chunk) -> Vec<GPUBlock> {
-        let mut culled_objects = vec![];
-
-        Self::corner_cases(&mut culled_objects, objects, right, left, front, back);
-        Self::edges_cases(&mut culled_objects, objects, right, left, front, back);
-        Self::middle_cases(&mut culled_objects, objects);
-
-        culled_objects
+    /// None if the chunk is not loaded or the position is outside of the world height.
+    pub fn get_block(&self, pos: IVec3) -> Option<BlockType> {
+        let ((chunk_x, chunk_z), (x, y, z)) = Self::locate(pos)?;
+        let chunk = self.root.chunk(chunk_x, chunk_z)?;
+        Some(chunk.blocks.get(x, y, z))
     }
 
-    pub fn get_objects(&self) -> Vec<GPUBlock> {
-        // let mut culled = vec![];
+    /// First block along the ray that can be selected, e.g. `world.raycast(camera.get_pos(), camera.get_front(), 8.0)`.
+    /// The ray goes through air and fluids, see `raycast::selectable`. Unloaded chunks count as air.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RayHit> {
+        self.raycast_with(origin, direction, max_distance, raycast::selectable)
+    }
 
-        // for i in 0..self.all_blocks.len() {
-        //     if self.all_blocks[i].block_type().bit_mask() != BlockType::Air.bit_mask() {
-        //         culled.push(self.all_blocks[i]);
-        //     }
-        // }
-        // self.quads.clone()
-        todo!();
+    /// Same as `raycast`, the ray stops at the first block `stops` returns true for, e.g. a bucket picking up water.
+    pub fn raycast_with(&self, origin: Vec3, direction: Vec3, max_distance: f32, stops: impl Fn(BlockType) -> bool) -> Option<RayHit> {
+        raycast::raycast(origin, direction, max_distance, |pos| self.get_block(pos).filter(|block| stops(*block)))
     }
 
-    fn get_block_up_bitmask(blocks: &Vec<GPUBlock>, current_offset: usize, current_y: usize) -> u64 {
-        if (current_y + 1) == CHUNK_HEIGHT {
-            return 1;
+    /// Changes a block and marks the chunks that have to be remeshed.
+    /// Returns false if the chunk is not loaded.
+    pub fn set_block(&mut self, pos: IVec3, block: BlockType) -> bool {
+        match self.change_block(pos, block) {
+            Some(true) => light::relight(self, pos),
+            Some(false) => {}
+            None => return false,
         }
-        blocks[current_offset + CHUNK_LENGTH * CHUNK_LENGTH].block_type().bit_mask()
+        true
     }
 
-    fn corner_cases(culled_objects: &mut Vec<GPUBlock>, objects: &Vec<GPUBlock>, right: &Chunk, left: &Chunk, front: &Chunk, back: &Chunk) {
-        let mut chunk_area = CHUNK_LENGTH * CHUNK_LENGTH;
-        // do the x edge with chunk (Back and front)
-
-        // TODO, check edges
-        for y in 0..CHUNK_HEIGHT {
-            let y_offset = y * chunk_area;
-            let y_offset_down = (y + 1) * chunk_area;
+    /// `set_block` without relighting, None if the chunk is not loaded.
+    /// Returns true if the light around the voxel has to be updated, only a change of opacity or emission does that.
+    fn change_block(&mut self, pos: IVec3, block: BlockType) -> Option<bool> {
+        let ((chunk_x, chunk_z), (x, y, z)) = Self::locate(pos)?;
+        let chunk = self.root.chunk_mut(chunk_x, chunk_z)?;
 
-            let offset_top_right = y_offset + (CHUNK_LENGTH * CHUNK_LENGTH) - 1;
-            let offset_top_left = y_offset + (CHUNK_LENGTH * (CHUNK_LENGTH - 1));
-            let offset_bot_right = y_offset + (CHUNK_LENGTH) - 1;
-            let offset_bot_left = y_offset;
+        let old = chunk.blocks.get(x, y, z);
+        if old == block {
+            return Some(false);
+        }
+        chunk.set_block(x, y, z, block);
+        self.mark_dirty((chunk_x, chunk_z), (x, z));
+        self.schedule_fluids(pos);
 
-            // Bottom Left Corner
-            {
-                let bot_left = objects[offset_bot_left];
+        Some(old.is_transparent() != block.is_transparent() || old.light_emission() != block.light_emission())
+    }
 
-                if bot_left.block_type() != BlockType::Air {
-                    let block_behind = back.all_blocks[offset_top_left].block_type().bit_mask();
-                    let block_left = left.all_blocks[offset_bot_right].block_type().bit_mask();
+    /// Marks the chunk of a voxel that changed, and the neighbor whose faces touch the voxel.
+    fn mark_dirty(&mut self, (chunk_x, chunk_z): (i32, i32), (x, z): (usize, usize)) {
+        self.dirty.insert((chunk_x, chunk_z));
 
-                    let block_front = objects[offset_bot_left + CHUNK_LENGTH].block_type().bit_mask();
-                    let block_right = objects[offset_bot_left + 1].block_type().bit_mask();
+        if x == 0 {
+            self.dirty.insert((chunk_x - 1, chunk_z));
+        } else if x == CHUNK_LENGTH - 1 {
+            self.dirty.insert((chunk_x + 1, chunk_z));
+        }
+        if z == 0 {
+            self.dirty.insert((chunk_x, chunk_z - 1));
+        } else if z == CHUNK_LENGTH - 1 {
+            self.dirty.insert((chunk_x, chunk_z + 1));
+        }
+    }
 
-                    let block_up = Self::get_block_up_bitmask(objects, offset_bot_left, y);
+    /// None if the chunk is not loaded or the position is outside of the world height.
+    pub fn get_light(&self, pos: IVec3) -> Option<Light> {
+        let ((chunk_x, chunk_z), (x, y, z)) = Self::locate(pos)?;
+        let chunk = self.root.chunk(chunk_x, chunk_z)?;
+        Some(chunk.light.get(x, y, z))
+    }
 
-                    let is_air = (block_behind | block_left | block_front | block_right | block_up) & BlockType::Air.bit_mask();
+    /// Lets the light of a chunk that was just loaded and of its loaded neighbors flow over their shared borders.
+    fn light_borders(&mut self, chunk_x: i32, chunk_z: i32) {
+        let Some(chunk) = self.root.chunk(chunk_x, chunk_z) else {
+            return;
+        };
 
-                    if is_air == BlockType::Air.bit_mask() {
-                        culled_objects.push(bot_left);
+        let last = CHUNK_LENGTH - 1;
+        let length = CHUNK_LENGTH as i32;
+        let world_pos = |chunk: &Chunk, x: usize, y: usize, z: usize| IVec3::new(chunk.chunk_x * length + x as i32, y as i32, chunk.chunk_z * length + z as i32);
+        // one voxel lights the other if it is at least 2 brighter
+        let lights = |from: Light, to: Light| from.sky() > to.sky() + 1 || from.block() > to.block() + 1;
+
+        let mut seeds = vec![];
+        for (side, neighbor) in [Face::Right, Face::Left, Face::Front, Face::Back].into_iter().zip(self.neighbors(chunk_x, chunk_z)) {
+            let Some(neighbor) = neighbor else {
+                continue;
+            };
+
+            for y in 0..CHUNK_HEIGHT {
+                for i in 0..CHUNK_LENGTH {
+                    let ((x, z), (neighbor_x, neighbor_z)) = match side {
+                        Face::Right => ((last, i), (0, i)),
+                        Face::Left => ((0, i), (last, i)),
+                        Face::Front => ((i, last), (i, 0)),
+                        _ => ((i, 0), (i, last)),
+                    };
+
+                    let light = chunk.light.get(x, y, z);
+                    let neighbor_light = neighbor.light.get(neighbor_x, y, neighbor_z);
+                    if lights(light, neighbor_light) && neighbor.blocks.get(neighbor_x, y, neighbor_z).is_transparent() {
+                        seeds.push(world_pos(chunk, x, y, z));
+                    }
+                    if lights(neighbor_light, light) && chunk.blocks.get(x, y, z).is_transparent() {
+                        seeds.push(world_pos(neighbor, neighbor_x, y, neighbor_z));
                     }
                 }
             }
+        }
+        light::spread(self, seeds);
+    }
 
-            // Bottom Right Corner
-            {
-                let bot_right = objects[offset_bot_right];
-
-                if bot_right.block_type() != BlockType::Air {
-                    let block_behind = back.all_blocks[offset_top_right].block_type().bit_mask();
-                    let block_right = right.all_blocks[offset_bot_left].block_type().bit_mask();
-
-                    let block_front = objects[offset_bot_right + CHUNK_LENGTH].block_type().bit_mask();
-                    let block_left = objects[offset_bot_right - 1].block_type().bit_mask();
+    /// Removes the light an unloaded chunk spread over the borders of its loaded neighbors.
+    fn unlight_borders(&mut self, chunk_x: i32, chunk_z: i32) {
+        let last = CHUNK_LENGTH - 1;
+        let length = CHUNK_LENGTH as i32;
+
+        let mut changed = vec![];
+        for (side, neighbor) in [Face::Right, Face::Left, Face::Front, Face::Back].into_iter().zip(self.neighbors(chunk_x, chunk_z)) {
+            let Some(neighbor) = neighbor else {
+                continue;
+            };
+
+            for y in 0..CHUNK_HEIGHT {
+                for i in 0..CHUNK_LENGTH {
+                    // the layer of the neighbor that touched the unloaded chunk
+                    let (x, z) = match side {
+                        Face::Right => (0, i),
+                        Face::Left => (last, i),
+                        Face::Front => (i, 0),
+                        _ => (i, last),
+                    };
+                    if neighbor.light.is_lit_from_outside(&neighbor.blocks, x, y, z) {
+                        changed.push(IVec3::new(neighbor.chunk_x * length + x as i32, y as i32, neighbor.chunk_z * length + z as i32));
+                    }
+                }
+            }
+        }
+        light::relight_all(self, changed);
+    }
 
-                    let block_up = Self::get_block_up_bitmask(objects, offset_bot_right, y);
+    /// Queues the fluids at and next to a voxel that changed.
+    fn schedule_fluids(&mut self, pos: IVec3) {
+        let registry = BlockRegistry::global();
+        let offsets = [(0, 0, 0), (1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)];
 
-                    let is_air = (block_behind | block_right | block_front | block_left | block_up) & 1;
+        for (x, y, z) in offsets {
+            let neighbor = pos + IVec3::new(x, y, z);
+            if let Some(fluid) = self.get_block(neighbor).and_then(|block| registry.fluid_info(block)) {
+                self.fluid_ticks.schedule(neighbor, fluid.tick_rate);
+            }
+        }
+    }
 
-                    if is_air == BlockType::Air.bit_mask() {
-                        culled_objects.push(bot_right);
+    /// Advances the world by one fixed tick, see `TICKS_PER_SECOND`.
+    /// Updates the fluid cells that are due, their chunks are marked dirty like any other edit.
+    /// Returns how many blocks changed.
+    pub fn tick(&mut self) -> usize {
+        let mut changed = 0;
+        // relit once at the end of the tick, a spreading lava front changes many voxels at once
+        let mut relight = vec![];
+
+        for pos in self.fluid_ticks.advance() {
+            for (pos, block) in fluid::update(pos, |pos| self.get_block(pos)) {
+                if self.get_block(pos).is_none_or(|old| old == block) {
+                    continue;
+                }
+                if let Some(lit) = self.change_block(pos, block) {
+                    changed += 1;
+                    if lit {
+                        relight.push(pos);
                     }
                 }
             }
+        }
+        light::relight_all(self, relight);
+        changed
+    }
 
-            // Top Left Corner
-            {
-                let bot_left = objects[offset_top_left];
+    /// Fluid cells that are waiting for a tick, 0 once every fluid has settled.
+    pub fn active_fluids(&self) -> usize {
+        self.fluid_ticks.active()
+    }
 
-                if bot_left.block_type() != BlockType::Air {
-                    let block_front = front.all_blocks[offset_bot_left].block_type().bit_mask();
-                    let block_left = left.all_blocks[offset_top_right].block_type().bit_mask();
+    /// Applies every edit, then relights the changed voxels and remeshes the touched chunks once.
+    /// Returns how many edits were applied.
+    pub fn set_blocks(&mut self, edits: impl IntoIterator<Item = (IVec3, BlockType)>) -> usize {
+        let mut applied = 0;
+        let mut relight = vec![];
+
+        for (pos, block) in edits {
+            if let Some(lit) = self.change_block(pos, block) {
+                applied += 1;
+                if lit {
+                    relight.push(pos);
+                }
+            }
+        }
+        light::relight_all(self, relight);
+        self.remesh_dirty();
+        applied
+    }
 
-                    let block_behind = objects[offset_top_left - CHUNK_LENGTH].block_type().bit_mask();
-                    let block_right = objects[offset_top_left + 1].block_type().bit_mask();
+    pub fn is_dirty(&self, chunk_x: i32, chunk_z: i32) -> bool {
+        self.dirty.contains(&(chunk_x, chunk_z))
+    }
 
-                    let block_up = Self::get_block_up_bitmask(objects, offset_top_left, y);
+    /// Culls and meshes the dirty chunks again, returns how many were remeshed.
+    pub fn remesh_dirty(&mut self) -> usize {
+        let mut remeshed = 0;
 
-                    let is_air = (block_behind | block_right | block_front | block_left | block_up) & 1;
+        for (chunk_x, chunk_z) in std::mem::take(&mut self.dirty) {
+            let Some((faces, borders)) = self.cull_chunk(chunk_x, chunk_z) else {
+                continue;
+            };
 
-                    if is_air == BlockType::Air.bit_mask() {
-                        culled_objects.push(bot_left);
-                    }
-                }
+            // a mesh that is still running would overwrite this one with an older state
+            self.jobs.cancel_mesh(chunk_x, chunk_z);
+
+            if let Some(chunk) = self.root.chunk_mut(chunk_x, chunk_z) {
+                chunk.remesh(&faces, borders.each_ref().map(Option::as_ref));
+                remeshed += 1;
             }
+        }
+        remeshed
+    }
 
-            // Top Right Corner
-            {
-                let bot_left = objects[offset_top_right];
+    /// Visible faces of a loaded chunk using the loaded neighbors, and the borders of the neighbors for meshing it.
+    fn cull_chunk(&self, chunk_x: i32, chunk_z: i32) -> Option<(FaceMasks, [Option<Border>; 4])> {
+        let chunk = self.root.chunk(chunk_x, chunk_z)?;
+        let neighbors = self.neighbors(chunk_x, chunk_z);
+        let [right, left, front, back] = neighbors.map(|neighbor| neighbor.map(|c| &c.binary_grid));
 
-                if bot_left.block_type() != BlockType::Air {
-                    let block_front = front.all_blocks[offset_bot_right].block_type().bit_mask();
-                    let block_right = right.all_blocks[offset_top_left].block_type().bit_mask();
+        let mut faces = chunk.binary_grid.cull_faces(right, left, front, back);
+        let borders = Self::borders(neighbors);
+        faces.cull_translucent(&chunk.blocks, borders.each_ref().map(Option::as_ref));
+        Some((faces, borders))
+    }
 
-                    let block_behind = objects[offset_top_right - CHUNK_LENGTH].block_type().bit_mask();
-                    let block_left = objects[offset_top_right - 1].block_type().bit_mask();
+    /// Loaded chunks at +x, -x, +z and -z.
+    fn neighbors(&self, chunk_x: i32, chunk_z: i32) -> [Option<&Chunk>; 4] {
+        [(chunk_x + 1, chunk_z), (chunk_x - 1, chunk_z), (chunk_x, chunk_z + 1), (chunk_x, chunk_z - 1)].map(|(x, z)| self.root.chunk(x, z))
+    }
 
-                    let block_up = Self::get_block_up_bitmask(objects, offset_top_right, y);
+    fn borders(neighbors: [Option<&Chunk>; 4]) -> [Option<Border>; 4] {
+        let sides = [Face::Right, Face::Left, Face::Front, Face::Back];
+        std::array::from_fn(|i| neighbors[i].map(|chunk| Border::new(&chunk.blocks, &chunk.light, sides[i])))
+    }
 
-                    let is_air = (block_behind | block_right | block_front | block_left | block_up) & BlockType::Air.bit_mask();
+    /// Translucent quads of every loaded chunk, sorted back to front for `eye`.
+    pub fn translucent_mesh(&self, eye: Vec3) -> Vec<VertexBlock> {
+        let mut vertices: Vec<VertexBlock> = self.root.chunks().iter().flat_map(|chunk| chunk.translucent_quads.iter().copied()).collect();
+        GreedyMesh::sort_back_to_front(&mut vertices, eye);
+        vertices
+    }
 
-                    if is_air == BlockType::Air.bit_mask() {
-                        culled_objects.push(bot_left);
-                    }
-                }
+    /// Blocks with at least one visible face, of the loaded chunks within the player distance of `player_pos`.
+    pub fn get_culled(&self, player_pos: Vec3) -> Vec<GPUBlock> {
+        let target = glm::Vec2::new(player_pos.x, player_pos.z);
+
+        let mut objects = vec![];
+        for chunk in self.root.chunks() {
+            if Octree::chunk_distance(target, chunk.chunk_x, chunk.chunk_z) > self.player_distance as f32 {
+                continue;
+            }
+            if let Some((faces, _)) = self.cull_chunk(chunk.chunk_x, chunk.chunk_z) {
+                objects.extend(chunk.visible_blocks(&faces));
             }
         }
+        objects
     }
+}
 
-    fn edges_cases(culled_objects: &mut Vec<GPUBlock>, objects: &Vec<GPUBlock>, right: &Chunk, left: &Chunk, front: &Chunk, back: &Chunk) {
-        let x_offset = 0;
-        let z_offset = CHUNK_LENGTH * 1;
-        let mut chunk_area = CHUNK_LENGTH * CHUNK_LENGTH;
-        // do the x edge with chunk (Back and front)
+impl Drop for World {
+    fn drop(&mut self) {
+        self.save_all();
+    }
+}
 
-        // TODO, check edges
+impl LightVolume for World {
+    fn block(&self, pos: IVec3) -> Option<BlockType> {
+        self.get_block(pos)
+    }
 
-        // CHECK FRONT AND BACK EDGES
-        for y in 0..CHUNK_HEIGHT {
-            let y_offset = y * chunk_area;
-            for x in 1..CHUNK_LENGTH - 1 {
-                // Bottom Edge
+    fn light(&self, pos: IVec3) -> Light {
+        self.get_light(pos).unwrap_or_default()
+    }
 
-                let lower_object = objects[y_offset + x];
+    /// Marks the chunks whose faces are in the changed light.
+    fn set_light(&mut self, pos: IVec3, light: Light) {
+        let Some(((chunk_x, chunk_z), (x, y, z))) = Self::locate(pos) else {
+            return;
+        };
+        if let Some(chunk) = self.root.chunk_mut(chunk_x, chunk_z) {
+            chunk.light.set(x, y, z, light);
+            self.mark_dirty((chunk_x, chunk_z), (x, z));
+        }
+    }
+}
 
-                if lower_object.block_type() != BlockType::Air {
-                    let back = back.all_blocks[y_offset + x].block_type().bit_mask();
-                    let front = objects[y_offset + CHUNK_LENGTH].block_type().bit_mask();
-                    let right = objects[y_offset + x + 1].block_type().bit_mask();
-                    let left = objects[y_offset + x - 1].block_type().bit_mask();
+pub const CHUNK_AREA_LENGTH: usize = 16;
 
-                    let block_up = Self::get_block_up_bitmask(objects, y_offset + x, y);
+/// Square of `CHUNK_AREA_LENGTH` chunks that are generated and culled together on the thread pool.
+pub struct ChunkArea {
+    chunks: Vec<Chunk>,
+    // Area offset
+    pub offset: (i32, i32),
+}
 
-                    let is_air = (back | front | right | left | block_up) & BlockType::Air.bit_mask();
+impl ChunkArea {
+    pub fn new(offset: (i32, i32), generator: &dyn TerrainGenerator) -> ChunkArea {
+        let chunk_start_x = offset.0 * CHUNK_AREA_LENGTH as i32 - 1;
+        let chunk_start_z = offset.1 * CHUNK_AREA_LENGTH as i32 - 1;
 
-                    if is_air == BlockType::Air.bit_mask() {
-                        culled_objects.push(lower_object);
-                    }
-                }
-                let top_offset = y_offset + x + CHUNK_LENGTH * (CHUNK_LENGTH - 1);
+        // the area with a border of one chunk, the border is only there for the culling
+        let area_length = CHUNK_AREA_LENGTH + 2;
+
+        let mut chunks: Vec<Option<Chunk>> = (0..area_length * area_length).map(|_| None).collect();
+        ThreadPool::parallel_for_mut(&mut chunks, |i, chunk| {
+            let (x, z) = ((i % area_length) as i32, (i / area_length) as i32);
+            *chunk = Some(Chunk::new(chunk_start_x + x, chunk_start_z + z, generator));
+        });
+        let mut chunks: Vec<Chunk> = chunks.into_iter().map(Option::unwrap).collect();
+
+        let mut culled = vec![vec![]; area_length * area_length];
+        ThreadPool::parallel_for_mut(&mut culled, |block_offset, culled| {
+            let (x, z) = (block_offset % area_length, block_offset / area_length);
+            if x == 0 || z == 0 || x == area_length - 1 || z == area_length - 1 {
+                return;
+            }
 
-                let front_object = objects[top_offset];
+            *culled = Chunk::occlusion_cull(
+                &chunks[block_offset],
+                &chunks[block_offset + 1],
+                &chunks[block_offset - 1],
+                &chunks[block_offset + area_length],
+                &chunks[block_offset - area_length],
+            );
+        });
+
+        for (chunk, culled) in chunks.iter_mut().zip(culled) {
+            chunk.culled_blocks = culled;
+        }
 
-                if front_object.block_type() != BlockType::Air {
-                    let front = front.all_blocks[y_offset + x].block_type().bit_mask();
-                    let right = objects[top_offset + 1].block_type().bit_mask();
-                    let left = objects[top_offset - 1].block_type().bit_mask();
-                    let back = objects[top_offset - 1 - CHUNK_LENGTH].block_type().bit_mask();
+        Self { chunks, offset }
+    }
 
-                    let block_up = Self::get_block_up_bitmask(objects, top_offset, y);
+    /// Quads of the chunks inside the area, the border chunks are left out.
+    pub fn get_culled_objects(&self) -> Vec<VertexBlock> {
+        let mut culled = vec![];
+        for z in 1..CHUNK_AREA_LENGTH + 1 {
+            let z_offset = z * (CHUNK_AREA_LENGTH + 2);
+            for x in 1..CHUNK_AREA_LENGTH + 1 {
+                culled.extend(self.chunks[z_offset + x].quads.iter().copied());
+            }
+        }
 
-                    let is_air = (front | right | left | back | block_up) & BlockType::Air.bit_mask();
+        culled
+    }
+}
 
-                    if is_air == BlockType::Air.bit_mask() {
-                        culled_objects.push(front_object);
-                    }
-                }
+/// Where chunks come from.
+/// Chunks that were saved to a region file are read from disk, the rest is generated.
+pub struct ChunkLoader {
+    generator: Arc<dyn TerrainGenerator>,
+    saves: Option<Arc<SaveQueue>>,
+}
 
-                let left_offset = y_offset + (x * CHUNK_LENGTH);
-                let left_object = objects[left_offset];
-                let right_offset = y_offset + ((CHUNK_LENGTH) * (x + 1)) - 1;
+impl ChunkLoader {
+    /// * `store` - region files of the world, None never saves anything.
+    pub fn new(generator: Arc<dyn TerrainGenerator>, store: Option<RegionStore>) -> Self {
+        Self { generator, saves: store.map(|store| Arc::new(SaveQueue::new(store))) }
+    }
 
-                if left_object.block_type() != BlockType::Air {
-                    let front = objects[left_offset + CHUNK_LENGTH].block_type().bit_mask();
-                    let back = objects[left_offset - CHUNK_LENGTH].block_type().bit_mask();
-                    let right = objects[left_offset + 1].block_type().bit_mask();
-                    let left = left.all_blocks[right_offset].block_type().bit_mask();
+    /// The chunk is lit but not meshed, `World` meshes it together with its neighbors.
+    pub fn load(&self, x: i32, z: i32) -> Chunk {
+        if let Some(saves) = &self.saves {
+            // unloaded, but not written yet
+            if let Some(blocks) = saves.get(x, z) {
+                return Chunk::unmeshed(x, z, blocks);
+            }
+            match saves.store().load_chunk(x, z) {
+                Ok(Some(blocks)) => return Chunk::unmeshed(x, z, blocks),
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to load chunk ({}, {}), generating it instead: {}", x, z, e),
+            }
+        }
+        Chunk::unmeshed(x, z, self.generator.generate(x, z))
+    }
 
-                    let block_up = Self::get_block_up_bitmask(objects, left_offset, y);
+    /// Saves the chunk on the thread pool if it was edited since it was loaded.
+    pub fn unload(&self, chunk: Chunk) {
+        if let Some(saves) = &self.saves {
+            if chunk.edited {
+                saves.push(chunk.chunk_x, chunk.chunk_z, chunk.blocks);
+            }
+        }
+    }
 
-                    let air = (front | back | right | left | block_up) & BlockType::Air.bit_mask();
+    /// Saves the chunk on the calling thread if it was edited since it was loaded.
+    pub fn save(&self, chunk: &Chunk) -> std::io::Result<()> {
+        match &self.saves {
+            Some(saves) if chunk.edited => saves.store().save_chunk(chunk.chunk_x, chunk.chunk_z, &chunk.blocks),
+            _ => Ok(()),
+        }
+    }
 
-                    if air == BlockType::Air.bit_mask() {
-                        culled_objects.push(left_object);
-                    }
-                }
+    /// Waits until every unloaded chunk is written.
+    pub fn flush(&self) {
+        if let Some(saves) = &self.saves {
+            saves.flush();
+        }
+    }
+}
 
-                let right_object = objects[right_offset];
+/// Save the chunks into a uniform buffer with their model. Then the object will
+pub const CHUNK_LENGTH: usize = 64;
+pub const VOXEL_SCALE: f32 = 1.0;
+const CHUNK_HEIGHT: usize = 90;
 
-                if right_object.block_type() != BlockType::Air {
-                    let back = objects[y_offset].block_type().bit_mask();
-                    let front = objects[right_offset + CHUNK_LENGTH].block_type().bit_mask();
-                    let left = objects[right_offset + 1].block_type().bit_mask();
-                    let right = right.all_blocks[y_offset + CHUNK_LENGTH * x].block_type().bit_mask();
+/// Chunk coordinate and the local position of a voxel in it, see `World::locate`.
+pub type VoxelLocation = ((i32, i32), (usize, usize, usize));
 
-                    let block_up = Self::get_block_up_bitmask(objects, right_offset, y);
+pub struct Chunk {
+    /// Chunk coordinate, world position of a voxel is derived from it.
+    pub chunk_x: i32,
+    pub chunk_z: i32,
 
-                    let air = (front | back | right | left | block_up) & BlockType::Air.bit_mask();
+    pub blocks: BlockStorage,
+    /// Opaque and cutout blocks.
+    pub quads: Vec<VertexBlock>,
+    /// Unsorted, see `World::translucent_mesh`.
+    pub translucent_quads: Vec<VertexBlock>,
+    pub culled_blocks: Vec<GPUBlock>,
+    pub binary_grid: BinaryGrid,
+    /// Kept up to date by `World::set_block`, `Chunk::set_block` does not relight.
+    pub light: LightMap,
+    /// Changed since it was loaded, only edited chunks are saved.
+    pub edited: bool,
+}
 
-                    if air == BlockType::Air.bit_mask() {
-                        culled_objects.push(right_object);
-                    }
-                }
-            }
-        }
+impl Chunk {
+    pub fn new(x: i32, z: i32, generator: &dyn TerrainGenerator) -> Self {
+        Self::from_blocks(x, z, generator.generate(x, z))
     }
 
-    fn middle_cases(culled_objects: &mut Vec<GPUBlock>, objects: &Vec<GPUBlock>) {
-        for y in 0..CHUNK_HEIGHT {
-            let y_offset = y * CHUNK_LENGTH * CHUNK_LENGTH;
-            for z in 1..CHUNK_LENGTH - 1 {
-                let z_offset = CHUNK_LENGTH * z;
-                for x in 1..CHUNK_LENGTH - 1 {
-                    let x_offset = x;
+    /// Lit and meshed without its neighbors.
+    pub fn from_blocks(x: i32, z: i32, blocks: BlockStorage) -> Self {
+        let mut chunk = Self::unmeshed(x, z, blocks);
 
-                    let block = objects[y_offset + x_offset + z_offset];
-                    if block.block_type() != BlockType::Air {
-                        if y == CHUNK_HEIGHT {
-                            culled_objects.push(block);
-                            continue;
-                        }
+        let mut faces = chunk.binary_grid.cull_faces(None, None, None, None);
+        faces.cull_translucent(&chunk.blocks, [None; 4]);
+        chunk.remesh(&faces, [None; 4]);
+        chunk
+    }
+
+    /// Lit, but without any quads until `remesh`.
+    pub fn unmeshed(x: i32, z: i32, blocks: BlockStorage) -> Self {
+        let binary_grid = BinaryGrid::from_blocks(&blocks);
+        let light = LightMap::from_blocks(&blocks);
 
-                        let front = objects[y_offset + x_offset + z_offset + CHUNK_LENGTH].block_type().bit_mask();
-                        let back = objects[y_offset + x_offset + z_offset - CHUNK_LENGTH].block_type().bit_mask();
-                        let right = objects[y_offset + x_offset + z_offset + 1].block_type().bit_mask();
-                        let left = objects[y_offset + x_offset + z_offset - 1].block_type().bit_mask();
+        Self { chunk_x: x, chunk_z: z, blocks, culled_blocks: vec![], quads: vec![], translucent_quads: vec![], binary_grid, light, edited: false }
+    }
 
-                        let up = Self::get_block_up_bitmask(objects, y_offset + x_offset + z_offset, y);
+    /// Meshes the chunk again, the mesh is not updated by `set_block`.
+    ///
+    /// * `borders` - right, left, front and back neighbor, see `GreedyMesh::create_greedy`.
+    pub fn remesh(&mut self, faces: &FaceMasks, borders: [Option<&Border>; 4]) {
+        let mesh = GreedyMesh::create_greedy(&self.blocks, &self.light, faces, borders, BlockStorage::world_position(self.chunk_x, self.chunk_z, 0, 0, 0));
+        self.quads = mesh.opaque;
+        self.translucent_quads = mesh.translucent;
+    }
 
-                        let is_air = (front | back | right | left | up) & 1;
-                        if is_air == BlockType::Air.bit_mask() {
-                            culled_objects.push(block);
-                        }
-                    }
+    /// Changes a block and keeps the binary grid in sync.
+    pub fn set_block(&mut self, x: usize, y: usize, z: usize, block: BlockType) {
+        self.blocks.set(x, y, z, block);
+        self.binary_grid.set(x, y, z, block);
+        self.edited = true;
+    }
+
+    pub fn block(&self, index: usize) -> BlockType {
+        self.blocks.get_index(index)
+    }
+
+    /// Builds the GPU representation of a voxel, the position comes from the chunk coordinate.
+    pub fn gpu_block(&self, index: usize) -> GPUBlock {
+        let (x, y, z) = BlockStorage::local_position(index);
+        GPUBlock::new(BlockStorage::world_position(self.chunk_x, self.chunk_z, x, y, z), self.block(index))
+    }
+
+    /// Rebuilds the binary grid from the blocks.
+    pub fn update_binary_mask(&mut self) {
+        self.binary_grid = BinaryGrid::from_blocks(&self.blocks);
+    }
+
+    /// Returns every block that has at least one face next to air.
+    pub fn occlusion_cull(objects: &Chunk, right: &Chunk, left: &Chunk, front: &Chunk, back: &Chunk) -> Vec<GPUBlock> {
+        let faces = objects.binary_grid.cull_faces(Some(&right.binary_grid), Some(&left.binary_grid), Some(&front.binary_grid), Some(&back.binary_grid));
+        objects.visible_blocks(&faces)
+    }
+
+    /// Blocks with at least one face in `faces`.
+    pub fn visible_blocks(&self, faces: &FaceMasks) -> Vec<GPUBlock> {
+        let mut culled_objects = vec![];
+
+        for z in 0..CHUNK_LENGTH {
+            for x in 0..CHUNK_LENGTH {
+                let mut column = faces.visible_column(x, z);
+                while column != 0 {
+                    let y = column.trailing_zeros() as usize;
+                    column &= column - 1;
+
+                    culled_objects.push(self.gpu_block(BlockStorage::index(x, y, z)));
                 }
             }
         }
-    }
 
-    pub fn generate_face(&self, x: usize, y: usize, z: usize, face: usize) {
-        // let mut face_vertices = Vec::new();
-        // let mut face_indices = Vec::new();
+        culled_objects
+    }
 
-        // VertexBlock::get_face(face as u32);
+    /// Every block that is not air.
+    pub fn get_objects(&self) -> Vec<GPUBlock> {
+        (0..CHUNK_VOLUME).filter(|&i| self.block(i) != BlockType::AIR).map(|i| self.gpu_block(i)).collect()
     }
 
     pub fn box_blur(mut grid: [[u32; 16]; 16]) -> [[u32; 16]; 16] {
@@ -649,195 +1118,132 @@ impl Chunk {
     }
 }
 
-pub struct SimplexNoise {}
-impl SimplexNoise {
-    const PERM: [u8; 256] = [
-        151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69, 142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148,
-        247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219, 203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68,
-        175, 74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230, 220, 105, 92, 41, 55, 46, 245, 40, 244,
-        102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76, 132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109,
-        198, 173, 186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206, 59, 227, 47, 16, 58, 17, 182, 189,
-        28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163, 70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79,
-        113, 224, 232, 178, 185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162, 241, 81, 51, 145, 235, 249, 14,
-        239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204, 176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29,
-        24, 72, 243, 141, 128, 195, 78, 66, 215, 61, 156, 180,
-    ];
-
-    fn hash(i: u32) -> u8 {
-        Self::PERM[i as u8 as usize]
-    }
-    fn grad(hash: u32, x: f32) -> f32 {
-        let h = hash & 0x0F;
-        let mut grad = 1.0 + (h & 7) as f32;
-
-        if (h & 8) != 0 {
-            grad *= -1.0;
-        }
-
-        grad * x
-    }
-
-    fn grad_2d(hash: u32, x: f32, y: f32) -> f32 {
-        let h = hash & 0x3F;
-        let (mut u, v) = {
-            if h < 4 {
-                (y, x)
-            } else {
-                (x, y)
-            }
-        };
-
-        if h & 1 == 1 {
-            u *= -1.0;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::TerrainBlocks;
 
-        let mut v_multi = 2.0;
-
-        if h & 2 == 2 {
-            v_multi = -2.0;
-        }
-
-        u + v * v_multi
+    fn quad_count(vertices: &[VertexBlock]) -> usize {
+        vertices.len() / 6
     }
 
-    pub fn noise_2d(x: usize, y: usize, frequency: f32, seed: u32, amplitude: f32, persistence: f32, octaves_count: u32) -> f32 {
-        let mut noise_value = 0.0;
-
-        let mut inner_amplitude = 1.0;
-        let mut total_amplitude = 0.0;
-
-        let mut inner_freq = 1.0;
-        let octal_offset_x = 5.4;
-        let octal_offset_y = 2.4;
-
-        let hill_steps = 5.0;
-
-        for i in 0..octaves_count {
-            noise_value += Self::two_d(
-                x as f32 * frequency * inner_freq * (octal_offset_x * i as f32) + seed as f32,
-                y as f32 * frequency * inner_freq * (octal_offset_y * i as f32) + seed as f32,
-            ) * inner_amplitude;
-
-            total_amplitude += inner_amplitude;
-
-            inner_amplitude *= persistence;
-            inner_freq *= 2.0;
+    #[test]
+    fn greedy_merges_a_uniform_slab() {
+        let mut blocks = BlockStorage::new();
+        for y in 0..4 {
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    blocks.set(x, y, z, TerrainBlocks::global().stone);
+                }
+            }
         }
-        noise_value /= total_amplitude;
-        //  noise_value = noise_value.powf(0.31);
-        noise_value = (noise_value + 1.0) / 2.0;
-        noise_value = (noise_value * hill_steps).round() / hill_steps;
-        noise_value *= amplitude;
-        noise_value
-    }
+        let chunk = Chunk::from_blocks(0, 0, blocks);
 
-    pub fn generate_noise(x: f32, y: f32, octaves: u32, persistence: f32) -> f32 {
-        let mut total = 0.0;
-        let mut frequency = 1.0;
-        let mut amplitude = 1.0;
-        let mut max_value = 0.0;
+        // one quad per side of the slab
+        assert_eq!(quad_count(&chunk.quads), 6);
+        assert!(chunk.translucent_quads.is_empty());
+    }
 
-        for _ in 0..octaves {
-            total += Self::two_d(x * frequency, y * frequency) * amplitude;
-            max_value += amplitude;
-            amplitude *= persistence;
-            frequency *= 2.0;
+    #[test]
+    fn hidden_blocks_are_culled() {
+        let mut blocks = BlockStorage::new();
+        for y in 10..13 {
+            for z in 10..13 {
+                for x in 10..13 {
+                    blocks.set(x, y, z, TerrainBlocks::global().stone);
+                }
+            }
         }
-
-        (total / max_value + 1.0) * 0.5
+        let chunk = Chunk::from_blocks(2, -1, blocks);
+        let faces = chunk.binary_grid.cull_faces(None, None, None, None);
+
+        let visible = chunk.visible_blocks(&faces);
+        assert_eq!(visible.len(), 26);
+        assert_eq!(chunk.get_objects().len(), 27);
+        let center = BlockStorage::world_position(2, -1, 11, 11, 11);
+        assert!(visible.iter().all(|block| block.position != center));
     }
 
-    pub fn one_d(x: f32) -> f32 {
-        let (n0, n1);
-
-        // Corners coordinates (nearest integer values):
-        let i0 = x.floor();
-        let i1 = i0 + 1.0;
-        // Distances to corners (between 0 and 1):
-        let x0 = x - i0;
-        let x1 = x0 - 1.0;
-
-        let mut t0 = 1.0 - x0 * x0;
-        t0 *= t0;
-        n0 = t0 * t0 * Self::grad(Self::PERM[i0 as usize] as u32, x0);
-
-        let mut t1 = 1.0 - x1 * x1;
-        t1 *= t1;
-        n1 = t1 * t1 * Self::grad(Self::PERM[i1 as usize] as u32, x1);
-
-        0.395 * (n0 + n1)
+    #[test]
+    fn corner_occlusion() {
+        assert_eq!(GreedyMesh::vertex_ao(false, false, false), 3);
+        assert_eq!(GreedyMesh::vertex_ao(true, false, false), 2);
+        assert_eq!(GreedyMesh::vertex_ao(false, false, true), 2);
+        assert_eq!(GreedyMesh::vertex_ao(false, true, true), 1);
+        // the diagonal does not matter between two blocks
+        assert_eq!(GreedyMesh::vertex_ao(true, true, false), 0);
+        assert_eq!(GreedyMesh::vertex_ao(true, true, true), 0);
+
+        let stone = TerrainBlocks::global().stone;
+        let mut blocks = BlockStorage::new();
+        blocks.set(10, 10, 10, stone);
+        // above the top face, at +z, which is the u axis of the top face
+        blocks.set(10, 11, 11, stone);
+        assert_eq!(GreedyMesh::face_ao(&blocks, [None; 4], Face::Top, [10, 10, 10]), [3, 2, 3, 2]);
+        // and at -x -z, the corner 0
+        blocks.set(9, 11, 9, stone);
+        assert_eq!(GreedyMesh::face_ao(&blocks, [None; 4], Face::Top, [10, 10, 10]), [2, 2, 3, 2]);
+        assert_eq!(GreedyMesh::face_ao(&blocks, [None; 4], Face::Bottom, [10, 10, 10]), [3; 4]);
     }
 
-    pub fn two_d(x: f32, y: f32) -> f32 {
-        const F2: f32 = 0.366025403;
-        const G2: f32 = 0.211324865;
-
-        // Skew the input space to determine which simplex cell we're in
-        let s: f32 = (x + y) * F2;
-        let xs: f32 = x + s;
-        let ys: f32 = y + s;
-        let i = xs.floor();
-        let j = ys.floor();
-
-        // Unskew the cell origin back to (x,y) space
-        let t = (i + j) as f32 * G2;
-        let _x0 = i - t;
-        let _y0 = j - t;
-        let x0 = x - _x0;
-        let y0 = y - _y0;
-
-        // For the 2D case, the simplex shape is an equilateral triangle.
-        // Determine which simplex we are in.
-        let (mut i1, mut j1) = (0, -1);
+    #[test]
+    fn quads_are_split_along_the_darker_diagonal() {
+        let stone = TerrainBlocks::global().stone;
+        // ao of the two vertices both triangles share, and the winding of each triangle
+        let split = |face: Face, ao: [u8; 4]| {
+            let mut vertices = vec![];
+            GreedyMesh::push_quad(&mut vertices, face, (stone, Light::default(), ao), Vec3::zero(), [0, 1, 0], [1, 1, 1]);
+            let (first, second) = vertices.split_at(3);
+
+            let mut shared: Vec<u32> = first.iter().filter(|a| second.iter().any(|b| b.pos == a.pos)).map(VertexBlock::ao).collect();
+            shared.sort();
+            let winding = |t: &[VertexBlock]| (t[1].pos - t[0].pos).cross(t[2].pos - t[0].pos).dot(face.normal()).signum();
+            (shared, winding(first), winding(second))
+        };
 
-        if x0 > y0 {
-            i1 = 1;
-            j1 = 0;
+        for face in Face::ALL {
+            // the dark corner 0 is on the diagonal
+            let (shared, first, second) = split(face, [0, 3, 3, 3]);
+            assert_eq!(shared, [0, 3], "{:?}", face);
+            // corner 1 is dark, the quad flips to the other diagonal and keeps its winding
+            let (flipped, flipped_first, flipped_second) = split(face, [3, 0, 3, 3]);
+            assert_eq!(flipped, [0, 3], "{:?}", face);
+            assert!(first == second && flipped_first == first && flipped_second == first, "{:?}", face);
         }
+    }
 
-        let x1 = x0 - (i1 as f32) + G2;
-        let y1 = y0 - (j1 as f32) + G2;
-        let x2 = x0 - 1.0 + 2.0 * G2;
-        let y2 = y0 - 1.0 + 2.0 * G2;
-
-        let gi0_hash = i as u32 + Self::hash(j as u32) as u32;
-        let gi1_hash = (i + i1 as f32) as u32 + Self::hash((j + j1 as f32) as u32) as u32;
-        let gi2_hash = (i + 1.0) as u32 + Self::hash((j + 1.0) as u32) as u32;
-
-        let gi0 = Self::hash(gi0_hash as u32);
-        let gi1 = Self::hash(gi1_hash as u32);
-        let gi2 = Self::hash(gi2_hash as u32);
-
-        let (n0, n1, n2);
-
-        // Calculate the contribution
-        let mut t0 = 0.5 - x0 * x0 - y0 * y0;
-
-        if t0 < 0.0 {
-            n0 = 0.0;
-        } else {
-            t0 *= t0;
-            n0 = t0 * t0 * Self::grad_2d(gi0 as u32, x0, y0);
-        }
-        // Calculate the contribution
-        let mut t1 = 0.5 - x1 * x1 - y1 * y1;
+    /// Dark caves, chunk (-1, 0) has a torch at its border to chunk (0, 0).
+    struct Caves;
 
-        if t1 < 0.0 {
-            n1 = 0.0;
-        } else {
-            t1 *= t1;
-            n1 = t1 * t1 * Self::grad_2d(gi1 as u32, x1, y1);
+    impl TerrainGenerator for Caves {
+        fn generate(&self, chunk_x: i32, chunk_z: i32) -> BlockStorage {
+            let stone = TerrainBlocks::global().stone;
+            let mut blocks = BlockStorage::new();
+            for z in 0..CHUNK_LENGTH {
+                for x in 0..CHUNK_LENGTH {
+                    blocks.set(x, 0, z, stone);
+                    blocks.set(x, 20, z, stone);
+                }
+            }
+            if (chunk_x, chunk_z) == (-1, 0) {
+                blocks.set(CHUNK_LENGTH - 1, 1, 5, BlockType::by_name("torch").unwrap());
+            }
+            blocks
         }
-        // Calculate the contribution
-        let mut t2 = 0.5 - x2 * x2 - y2 * y2;
+    }
 
-        if t2 < 0.0 {
-            n2 = 0.0;
-        } else {
-            t2 *= t2;
-            n2 = t2 * t2 * Self::grad_2d(gi2 as u32, x2, y2);
+    #[test]
+    fn unloaded_chunks_take_their_light_with_them() {
+        let chunk_size = CHUNK_LENGTH as f32 * VOXEL_SCALE;
+        let mut world = World::with_loader(Vec3::new(chunk_size / 2.0, 10.0, chunk_size / 2.0), 1, ChunkLoader::new(Arc::new(Caves), None));
+        assert_eq!(world.get_light(IVec3::new(0, 1, 5)), Some(Light::new(0, 13)));
+        assert_eq!(world.get_light(IVec3::new(2, 1, 5)), Some(Light::new(0, 11)));
+
+        // (-1, 0) leaves the unload distance, (0, 0) stays
+        world.update(Vec3::new(chunk_size * 3.5, 10.0, chunk_size / 2.0));
+        assert!(world.get_block(IVec3::new(-1, 1, 5)).is_none());
+        for x in 0..14 {
+            assert_eq!(world.get_light(IVec3::new(x, 1, 5)), Some(Light::default()), "x = {}", x);
         }
-
-        45.23065 * (n0 + n1 + n2)
     }
 }
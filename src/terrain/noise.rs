@@ -0,0 +1,298 @@
+/// Seeded simplex noise, 2D and 3D.
+/// Sampled in world coordinates, so neighboring chunks line up without any seams.
+pub struct SimplexNoise {
+    /// Shuffled 0..256, repeated twice to avoid wrapping the index.
+    perm: [u8; 512],
+}
+
+/// Settings of the fractal combinators.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fractal {
+    pub octaves: u32,
+    pub frequency: f64,
+    /// Frequency multiplier per octave.
+    pub lacunarity: f64,
+    /// Amplitude multiplier per octave.
+    pub persistence: f64,
+}
+
+impl Fractal {
+    pub fn new(octaves: u32, frequency: f64, persistence: f64) -> Self {
+        Self { octaves, frequency, lacunarity: 2.0, persistence }
+    }
+}
+
+impl SimplexNoise {
+    const GRAD_3: [[f64; 3]; 12] = [
+        [1.0, 1.0, 0.0],
+        [-1.0, 1.0, 0.0],
+        [1.0, -1.0, 0.0],
+        [-1.0, -1.0, 0.0],
+        [1.0, 0.0, 1.0],
+        [-1.0, 0.0, 1.0],
+        [1.0, 0.0, -1.0],
+        [-1.0, 0.0, -1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, -1.0, 1.0],
+        [0.0, 1.0, -1.0],
+        [0.0, -1.0, -1.0],
+    ];
+
+    pub fn new(seed: u64) -> Self {
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+
+        // Fisher-Yates with splitmix64, the same seed always gives the same table
+        let mut state = seed;
+        for i in (1..table.len()).rev() {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^= z >> 31;
+
+            table.swap(i, (z % (i as u64 + 1)) as usize);
+        }
+
+        let mut perm = [0; 512];
+        for i in 0..perm.len() {
+            perm[i] = table[i & 255];
+        }
+        Self { perm }
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        self.perm[i] as usize
+    }
+
+    fn corner(t: f64, gradient: usize, point: [f64; 3]) -> f64 {
+        if t < 0.0 {
+            return 0.0;
+        }
+        let g = Self::GRAD_3[gradient];
+        let t = t * t;
+        t * t * (g[0] * point[0] + g[1] * point[1] + g[2] * point[2])
+    }
+
+    /// Returns a value in [-1, 1].
+    pub fn noise_2d(&self, x: f64, y: f64) -> f64 {
+        const F2: f64 = 0.366_025_403_784_438_6;
+        const G2: f64 = 0.211_324_865_405_187_1;
+
+        // Skew the input space to determine which simplex cell we're in
+        let s = (x + y) * F2;
+        let i = (x + s).floor();
+        let j = (y + s).floor();
+
+        // Unskew the cell origin back to (x,y) space
+        let t = (i + j) * G2;
+        let x0 = x - (i - t);
+        let y0 = y - (j - t);
+
+        // For the 2D case, the simplex shape is an equilateral triangle.
+        let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+
+        let x1 = x0 - i1 as f64 + G2;
+        let y1 = y0 - j1 as f64 + G2;
+        let x2 = x0 - 1.0 + 2.0 * G2;
+        let y2 = y0 - 1.0 + 2.0 * G2;
+
+        let ii = (i as i64 & 255) as usize;
+        let jj = (j as i64 & 255) as usize;
+
+        let gi0 = self.hash(ii + self.hash(jj)) % 12;
+        let gi1 = self.hash(ii + i1 + self.hash(jj + j1)) % 12;
+        let gi2 = self.hash(ii + 1 + self.hash(jj + 1)) % 12;
+
+        let n0 = Self::corner(0.5 - x0 * x0 - y0 * y0, gi0, [x0, y0, 0.0]);
+        let n1 = Self::corner(0.5 - x1 * x1 - y1 * y1, gi1, [x1, y1, 0.0]);
+        let n2 = Self::corner(0.5 - x2 * x2 - y2 * y2, gi2, [x2, y2, 0.0]);
+
+        // the largest sum, with the best gradient at every corner, is 1 / 70.148
+        70.0 * (n0 + n1 + n2)
+    }
+
+    /// Returns a value in [-1, 1].
+    pub fn noise_3d(&self, x: f64, y: f64, z: f64) -> f64 {
+        const F3: f64 = 1.0 / 3.0;
+        const G3: f64 = 1.0 / 6.0;
+
+        let s = (x + y + z) * F3;
+        let i = (x + s).floor();
+        let j = (y + s).floor();
+        let k = (z + s).floor();
+
+        let t = (i + j + k) * G3;
+        let x0 = x - (i - t);
+        let y0 = y - (j - t);
+        let z0 = z - (k - t);
+
+        // The simplex is a tetrahedron, find out which one of the six we are in.
+        let ((i1, j1, k1), (i2, j2, k2)) = if x0 >= y0 {
+            if y0 >= z0 {
+                ((1, 0, 0), (1, 1, 0))
+            } else if x0 >= z0 {
+                ((1, 0, 0), (1, 0, 1))
+            } else {
+                ((0, 0, 1), (1, 0, 1))
+            }
+        } else if y0 < z0 {
+            ((0, 0, 1), (0, 1, 1))
+        } else if x0 < z0 {
+            ((0, 1, 0), (0, 1, 1))
+        } else {
+            ((0, 1, 0), (1, 1, 0))
+        };
+
+        let x1 = x0 - i1 as f64 + G3;
+        let y1 = y0 - j1 as f64 + G3;
+        let z1 = z0 - k1 as f64 + G3;
+        let x2 = x0 - i2 as f64 + 2.0 * G3;
+        let y2 = y0 - j2 as f64 + 2.0 * G3;
+        let z2 = z0 - k2 as f64 + 2.0 * G3;
+        let x3 = x0 - 1.0 + 3.0 * G3;
+        let y3 = y0 - 1.0 + 3.0 * G3;
+        let z3 = z0 - 1.0 + 3.0 * G3;
+
+        let ii = (i as i64 & 255) as usize;
+        let jj = (j as i64 & 255) as usize;
+        let kk = (k as i64 & 255) as usize;
+
+        let gi0 = self.hash(ii + self.hash(jj + self.hash(kk))) % 12;
+        let gi1 = self.hash(ii + i1 + self.hash(jj + j1 + self.hash(kk + k1))) % 12;
+        let gi2 = self.hash(ii + i2 + self.hash(jj + j2 + self.hash(kk + k2))) % 12;
+        let gi3 = self.hash(ii + 1 + self.hash(jj + 1 + self.hash(kk + 1))) % 12;
+
+        // 0.5 instead of the 0.6 from the paper, the bigger radius leaks into the next simplex and leaves seams
+        let n0 = Self::corner(0.5 - x0 * x0 - y0 * y0 - z0 * z0, gi0, [x0, y0, z0]);
+        let n1 = Self::corner(0.5 - x1 * x1 - y1 * y1 - z1 * z1, gi1, [x1, y1, z1]);
+        let n2 = Self::corner(0.5 - x2 * x2 - y2 * y2 - z2 * z2, gi2, [x2, y2, z2]);
+        let n3 = Self::corner(0.5 - x3 * x3 - y3 * y3 - z3 * z3, gi3, [x3, y3, z3]);
+
+        // the largest sum, with the best gradient at every corner, is 1 / 76.8807
+        76.88 * (n0 + n1 + n2 + n3)
+    }
+
+    /// Fractal brownian motion, returns a value in [-1, 1].
+    pub fn fbm_2d(&self, x: f64, y: f64, fractal: &Fractal) -> f64 {
+        Self::octaves(fractal, |f| self.noise_2d(x * f, y * f), |n| n)
+    }
+
+    /// Fractal brownian motion, returns a value in [-1, 1].
+    pub fn fbm_3d(&self, x: f64, y: f64, z: f64, fractal: &Fractal) -> f64 {
+        Self::octaves(fractal, |f| self.noise_3d(x * f, y * f, z * f), |n| n)
+    }
+
+    /// Sharp ridges where the noise crosses zero, returns a value in [0, 1].
+    pub fn ridged_2d(&self, x: f64, y: f64, fractal: &Fractal) -> f64 {
+        Self::octaves(fractal, |f| self.noise_2d(x * f, y * f), Self::ridge)
+    }
+
+    /// Sharp ridges where the noise crosses zero, returns a value in [0, 1].
+    pub fn ridged_3d(&self, x: f64, y: f64, z: f64, fractal: &Fractal) -> f64 {
+        Self::octaves(fractal, |f| self.noise_3d(x * f, y * f, z * f), Self::ridge)
+    }
+
+    /// Rounded bumps, returns a value in [-1, 1].
+    pub fn billow_2d(&self, x: f64, y: f64, fractal: &Fractal) -> f64 {
+        Self::octaves(fractal, |f| self.noise_2d(x * f, y * f), Self::billow)
+    }
+
+    /// Rounded bumps, returns a value in [-1, 1].
+    pub fn billow_3d(&self, x: f64, y: f64, z: f64, fractal: &Fractal) -> f64 {
+        Self::octaves(fractal, |f| self.noise_3d(x * f, y * f, z * f), Self::billow)
+    }
+
+    fn ridge(noise: f64) -> f64 {
+        let ridge = 1.0 - noise.abs();
+        ridge * ridge
+    }
+
+    fn billow(noise: f64) -> f64 {
+        2.0 * noise.abs() - 1.0
+    }
+
+    /// Weighted average of every octave, so the output keeps the range of `shape`.
+    fn octaves(fractal: &Fractal, sample: impl Fn(f64) -> f64, shape: impl Fn(f64) -> f64) -> f64 {
+        let mut total = 0.0;
+        let mut frequency = fractal.frequency;
+        let mut amplitude = 1.0;
+        let mut max_value = 0.0;
+
+        for _ in 0..fractal.octaves.max(1) {
+            total += shape(sample(frequency)) * amplitude;
+            max_value += amplitude;
+            amplitude *= fractal.persistence;
+            frequency *= fractal.lacunarity;
+        }
+
+        total / max_value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points spread over many simplex cells, with fractions that do not repeat.
+    fn grid() -> impl Iterator<Item = (f64, f64, f64)> {
+        (0..60).flat_map(|x| (0..60).flat_map(move |y| (0..20).map(move |z| (x as f64 * 0.137 - 4.0, y as f64 * 0.291 - 8.0, z as f64 * 0.613))))
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_values() {
+        let (first, second) = (SimplexNoise::new(42), SimplexNoise::new(42));
+        for (x, y, z) in grid().step_by(97) {
+            assert_eq!(first.noise_2d(x, y), second.noise_2d(x, y));
+            assert_eq!(first.noise_3d(x, y, z), second.noise_3d(x, y, z));
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_fields() {
+        let (first, second) = (SimplexNoise::new(1), SimplexNoise::new(2));
+        let different = grid().step_by(97).filter(|&(x, y, z)| (first.noise_3d(x, y, z) - second.noise_3d(x, y, z)).abs() > 1e-3).count();
+        assert!(different > grid().step_by(97).count() / 2);
+        assert!(grid().step_by(97).any(|(x, y, _)| first.noise_2d(x, y) != second.noise_2d(x, y)));
+    }
+
+    #[test]
+    fn values_stay_in_range() {
+        for seed in 0..4 {
+            let noise = SimplexNoise::new(seed);
+            let (mut max_2d, mut max_3d) = (0.0f64, 0.0f64);
+            for (x, y, z) in grid() {
+                let (value_2d, value_3d) = (noise.noise_2d(x * 3.1, y + z), noise.noise_3d(x, y, z));
+                assert!((-1.0..=1.0).contains(&value_2d) && (-1.0..=1.0).contains(&value_3d), "{} {} at ({}, {}, {})", value_2d, value_3d, x, y, z);
+                max_2d = max_2d.max(value_2d.abs());
+                max_3d = max_3d.max(value_3d.abs());
+            }
+            // the scale is not too small either
+            assert!(max_2d > 0.7 && max_3d > 0.7, "{} {}", max_2d, max_3d);
+        }
+    }
+
+    #[test]
+    fn fractals_are_continuous() {
+        let noise = SimplexNoise::new(7);
+        let fractal = Fractal::new(4, 1.0 / 64.0, 0.5);
+        let epsilon = 1e-7;
+
+        // integer lattice of the first octave and chunk borders in world coordinates
+        for border in [-128.0, -64.0, 0.0, 1.0, 64.0, 96.0, 128.0] {
+            for other in [0.5, 13.25, 40.0] {
+                let samples: [&dyn Fn(f64) -> f64; 6] = [
+                    &|x| noise.fbm_2d(x, other, &fractal),
+                    &|x| noise.fbm_3d(other, x, other * 2.0, &fractal),
+                    &|x| noise.ridged_2d(other, x, &fractal),
+                    &|x| noise.ridged_3d(x, other, other, &fractal),
+                    &|x| noise.billow_2d(x, other, &fractal),
+                    &|x| noise.billow_3d(other, other, x, &fractal),
+                ];
+                for sample in samples {
+                    let jump = (sample(border - epsilon) - sample(border + epsilon)).abs();
+                    assert!(jump < 1e-4, "jump of {} at {}", jump, border);
+                }
+            }
+        }
+    }
+}
@@ -1,216 +1,186 @@
-use std::ops::Div;
-
 use crate::terrain::Chunk;
-use glm::{Vec2, Vec3};
+use glm::{IVec2, Vec2};
 
-use super::{block::GPUBlock, CHUNK_LENGTH, VOXEL_SCALE};
+use super::{block::GPUBlock, storage::CHUNK_VOLUME, ChunkLoader, CHUNK_LENGTH, VOXEL_SCALE};
 
-// lazily allocate them
-type Chunkindex = u32;
+pub struct Node {
+    /// Chunk coordinate of the bottom left corner
+    min: IVec2,
 
-struct Range {
-    pub start: usize,
-    pub end: usize,
-}
-impl Range {
-    pub fn new(start: usize, end: usize) -> Self {
-        Self { start, end }
-    }
-}
+    /// Size in chunks, always a power of two
+    size: i32,
 
-/// Own the data, it has.
-struct Freelist<T> {
-    list: Vec<T>,
-    free: Vec<Range>,
+    // Quadrant children, top left, top right, bot left, bot right
+    children: Option<Box<[Node; 4]>>,
+    /// Only leaves of a single chunk hold chunks, unless they are handed down by `split`.
+    chunks: Vec<Chunk>,
 }
 
-impl<T> Freelist<T> {
-    pub fn new() -> Freelist<T> {
-        Freelist { list: vec![], free: vec![] }
+impl Node {
+    pub fn new(min: IVec2, size: i32) -> Self {
+        Node { min, size, children: None, chunks: vec![] }
     }
 
-    pub fn add(&mut self, data: T) -> usize {
-        let data_index;
-        if self.free.len() == 0 {
-            self.list.push(data);
-            data_index = self.list.len() - 1;
-        } else {
-            let last_index = self.free.len() - 1;
-
-            data_index = self.free[last_index].end;
-            self.free[last_index].end -= 1;
+    pub fn get_all_nodes_debug_lines(&mut self) {}
 
-            if self.free[last_index].start == self.free[1].end {
-                self.free.remove(last_index);
-            }
+    /// Splits the nodes in view down to single chunks, merges the nodes past the unload distance.
+    /// Chunks in view that are not loaded yet are added to `missing`.
+    pub fn load_player_nodes(&mut self, player_pos: Vec2, player_distance: f32, unload_distance: f32, loader: &ChunkLoader, missing: &mut Vec<IVec2>) {
+        let distance = self.distance(player_pos);
+        if distance > unload_distance {
+            self.merge(loader);
+            return;
         }
-        data_index
-    }
-    // might not do a Range, if I dont have a lot of free spots, more memory intensive but O(1) remove.
-
-    pub fn remove(&mut self, index: usize) {
-        if self.free.len() == 0 {
-            self.free.push(Range::new(index - 1, index));
-        } else {
-            let mut added = false;
-            for e in &mut self.free {
-                if (e.end + 1) == index {
-                    e.end += 1;
-                    added = true;
-                    break;
-                } else if e.start == index {
-                    e.start -= 1;
-                    added = true;
-                    break;
+
+        if self.size > 1 {
+            if self.children.is_none() {
+                // between the two distances nothing changes
+                if distance > player_distance {
+                    return;
                 }
+                self.split();
             }
 
-            if added == false {
-                self.free.push(Range::new(index - 1, index));
+            for child in self.children.as_mut().unwrap().iter_mut() {
+                child.load_player_nodes(player_pos, player_distance, unload_distance, loader, missing);
             }
+        } else if self.chunks.is_empty() && distance <= player_distance {
+            missing.push(self.min);
         }
     }
-}
-
-struct Node {
-    /// Center position of the node
-    pos: glm::Vec2,
-
-    /// Size in voxels
-    size: glm::Vec2,
 
-    // Quadrant children
-    children: Option<Box<[Node; 4]>>,
-    /// indices to global chunk array freelist
-    chunks: Vec<Chunk>,
-
-    // does not own it, might only need &, will see
-    parent: *mut Node,
-}
+    /// Deepest node that contains the chunk coordinate.
+    pub fn find_node(&self, chunk: IVec2) -> Option<&Node> {
+        if !self.contains(chunk) {
+            return None;
+        }
 
-impl Node {
-    pub fn new(parent: *mut Node, position: glm::Vec2, size: glm::Vec2) -> Self {
-        Node { pos: position, size, children: None, chunks: vec![], parent }
+        match &self.children {
+            Some(children) => children.iter().find_map(|child| child.find_node(chunk)),
+            None => Some(self),
+        }
     }
 
-    pub fn get_all_nodes_debug_lines(&mut self) {}
-
-    pub fn load_player_nodes(&mut self, max_depth: u32, player_pos: Vec2, player_distance: usize) {
-        if max_depth > 0 {
-            self.split();
+    pub fn chunk(&self, chunk_x: i32, chunk_z: i32) -> Option<&Chunk> {
+        let node = self.find_node(IVec2::new(chunk_x, chunk_z))?;
+        node.chunks.iter().find(|c| c.chunk_x == chunk_x && c.chunk_z == chunk_z)
+    }
 
-            for child in self.children.as_mut().unwrap().iter_mut() {
-                let distance_to_child = child.distance(player_pos) - self.size.x;
-                if distance_to_child < player_distance as f32 {
-                    child.load_player_nodes(max_depth - 1, player_pos, player_distance);
-                }
-            }
-        } else {
-            self.load_chunks();
+    pub fn chunk_mut(&mut self, chunk_x: i32, chunk_z: i32) -> Option<&mut Chunk> {
+        let chunk = IVec2::new(chunk_x, chunk_z);
+        if !self.contains(chunk) {
+            return None;
         }
-    }
 
-    pub fn find_node(&self, max_depth: u32, pos: Vec2) -> &Node {
-        if max_depth > 0 {
-            if self.children.is_some() {
-                let children = unsafe { &*self.children.as_ref().unwrap_unchecked() };
-                for child in children.iter() {
-                    if child.is_inside(pos) {
-                        let node = child.find_node(max_depth - 1, pos);
-                        return node;
-                    }
-                }
-                panic!("")
-            } else {
-                return self;
-            }
-        } else {
-            return self;
+        if let Some(children) = &mut self.children {
+            return children.iter_mut().find_map(|child| child.chunk_mut(chunk_x, chunk_z));
         }
+        self.chunks.iter_mut().find(|c| c.chunk_x == chunk_x && c.chunk_z == chunk_z)
     }
 
     pub fn get_objects(&self) -> Vec<GPUBlock> {
         let mut objects = vec![];
         for chunk in &self.chunks {
-            objects.extend(chunk.all_blocks.clone());
+            objects.extend((0..CHUNK_VOLUME).map(|i| chunk.gpu_block(i)));
         }
         objects
     }
 
-    /// the chunks are loaded from bot left -> top right in lines.
-    pub fn load_chunks(&mut self) {
-        let pos = self.pos;
-
-        let chunks_fit = self.size.x / CHUNK_LENGTH as f32;
+    fn collect_chunks<'a>(&'a self, chunks: &mut Vec<&'a Chunk>) {
+        chunks.extend(self.chunks.iter());
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.collect_chunks(chunks);
+            }
+        }
+    }
 
-        assert!(
-            chunks_fit.fract() == 0.0,
-            "should not be a fractional chunk. only whole numbers, check Octree max size and depth"
-        );
+    /// Removes every chunk of the node and its children.
+    fn drain_chunks(&mut self, chunks: &mut Vec<Chunk>) {
+        chunks.append(&mut self.chunks);
+        if let Some(children) = &mut self.children {
+            for child in children.iter_mut() {
+                child.drain_chunks(chunks);
+            }
+        }
+    }
 
-        let pos_offset = (pos - self.size.div(2.0)).div(CHUNK_LENGTH as f32);
+    /// Edited chunks are saved by the loader.
+    pub fn unload_chunk(&mut self, loader: &ChunkLoader) {
+        for chunk in self.chunks.drain(..) {
+            loader.unload(chunk);
+        }
+    }
 
-        for z in 0..chunks_fit as usize {
-            let z_offset = z as f32 * chunks_fit + pos_offset.y;
-            for x in 0..chunks_fit as usize {
-                let x_offset = pos_offset.y + x as f32;
+    /// Adds a chunk to the leaf of its coordinate, splits on the way down.
+    /// Gives the chunk back if it is outside of the node.
+    pub fn insert(&mut self, chunk: Chunk) -> Result<(), Box<Chunk>> {
+        if !self.contains(IVec2::new(chunk.chunk_x, chunk.chunk_z)) {
+            return Err(Box::new(chunk));
+        }
 
-                self.chunks.push(Chunk::new(x_offset as i32, z_offset as i32));
+        if self.size > 1 {
+            if self.children.is_none() {
+                self.split();
             }
+            let children = self.children.as_mut().unwrap();
+            let index = children.iter().position(|child| child.contains(IVec2::new(chunk.chunk_x, chunk.chunk_z))).unwrap();
+            return children[index].insert(chunk);
         }
+
+        self.chunks.push(chunk);
+        Ok(())
     }
 
-    pub fn unload_chunk(&mut self) {
-        self.chunks.clear();
-        // TODO, saving system etc. we will see how we do it
+    /// Unloads the chunks of the children and removes them.
+    fn merge(&mut self, loader: &ChunkLoader) {
+        let mut chunks = vec![];
+        self.drain_chunks(&mut chunks);
+        for chunk in chunks {
+            loader.unload(chunk);
+        }
+        self.children = None;
     }
 
+    /// Distance in chunks from the point to the closest point of the node, 0 if it is inside.
     fn distance(&self, point: Vec2) -> f32 {
-        ((self.pos.x - point.x).powi(2) + (self.pos.y - point.y).powi(2)).powf(0.5)
-    }
+        let max = self.min + IVec2::new(self.size, self.size);
 
-    fn is_inside(&self, pos: Vec2) -> bool {
-        let half_size_x = self.size.x / 2.0;
-        let half_size_z = self.size.y / 2.0;
+        let dx = (self.min.x as f32 - point.x).max(point.x - max.x as f32).max(0.0);
+        let dz = (self.min.y as f32 - point.y).max(point.y - max.y as f32).max(0.0);
 
-        if pos.x > (self.pos.x + half_size_x)
-            || pos.x < (self.pos.x - half_size_x)
-            || pos.y > (self.pos.y + half_size_z)
-            || pos.y < (self.pos.y - half_size_z)
-        {
-            return false;
-        }
-        true
+        (dx * dx + dz * dz).sqrt()
     }
 
-    pub fn split(&mut self) {
-        let s_child = self.size.div(2.0);
-        let s_half_child = self.size.div(4.0);
-
-        let p_t_l = Vec2::new(self.pos.x - s_half_child.x, self.pos.y + s_half_child.y);
-        let p_t_r = Vec2::new(self.pos.x + s_half_child.x, self.pos.y + s_half_child.y);
+    /// Half open, a chunk on the border belongs to exactly one node.
+    fn contains(&self, chunk: IVec2) -> bool {
+        chunk.x >= self.min.x && chunk.x < self.min.x + self.size && chunk.y >= self.min.y && chunk.y < self.min.y + self.size
+    }
 
-        let p_b_l = Vec2::new(self.pos.x - s_half_child.x, self.pos.y - s_half_child.y);
-        let p_b_r = Vec2::new(self.pos.x + s_half_child.x, self.pos.y - s_half_child.y);
+    pub fn split(&mut self) {
+        let half = self.size / 2;
 
-        // Top left
-        let top_left = Node::new(self, p_t_l, s_child);
-        // top_right
-        let top_right = Node::new(self, p_t_r, s_child);
-        // bot_left
-        let bot_left = Node::new(self, p_b_l, s_child);
-        // bot_right
-        let bot_right = Node::new(self, p_b_r, s_child);
+        let top_left = Node::new(IVec2::new(self.min.x, self.min.y + half), half);
+        let top_right = Node::new(IVec2::new(self.min.x + half, self.min.y + half), half);
+        let bot_left = Node::new(self.min, half);
+        let bot_right = Node::new(IVec2::new(self.min.x + half, self.min.y), half);
 
         self.children = Some(Box::new([top_left, top_right, bot_left, bot_right]));
 
-        // TODO, if chunks are loaded, should give them to the children then clear.
+        // the chunks belong to the children now
+        for chunk in std::mem::take(&mut self.chunks) {
+            let _ = self.insert(chunk);
+        }
     }
 }
-/// Root octree
+/// Root octree, a quadtree over the chunk grid that follows the player.
 pub struct Octree {
     root: Node,
     max_depth: u32,
+    /// How far the target can see in chunks
+    player_view: usize,
+    /// Chunks further than this are unloaded, bigger than the view so walking on a border does not reload them.
+    unload_view: usize,
 }
 
 impl Octree {
@@ -220,23 +190,196 @@ impl Octree {
     ///
     /// * `target_pos` - position of the thing that looks. In order to lazily allocate further away chunks.
     /// * `player_view` - How far the target can see in chunks
-    /// * `max_depth` - how deep it goes.
-    pub fn new(target_pos: Vec2, player_view: usize, mut max_depth: u32) -> Self {
-        let divs = 2u32.pow(max_depth - 1);
-        let chunks = 2u32.pow(divs);
-        let player_max_distance = player_view * CHUNK_LENGTH;
+    /// * `unload_view` - chunks further than this get unloaded, at least `player_view`.
+    /// * `max_depth` - how deep it goes, the root is `2^max_depth` chunks wide and the leaves are one chunk.
+    ///
+    /// Starts empty, `update` returns the chunks that have to be loaded.
+    pub fn new(target_pos: Vec2, player_view: usize, unload_view: usize, max_depth: u32) -> Self {
+        let unload_view = unload_view.max(player_view);
+        assert!(
+            max_depth > 0 && 1usize << max_depth > unload_view * 2,
+            "the view does not fit inside of the root, check Octree player view and depth"
+        );
+
+        let root = Node::new(Self::root_min(target_pos, max_depth), 1 << max_depth);
+
+        Self { root, max_depth, player_view, unload_view }
+    }
+
+    /// Depth where the root fits the view twice, so it does not have to move every time the player crosses a chunk.
+    pub fn depth_for_view(view: usize) -> u32 {
+        (view * 4).next_power_of_two().trailing_zeros().max(1)
+    }
+
+    /// World position to chunk space, 1.0 is one chunk.
+    fn to_chunk_space(pos: Vec2) -> Vec2 {
+        pos / (CHUNK_LENGTH as f32 * VOXEL_SCALE)
+    }
+
+    /// Root corner so that the chunk of the target is in the center.
+    fn root_min(target_pos: Vec2, max_depth: u32) -> IVec2 {
+        let target = Self::to_chunk_space(target_pos);
+        let half = 1 << (max_depth - 1);
+        IVec2::new(target.x.floor() as i32 - half, target.y.floor() as i32 - half)
+    }
 
-        let size_of_world = (chunks as usize * CHUNK_LENGTH) as f32 * VOXEL_SCALE;
+    /// Follows the target and unloads the chunks that left the unload view.
+    /// Returns the chunks in view that are not loaded yet, they are added with `insert`.
+    pub fn update(&mut self, target_pos: Vec2, loader: &ChunkLoader) -> Vec<(i32, i32)> {
+        let target = Self::to_chunk_space(target_pos);
 
-        let mut root = Node::new(std::ptr::null_mut(), Vec2::new(5000.0, 5000.0), Vec2::new(size_of_world, size_of_world));
-        root.load_player_nodes(max_depth, Vec2::new(5000.0, 5000.0), player_max_distance);
+        // the unload view has to stay inside of the root, move it before it reaches the border
+        let center = self.root.min + IVec2::new(self.root.size / 2, self.root.size / 2);
+        let margin = (self.root.size / 2) as f32 - self.unload_view as f32;
+        if (target.x - center.x as f32).abs() > margin || (target.y - center.y as f32).abs() > margin {
+            self.recenter(target_pos, loader);
+        }
 
-        Self { root, max_depth }
+        let mut missing = vec![];
+        self.root.load_player_nodes(target, self.player_view as f32, self.unload_view as f32, loader, &mut missing);
+        missing.into_iter().map(|chunk| (chunk.x, chunk.y)).collect()
     }
 
-    pub fn find_node(&self, pos: Vec2) -> &Node {
-        self.root.find_node(self.max_depth, pos)
+    /// Adds a loaded chunk, gives it back if it is outside of the root.
+    pub fn insert(&mut self, chunk: Chunk) -> Result<(), Box<Chunk>> {
+        self.root.insert(chunk)
+    }
+
+    /// Distance in chunks from the target to the chunk, 0 if the target is inside of it.
+    pub fn chunk_distance(target_pos: Vec2, chunk_x: i32, chunk_z: i32) -> f32 {
+        Node::new(IVec2::new(chunk_x, chunk_z), 1).distance(Self::to_chunk_space(target_pos))
+    }
+
+    /// Moves the root, chunks that are still inside of it are kept.
+    fn recenter(&mut self, target_pos: Vec2, loader: &ChunkLoader) {
+        let mut chunks = vec![];
+        self.root.drain_chunks(&mut chunks);
+
+        self.root = Node::new(Self::root_min(target_pos, self.max_depth), 1 << self.max_depth);
+
+        for chunk in chunks {
+            if let Err(chunk) = self.root.insert(chunk) {
+                loader.unload(*chunk);
+            }
+        }
+    }
+
+    /// Unloads every chunk, edited ones get saved.
+    pub fn unload_all(&mut self, loader: &ChunkLoader) {
+        self.root.merge(loader);
+    }
+
+    /// None if the position is outside of the root.
+    pub fn find_node(&self, pos: Vec2) -> Option<&Node> {
+        let chunk = Self::to_chunk_space(pos);
+        self.root.find_node(IVec2::new(chunk.x.floor() as i32, chunk.y.floor() as i32))
+    }
+
+    pub fn chunk(&self, chunk_x: i32, chunk_z: i32) -> Option<&Chunk> {
+        self.root.chunk(chunk_x, chunk_z)
+    }
+
+    pub fn chunk_mut(&mut self, chunk_x: i32, chunk_z: i32) -> Option<&mut Chunk> {
+        self.root.chunk_mut(chunk_x, chunk_z)
+    }
+
+    /// Every loaded chunk.
+    pub fn chunks(&self) -> Vec<&Chunk> {
+        let mut chunks = vec![];
+        self.root.collect_chunks(&mut chunks);
+        chunks
     }
 
     pub fn get_all_nodes_debug_lines(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use super::*;
+    use crate::terrain::{generator::TerrainGenerator, storage::BlockStorage};
+
+    struct Flat;
+
+    impl TerrainGenerator for Flat {
+        fn generate(&self, _chunk_x: i32, _chunk_z: i32) -> BlockStorage {
+            BlockStorage::new()
+        }
+    }
+
+    fn leaves<'a>(node: &'a Node, found: &mut Vec<&'a Node>) {
+        match &node.children {
+            Some(children) => children.iter().for_each(|child| leaves(child, found)),
+            None => found.push(node),
+        }
+    }
+
+    /// Every chunk coordinate of the root is in exactly one leaf, and every chunk sits in the leaf of its coordinate.
+    fn assert_partition(root: &Node) {
+        let mut all = vec![];
+        leaves(root, &mut all);
+
+        assert_eq!(all.iter().map(|leaf| (leaf.size * leaf.size) as i64).sum::<i64>(), (root.size * root.size) as i64);
+        for z in root.min.y..root.min.y + root.size {
+            for x in root.min.x..root.min.x + root.size {
+                assert_eq!(all.iter().filter(|leaf| leaf.contains(IVec2::new(x, z))).count(), 1, "chunk ({}, {})", x, z);
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for leaf in all {
+            for chunk in &leaf.chunks {
+                assert!(leaf.contains(IVec2::new(chunk.chunk_x, chunk.chunk_z)));
+                assert!(seen.insert((chunk.chunk_x, chunk.chunk_z)), "chunk ({}, {}) is stored twice", chunk.chunk_x, chunk.chunk_z);
+            }
+        }
+    }
+
+    /// Updates the octree and loads everything it asks for.
+    fn follow(octree: &mut Octree, target: Vec2, loader: &ChunkLoader) {
+        for (x, z) in octree.update(target, loader) {
+            octree.insert(loader.load(x, z)).ok().unwrap();
+        }
+        assert!(octree.update(target, loader).is_empty());
+    }
+
+    #[test]
+    fn split_covers_the_node() {
+        let mut node = Node::new(IVec2::new(-8, 4), 16);
+        // split hands the chunks of the node down to the children
+        node.chunks = [(-8, 4), (7, 19), (0, 12), (-1, 11)].map(|(x, z)| Chunk::from_blocks(x, z, Default::default())).into();
+        node.split();
+        assert!(node.chunks.is_empty());
+        node.children.as_mut().unwrap()[1].split();
+        node.children.as_mut().unwrap()[1].children.as_mut().unwrap()[2].split();
+        assert_partition(&node);
+    }
+
+    #[test]
+    fn update_and_recenter_keep_the_partition() {
+        let loader = ChunkLoader::new(Arc::new(Flat), None);
+        let chunk_size = CHUNK_LENGTH as f32 * VOXEL_SCALE;
+        let (player_view, unload_view) = (2, 3);
+        let mut octree = Octree::new(Vec2::zero(), player_view, unload_view, Octree::depth_for_view(unload_view));
+
+        for step in 0..12 {
+            let target = Vec2::new(step as f32 * 1.5 * chunk_size, -(step as f32) * 0.75 * chunk_size);
+            follow(&mut octree, target, &loader);
+            assert_partition(&octree.root);
+
+            for chunk in octree.chunks() {
+                assert!(Octree::chunk_distance(target, chunk.chunk_x, chunk.chunk_z) <= unload_view as f32, "chunk ({}, {}) was not unloaded", chunk.chunk_x, chunk.chunk_z);
+            }
+            let center = Octree::to_chunk_space(target);
+            for z in -4..=4 {
+                for x in -4..=4 {
+                    let (x, z) = (center.x.floor() as i32 + x, center.y.floor() as i32 + z);
+                    if Octree::chunk_distance(target, x, z) <= player_view as f32 {
+                        assert!(octree.chunk(x, z).is_some(), "chunk ({}, {}) in view is not loaded", x, z);
+                    }
+                }
+            }
+        }
+    }
+}
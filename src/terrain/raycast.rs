@@ -0,0 +1,176 @@
+use glm::{IVec3, Vec3};
+
+use super::{block::BlockType, VOXEL_SCALE};
+
+/// Result of a voxel raycast.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    /// Voxel that was hit.
+    pub position: IVec3,
+    pub block: BlockType,
+    /// Normal of the face the ray entered through, zero if the ray started inside the block.
+    pub normal: IVec3,
+    /// Cell in front of the hit face, where a placed block goes.
+    pub previous: IVec3,
+    /// Distance along the ray to the hit face.
+    pub distance: f32,
+}
+
+/// Blocks the player can point at, air and fluids let the ray through.
+pub fn selectable(block: BlockType) -> bool {
+    block != BlockType::AIR && block.fluid().is_none()
+}
+
+/// Amanatides-Woo voxel traversal, visits every voxel the ray passes through in order.
+///
+/// * `origin` - start of the ray in world space.
+/// * `direction` - does not have to be normalized.
+/// * `max_distance` - in world space.
+/// * `block_at` - returns the block if the voxel stops the ray.
+pub fn raycast(origin: Vec3, direction: Vec3, max_distance: f32, block_at: impl Fn(IVec3) -> Option<BlockType>) -> Option<RayHit> {
+    if direction.mag_sq() == 0.0 || !direction.mag_sq().is_finite() {
+        return None;
+    }
+
+    // walk in voxel space, the distances get scaled back at the end
+    let origin = [origin.x / VOXEL_SCALE, origin.y / VOXEL_SCALE, origin.z / VOXEL_SCALE];
+    let direction = direction.normalized();
+    let direction = [direction.x, direction.y, direction.z];
+    let max_distance = max_distance / VOXEL_SCALE;
+
+    let mut voxel = origin.map(|o| o.floor() as i32);
+    let mut step = [0; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+
+    for axis in 0..3 {
+        if direction[axis] > 0.0 {
+            step[axis] = 1;
+            t_max[axis] = (voxel[axis] as f32 + 1.0 - origin[axis]) / direction[axis];
+        } else if direction[axis] < 0.0 {
+            step[axis] = -1;
+            t_max[axis] = (voxel[axis] as f32 - origin[axis]) / direction[axis];
+        } else {
+            continue;
+        }
+        t_delta[axis] = 1.0 / direction[axis].abs();
+    }
+
+    let to_ivec = |v: [i32; 3]| IVec3::new(v[0], v[1], v[2]);
+
+    if let Some(block) = block_at(to_ivec(voxel)) {
+        let position = to_ivec(voxel);
+        return Some(RayHit { position, block, normal: IVec3::zero(), previous: position, distance: 0.0 });
+    }
+
+    loop {
+        let axis = match (t_max[0] < t_max[1], t_max[0] < t_max[2], t_max[1] < t_max[2]) {
+            (true, true, _) => 0,
+            (false, _, true) => 1,
+            _ => 2,
+        };
+
+        let distance = t_max[axis];
+        if distance > max_distance {
+            return None;
+        }
+
+        let previous = to_ivec(voxel);
+        voxel[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+
+        let position = to_ivec(voxel);
+        if let Some(block) = block_at(position) {
+            let mut normal = [0; 3];
+            normal[axis] = -step[axis];
+
+            return Some(RayHit { position, block, normal: to_ivec(normal), previous, distance: distance * VOXEL_SCALE });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashSet};
+
+    use super::*;
+    use crate::terrain::block::{BlockRegistry, TerrainBlocks};
+
+    fn solid(blocks: &[(i32, i32, i32)]) -> impl Fn(IVec3) -> Option<BlockType> + '_ {
+        |pos| blocks.contains(&(pos.x, pos.y, pos.z)).then_some(TerrainBlocks::global().stone)
+    }
+
+    #[test]
+    fn axis_aligned_rays() {
+        let blocks = [(5, 0, 0), (-3, 0, 0), (0, -4, 0), (0, 0, 7)];
+        let origin = Vec3::new(0.5, 0.5, 0.5);
+
+        let cases = [
+            (Vec3::new(1.0, 0.0, 0.0), (5, 0, 0), (-1, 0, 0), 4.5),
+            (Vec3::new(-2.0, 0.0, 0.0), (-3, 0, 0), (1, 0, 0), 2.5),
+            (Vec3::new(0.0, -1.0, 0.0), (0, -4, 0), (0, 1, 0), 3.5),
+            (Vec3::new(0.0, 0.0, 1.0), (0, 0, 7), (0, 0, -1), 6.5),
+        ];
+        for (direction, (x, y, z), (nx, ny, nz), distance) in cases {
+            let hit = raycast(origin, direction, 20.0, solid(&blocks)).unwrap();
+            assert_eq!(hit.position, IVec3::new(x, y, z));
+            assert_eq!(hit.normal, IVec3::new(nx, ny, nz));
+            assert_eq!(hit.previous, hit.position + hit.normal);
+            assert!((hit.distance - distance).abs() < 1e-5, "{} != {}", hit.distance, distance);
+        }
+    }
+
+    #[test]
+    fn diagonal_ray_crosses_the_chunk_border() {
+        let blocks = [(65, 10, 65)];
+        let visited = RefCell::new(vec![]);
+        let block_at = |pos: IVec3| {
+            visited.borrow_mut().push(pos);
+            solid(&blocks)(pos)
+        };
+
+        let hit = raycast(Vec3::new(62.5, 10.5, 62.5), Vec3::new(1.0, 0.0, 1.0), 20.0, block_at).unwrap();
+        assert_eq!(hit.position, IVec3::new(65, 10, 65));
+        assert!(hit.normal == IVec3::new(-1, 0, 0) || hit.normal == IVec3::new(0, 0, -1));
+        assert!((hit.distance - 2.5 * std::f32::consts::SQRT_2).abs() < 1e-4);
+
+        // every step goes to a voxel that shares a face with the last one, through the border at 64
+        let visited = visited.into_inner();
+        for pair in visited.windows(2) {
+            let step = pair[1] - pair[0];
+            assert_eq!(step.x.abs() + step.y.abs() + step.z.abs(), 1);
+        }
+        assert!(visited.contains(&IVec3::new(64, 10, 64)));
+        assert_eq!(visited.iter().map(|pos| (pos.x, pos.z)).collect::<HashSet<_>>().len(), visited.len());
+    }
+
+    #[test]
+    fn ray_starting_inside_a_block() {
+        let hit = raycast(Vec3::new(2.25, 3.5, 4.75), Vec3::new(0.3, -1.0, 0.2), 10.0, solid(&[(2, 3, 4)])).unwrap();
+        assert_eq!(hit.position, IVec3::new(2, 3, 4));
+        assert_eq!(hit.normal, IVec3::zero());
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn max_distance() {
+        let blocks = [(10, 0, 0)];
+        let origin = Vec3::new(0.5, 0.5, 0.5);
+        let direction = Vec3::new(1.0, 0.0, 0.0);
+
+        assert!(raycast(origin, direction, 9.0, solid(&blocks)).is_none());
+        assert_eq!(raycast(origin, direction, 9.5, solid(&blocks)).unwrap().position, IVec3::new(10, 0, 0));
+        assert!(raycast(origin, Vec3::zero(), 100.0, solid(&blocks)).is_none());
+    }
+
+    #[test]
+    fn fluids_are_not_selectable() {
+        assert!(selectable(TerrainBlocks::global().stone));
+        assert!(!selectable(BlockType::AIR));
+        for name in ["water", "lava"] {
+            let fluid = BlockType::by_name(name).unwrap();
+            assert!(!selectable(fluid));
+            assert!(!selectable(BlockRegistry::global().fluid_block(fluid, 3).unwrap()));
+        }
+    }
+}
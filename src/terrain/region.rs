@@ -0,0 +1,515 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::t_thread::{JobDesc, Priority, ThreadPool};
+
+use super::{
+    block::BlockType,
+    storage::{BlockStorage, CHUNK_VOLUME},
+};
+
+/// Chunks along x and z in one region file.
+pub const REGION_LENGTH: i32 = 32;
+const REGION_CHUNKS: usize = (REGION_LENGTH * REGION_LENGTH) as usize;
+
+const MAGIC: [u8; 4] = *b"VXRG";
+const VERSION: u32 = 1;
+
+/// magic + version + (offset, length) for every chunk
+const HEADER_SIZE: usize = 4 + 4 + REGION_CHUNKS * 8;
+
+/// Regions are rewritten without the old payloads once those are bigger than this and the payloads still in use.
+const MIN_GARBAGE: u64 = 1 << 20;
+
+/// Held while a region file is read or written.
+type RegionLock = Arc<Mutex<()>>;
+
+/// Saves edited chunks to disk.
+///
+/// Every region file holds 32x32 chunks:
+/// * `magic` - "VXRG"
+/// * `version` - u32
+/// * `offsets` - (offset: u32, length: u32) per chunk, x -> z order. A length of 0 means the chunk is not saved.
+/// * `payloads` - zlib compressed `BlockStorage` of every saved chunk.
+///
+/// All numbers are little endian.
+/// Loading a chunk only reads its offset and its payload. Saving appends the payload and then updates the offset,
+/// a crash in between leaves the old payload in place. The old payloads are dropped once they take up more space than the used ones.
+pub struct RegionStore {
+    folder: PathBuf,
+    /// One lock per region file, chunks of different regions are read and written at the same time.
+    locks: Mutex<HashMap<(i32, i32), RegionLock>>,
+}
+
+impl RegionStore {
+    pub fn new(folder: impl Into<PathBuf>) -> io::Result<Self> {
+        let folder = folder.into();
+        fs::create_dir_all(&folder)?;
+        Ok(Self { folder, locks: Mutex::new(HashMap::new()) })
+    }
+
+    /// Region coordinate and the chunk slot inside of it.
+    fn locate(chunk_x: i32, chunk_z: i32) -> ((i32, i32), usize) {
+        let region = (chunk_x.div_euclid(REGION_LENGTH), chunk_z.div_euclid(REGION_LENGTH));
+        let slot = chunk_x.rem_euclid(REGION_LENGTH) + chunk_z.rem_euclid(REGION_LENGTH) * REGION_LENGTH;
+        (region, slot as usize)
+    }
+
+    fn region_path(&self, region: (i32, i32)) -> PathBuf {
+        self.folder.join(format!("r.{}.{}.region", region.0, region.1))
+    }
+
+    fn region_lock(&self, region: (i32, i32)) -> Arc<Mutex<()>> {
+        self.locks.lock().unwrap().entry(region).or_default().clone()
+    }
+
+    pub fn has_chunk(&self, chunk_x: i32, chunk_z: i32) -> bool {
+        let (region, slot) = Self::locate(chunk_x, chunk_z);
+        let lock = self.region_lock(region);
+        let _guard = lock.lock().unwrap();
+
+        match self.open_region(region) {
+            Ok(Some(mut file)) => matches!(read_slot(&mut file, slot), Ok((_, length)) if length > 0),
+            _ => false,
+        }
+    }
+
+    pub fn load_chunk(&self, chunk_x: i32, chunk_z: i32) -> io::Result<Option<BlockStorage>> {
+        match self.load_payload(chunk_x, chunk_z)? {
+            Some(payload) => decode_chunk(&payload).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_chunk(&self, chunk_x: i32, chunk_z: i32, blocks: &BlockStorage) -> io::Result<()> {
+        let payload = encode_chunk(blocks)?;
+
+        let (region, slot) = Self::locate(chunk_x, chunk_z);
+        let lock = self.region_lock(region);
+        let _guard = lock.lock().unwrap();
+
+        let path = self.region_path(region);
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut payloads = vec![None; REGION_CHUNKS];
+                payloads[slot] = Some(payload);
+                return write_region_atomic(&path, &payloads);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let header = read_header(&mut file)?;
+        let end = file.seek(SeekFrom::End(0))?;
+        let offset = u32::try_from(end).map_err(|_| invalid_data("region file is full"))?;
+
+        // the payload is on disk before the header points to it
+        file.write_all(&payload)?;
+        file.sync_data()?;
+        file.seek(SeekFrom::Start(8 + slot as u64 * 8))?;
+        file.write_all(&offset.to_le_bytes())?;
+        file.write_all(&(payload.len() as u32).to_le_bytes())?;
+        file.sync_data()?;
+
+        let used: u64 = (0..REGION_CHUNKS).map(|i| if i == slot { payload.len() as u64 } else { slot_entry(&header, i).1 as u64 }).sum();
+        let garbage = end + payload.len() as u64 - HEADER_SIZE as u64 - used;
+        if garbage > MIN_GARBAGE && garbage > used {
+            let payloads = read_region(&mut file)?;
+            drop(file);
+            write_region_atomic(&path, &payloads)?;
+        }
+        Ok(())
+    }
+
+    /// None if the region was never saved.
+    fn open_region(&self, region: (i32, i32)) -> io::Result<Option<File>> {
+        match File::open(self.region_path(region)) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_payload(&self, chunk_x: i32, chunk_z: i32) -> io::Result<Option<Vec<u8>>> {
+        let (region, slot) = Self::locate(chunk_x, chunk_z);
+        let lock = self.region_lock(region);
+        let _guard = lock.lock().unwrap();
+
+        let Some(mut file) = self.open_region(region)? else {
+            return Ok(None);
+        };
+        let (offset, length) = read_slot(&mut file, slot)?;
+        if length == 0 {
+            return Ok(None);
+        }
+        if offset as u64 + length as u64 > file.metadata()?.len() {
+            return Err(invalid_data("chunk payload is out of bounds"));
+        }
+
+        let mut payload = vec![0; length as usize];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut payload)?;
+        Ok(Some(payload))
+    }
+}
+
+/// Edited chunks that were unloaded and still have to be written, saved by a job on the `ThreadPool`.
+/// Loading a chunk that is still waiting here gives the waiting blocks, so the disk is never read before they are written.
+pub struct SaveQueue {
+    store: RegionStore,
+    /// Newest blocks of every waiting chunk, the number tells a chunk that was queued again apart.
+    waiting: Mutex<HashMap<(i32, i32), (u64, BlockStorage)>>,
+    next: AtomicU64,
+    /// A flush job is queued or running.
+    flushing: AtomicBool,
+    /// Only one flush at a time, so an older save never overwrites a newer one.
+    flush_lock: Mutex<()>,
+}
+
+impl SaveQueue {
+    pub fn new(store: RegionStore) -> Self {
+        Self { store, waiting: Mutex::new(HashMap::new()), next: AtomicU64::new(0), flushing: AtomicBool::new(false), flush_lock: Mutex::new(()) }
+    }
+
+    pub fn store(&self) -> &RegionStore {
+        &self.store
+    }
+
+    /// Chunks that are not written yet.
+    pub fn waiting(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    /// Saves the chunk in the background, replaces the blocks of the chunk if it is already waiting.
+    pub fn push(self: &Arc<Self>, chunk_x: i32, chunk_z: i32, blocks: BlockStorage) {
+        let version = self.next.fetch_add(1, Ordering::Relaxed);
+        self.waiting.lock().unwrap().insert((chunk_x, chunk_z), (version, blocks));
+
+        if !self.flushing.swap(true, Ordering::AcqRel) {
+            let queue = self.clone();
+            let _ = ThreadPool::execute_with(JobDesc::new(Priority::Background), move || loop {
+                queue.flush();
+                queue.flushing.store(false, Ordering::Release);
+
+                // a chunk pushed while the flag was still set would wait for the next push otherwise
+                if queue.waiting() == 0 || queue.flushing.swap(true, Ordering::AcqRel) {
+                    break;
+                }
+            });
+        }
+    }
+
+    /// Blocks of the chunk if it is still waiting.
+    pub fn get(&self, chunk_x: i32, chunk_z: i32) -> Option<BlockStorage> {
+        self.waiting.lock().unwrap().get(&(chunk_x, chunk_z)).map(|(_, blocks)| blocks.clone())
+    }
+
+    /// Writes every waiting chunk on the calling thread, waits for a flush that is already running.
+    pub fn flush(&self) {
+        let _guard = self.flush_lock.lock().unwrap();
+
+        let chunks: Vec<(i32, i32)> = self.waiting.lock().unwrap().keys().copied().collect();
+        for (chunk_x, chunk_z) in chunks {
+            let Some((version, blocks)) = self.waiting.lock().unwrap().get(&(chunk_x, chunk_z)).cloned() else {
+                continue;
+            };
+            if let Err(e) = self.store.save_chunk(chunk_x, chunk_z, &blocks) {
+                log::error!("Failed to save chunk ({}, {}): {}", chunk_x, chunk_z, e);
+            }
+
+            let mut waiting = self.waiting.lock().unwrap();
+            if waiting.get(&(chunk_x, chunk_z)).is_some_and(|(newest, _)| *newest == version) {
+                waiting.remove(&(chunk_x, chunk_z));
+            }
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Magic, version and the offset table.
+fn read_header(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut header = vec![0; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header).map_err(|_| invalid_data("not a region file"))?;
+    check_header(&header)?;
+    Ok(header)
+}
+
+fn check_header(header: &[u8]) -> io::Result<()> {
+    if header.len() < 8 || header[0..4] != MAGIC {
+        return Err(invalid_data("not a region file"));
+    }
+
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid_data(&format!("unsupported region version {}", version)));
+    }
+    Ok(())
+}
+
+/// (offset, length) of a chunk in the header.
+fn slot_entry(header: &[u8], slot: usize) -> (u32, u32) {
+    let entry = 8 + slot * 8;
+    (u32::from_le_bytes(header[entry..entry + 4].try_into().unwrap()), u32::from_le_bytes(header[entry + 4..entry + 8].try_into().unwrap()))
+}
+
+/// Reads the magic, the version and the (offset, length) of a single chunk.
+fn read_slot(file: &mut File, slot: usize) -> io::Result<(u32, u32)> {
+    let mut start = [0; 8];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut start).map_err(|_| invalid_data("not a region file"))?;
+    check_header(&start)?;
+
+    let mut entry = [0; 8];
+    file.seek(SeekFrom::Start(8 + slot as u64 * 8))?;
+    file.read_exact(&mut entry).map_err(|_| invalid_data("not a region file"))?;
+    Ok((u32::from_le_bytes(entry[0..4].try_into().unwrap()), u32::from_le_bytes(entry[4..8].try_into().unwrap())))
+}
+
+fn read_region(file: &mut File) -> io::Result<Vec<Option<Vec<u8>>>> {
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+
+    if data.len() < HEADER_SIZE {
+        return Err(invalid_data("not a region file"));
+    }
+    check_header(&data)?;
+
+    let mut payloads = Vec::with_capacity(REGION_CHUNKS);
+    for slot in 0..REGION_CHUNKS {
+        let (offset, length) = slot_entry(&data, slot);
+        if length == 0 {
+            payloads.push(None);
+            continue;
+        }
+
+        let (offset, length) = (offset as usize, length as usize);
+        let payload = data.get(offset..offset + length).ok_or_else(|| invalid_data("chunk payload is out of bounds"))?;
+        payloads.push(Some(payload.to_vec()));
+    }
+    Ok(payloads)
+}
+
+/// Writes next to the region and renames it, a crash never leaves a half written region.
+fn write_region_atomic(path: &Path, payloads: &[Option<Vec<u8>>]) -> io::Result<()> {
+    let temp_path = path.with_extension("region.tmp");
+    write_region(&temp_path, payloads)?;
+    fs::rename(&temp_path, path)
+}
+
+fn write_region(path: &Path, payloads: &[Option<Vec<u8>>]) -> io::Result<()> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mut body = vec![];
+    for payload in payloads {
+        let (offset, length) = match payload {
+            Some(payload) => {
+                let offset = HEADER_SIZE + body.len();
+                body.extend_from_slice(payload);
+                (offset as u32, payload.len() as u32)
+            }
+            None => (0, 0),
+        };
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&length.to_le_bytes());
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&header)?;
+    file.write_all(&body)?;
+    file.sync_all()
+}
+
+/// palette length: u16, palette: u32 per block, bits per index: u8, word count: u32, words: u64 per word
+/// The blocks are compacted first, so replaced blocks do not widen the saved indices.
+fn encode_chunk(blocks: &BlockStorage) -> io::Result<Vec<u8>> {
+    let blocks = blocks.compacted();
+    let (palette, bits, words) = blocks.raw_parts();
+
+    let mut raw = Vec::with_capacity(2 + palette.len() * 4 + 1 + 4 + words.len() * 8);
+    raw.extend_from_slice(&(palette.len() as u16).to_le_bytes());
+    for block in palette {
+        raw.extend_from_slice(&block.as_raw().to_le_bytes());
+    }
+    raw.push(bits as u8);
+    raw.extend_from_slice(&(words.len() as u32).to_le_bytes());
+    for word in words {
+        raw.extend_from_slice(&word.to_le_bytes());
+    }
+
+    let mut encoder = ZlibEncoder::new(vec![], Compression::fast());
+    encoder.write_all(&raw)?;
+    encoder.finish()
+}
+
+fn take_bytes<'a>(cursor: &mut &'a [u8], count: usize) -> io::Result<&'a [u8]> {
+    if cursor.len() < count {
+        return Err(invalid_data("chunk payload is truncated"));
+    }
+    let (head, tail) = cursor.split_at(count);
+    *cursor = tail;
+    Ok(head)
+}
+
+/// Largest uncompressed chunk: a full u16 palette and one word per voxel.
+const MAX_RAW_SIZE: usize = 2 + u16::MAX as usize * 4 + 1 + 4 + CHUNK_VOLUME * 8;
+
+fn decode_chunk(payload: &[u8]) -> io::Result<BlockStorage> {
+    let mut raw = vec![];
+    // one byte more than the largest chunk is enough to know it is corrupted
+    ZlibDecoder::new(payload).take(MAX_RAW_SIZE as u64 + 1).read_to_end(&mut raw)?;
+    if raw.len() > MAX_RAW_SIZE {
+        return Err(invalid_data("chunk payload is too large"));
+    }
+
+    let mut cursor = raw.as_slice();
+    let mut take = |count: usize| take_bytes(&mut cursor, count);
+
+    let palette_len = u16::from_le_bytes(take(2)?.try_into().unwrap()) as usize;
+    let mut palette = Vec::with_capacity(palette_len);
+    for _ in 0..palette_len {
+        let raw_block = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        palette.push(BlockType::from_raw(raw_block).ok_or_else(|| invalid_data("unknown block type"))?);
+    }
+
+    let bits = take(1)?[0] as u32;
+    let word_count = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+    // a corrupted count fails here or in `take`, before anything is allocated for it
+    if word_count > CHUNK_VOLUME {
+        return Err(invalid_data("chunk payload has too many words"));
+    }
+    let words = take(word_count * 8)?.chunks_exact(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect();
+
+    BlockStorage::from_raw_parts(palette, bits, words).ok_or_else(|| invalid_data("chunk payload does not match the chunk size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::block::TerrainBlocks;
+
+    fn temp_store(name: &str) -> RegionStore {
+        let folder = std::env::temp_dir().join(format!("voxelengine-region-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        RegionStore::new(folder).unwrap()
+    }
+
+    fn blocks(seed: usize) -> BlockStorage {
+        let mut blocks = BlockStorage::new();
+        for i in 0..64 {
+            blocks.set(i, (i * seed) % 80, (i + seed) % 64, if i % 2 == 0 { TerrainBlocks::global().stone } else { TerrainBlocks::global().dirt });
+        }
+        blocks
+    }
+
+    #[test]
+    fn saved_chunks_are_loaded_again() {
+        let store = temp_store("roundtrip");
+        assert!(!store.has_chunk(0, 0));
+        assert!(store.load_chunk(0, 0).unwrap().is_none());
+
+        for (i, (x, z)) in [(0, 0), (1, 0), (-1, -33), (31, 31)].into_iter().enumerate() {
+            store.save_chunk(x, z, &blocks(i + 1)).unwrap();
+        }
+        // saving again replaces the chunk and keeps the others
+        store.save_chunk(1, 0, &blocks(7)).unwrap();
+
+        // a single palette entry without any words, and a chunk without air
+        let full = BlockStorage::filled(TerrainBlocks::global().stone);
+        store.save_chunk(2, 2, &BlockStorage::new()).unwrap();
+        store.save_chunk(3, 2, &full).unwrap();
+        assert!(store.load_chunk(2, 2).unwrap().unwrap() == BlockStorage::new());
+        assert!(store.load_chunk(3, 2).unwrap().unwrap() == full);
+
+        assert!(store.load_chunk(0, 0).unwrap().unwrap() == blocks(1));
+        assert!(store.load_chunk(1, 0).unwrap().unwrap() == blocks(7));
+        assert!(store.load_chunk(-1, -33).unwrap().unwrap() == blocks(3));
+        assert!(store.load_chunk(31, 31).unwrap().unwrap() == blocks(4));
+        assert!(store.has_chunk(31, 31) && !store.has_chunk(2, 0));
+    }
+
+    /// Every voxel random, so the payload does not compress.
+    fn noisy_blocks(mut seed: u64) -> BlockStorage {
+        let palette = [BlockType::AIR, TerrainBlocks::global().stone, TerrainBlocks::global().dirt, TerrainBlocks::global().sand];
+        let mut blocks = BlockStorage::new();
+        for i in 0..CHUNK_VOLUME {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            blocks.set_index(i, palette[(seed >> 62) as usize]);
+        }
+        blocks
+    }
+
+    #[test]
+    fn old_payloads_are_dropped() {
+        let store = temp_store("compact");
+        let path = store.region_path((0, 0));
+
+        let (mut largest, mut shrunk) = (0, false);
+        for i in 0..40 {
+            store.save_chunk(0, 0, &noisy_blocks(i)).unwrap();
+            let size = fs::metadata(&path).unwrap().len();
+            shrunk |= size < largest;
+            largest = largest.max(size);
+        }
+        assert!(shrunk);
+        assert!(store.load_chunk(0, 0).unwrap().unwrap() == noisy_blocks(39));
+        assert!(largest < HEADER_SIZE as u64 + 2 * MIN_GARBAGE + 4 * 100_000, "region grew to {} bytes", largest);
+    }
+
+    #[test]
+    fn waiting_chunks_are_loaded_before_they_are_written() {
+        let queue = SaveQueue::new(temp_store("queue"));
+        queue.waiting.lock().unwrap().insert((2, 3), (0, blocks(5)));
+
+        assert!(queue.get(2, 3).unwrap() == blocks(5));
+        assert!(!queue.store().has_chunk(2, 3));
+
+        queue.flush();
+        assert_eq!(queue.waiting(), 0);
+        assert!(queue.store().load_chunk(2, 3).unwrap().unwrap() == blocks(5));
+    }
+
+    fn compress(raw: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::fast());
+        encoder.write_all(raw).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn corrupted_word_counts_are_rejected() {
+        let blocks = blocks(1);
+        let mut raw = vec![];
+        ZlibDecoder::new(encode_chunk(&blocks).unwrap().as_slice()).read_to_end(&mut raw).unwrap();
+        assert!(decode_chunk(&compress(&raw)).unwrap() == blocks.compacted());
+
+        // palette length, palette, bits and then the word count
+        let count = 2 + blocks.compacted().palette().len() * 4 + 1;
+        for word_count in [u32::MAX, CHUNK_VOLUME as u32, 1] {
+            raw[count..count + 4].copy_from_slice(&word_count.to_le_bytes());
+            let error = decode_chunk(&compress(&raw)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{} words", word_count);
+        }
+    }
+
+    #[test]
+    fn oversized_payloads_are_rejected() {
+        let error = decode_chunk(&compress(&vec![0; MAX_RAW_SIZE + 1])).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,294 @@
+use glm::Vec3;
+
+use crate::core::hash::fnv1a;
+
+use super::{block::BlockType, CHUNK_HEIGHT, CHUNK_LENGTH, VOXEL_SCALE};
+
+pub const CHUNK_VOLUME: usize = CHUNK_LENGTH * CHUNK_LENGTH * CHUNK_HEIGHT;
+
+/// Voxel store of a chunk.
+/// Only the block ids are kept in a small per-chunk palette, every voxel stores a bit-packed index into it.
+/// The bit width grows when a new `BlockType` is added, a chunk with only air costs a single palette entry.
+/// Blocks that are replaced stay in the palette until `compact`, which runs before a chunk is saved.
+///
+/// Layout of the voxels is x -> z -> y, the same as the old `Vec<GPUBlock>`.
+#[derive(Clone)]
+pub struct BlockStorage {
+    palette: Vec<BlockType>,
+    bits_per_index: u32,
+    words: Vec<u64>,
+}
+
+impl BlockStorage {
+    /// Creates a chunk that is only air.
+    pub fn new() -> Self {
+        Self::filled(BlockType::AIR)
+    }
+
+    /// Creates a chunk where every voxel is `block`.
+    pub fn filled(block: BlockType) -> Self {
+        Self { palette: vec![block], bits_per_index: 0, words: vec![] }
+    }
+
+    pub const fn index(x: usize, y: usize, z: usize) -> usize {
+        x + z * CHUNK_LENGTH + y * CHUNK_LENGTH * CHUNK_LENGTH
+    }
+
+    /// Inverse of `index`, returns the local (x, y, z).
+    pub const fn local_position(index: usize) -> (usize, usize, usize) {
+        let x = index % CHUNK_LENGTH;
+        let z = (index / CHUNK_LENGTH) % CHUNK_LENGTH;
+        let y = index / (CHUNK_LENGTH * CHUNK_LENGTH);
+        (x, y, z)
+    }
+
+    /// World position of the voxel, derived from the chunk coordinate.
+    pub fn world_position(chunk_x: i32, chunk_z: i32, x: usize, y: usize, z: usize) -> Vec3 {
+        let x_start = chunk_x as f32 * CHUNK_LENGTH as f32;
+        let z_start = chunk_z as f32 * CHUNK_LENGTH as f32;
+
+        Vec3::new(x as f32 + x_start, y as f32, z as f32 + z_start) * VOXEL_SCALE
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> BlockType {
+        self.get_index(Self::index(x, y, z))
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block: BlockType) {
+        self.set_index(Self::index(x, y, z), block);
+    }
+
+    pub fn get_index(&self, index: usize) -> BlockType {
+        debug_assert!(index < CHUNK_VOLUME);
+        self.palette[self.read_packed(index)]
+    }
+
+    pub fn set_index(&mut self, index: usize, block: BlockType) {
+        debug_assert!(index < CHUNK_VOLUME);
+        let palette_index = self.palette_index(block);
+        self.write_packed(index, palette_index);
+    }
+
+    pub fn palette(&self) -> &[BlockType] {
+        &self.palette
+    }
+
+    pub fn bits_per_index(&self) -> u32 {
+        self.bits_per_index
+    }
+
+    pub fn raw_parts(&self) -> (&[BlockType], u32, &[u64]) {
+        (&self.palette, self.bits_per_index, &self.words)
+    }
+
+    /// Rebuilds the storage from `raw_parts`, returns None if they do not describe a full chunk.
+    pub fn from_raw_parts(palette: Vec<BlockType>, bits_per_index: u32, words: Vec<u64>) -> Option<Self> {
+        if palette.is_empty() || bits_per_index >= u64::BITS || palette.len() > 1 << bits_per_index {
+            return None;
+        }
+
+        let word_count = match bits_per_index {
+            0 => 0,
+            bits => CHUNK_VOLUME.div_ceil(Self::per_word(bits)),
+        };
+        if words.len() != word_count {
+            return None;
+        }
+
+        let storage = Self { palette, bits_per_index, words };
+        if (0..CHUNK_VOLUME).any(|i| storage.read_packed(i) >= storage.palette.len()) {
+            return None;
+        }
+        Some(storage)
+    }
+
+    /// Hash of the voxel content, stable between runs and rust versions so generated chunks can be compared.
+    /// Storages with the same voxels have the same hash, no matter the order of their palettes.
+    pub fn content_hash(&self) -> u64 {
+        let compacted = self.compacted();
+        let (palette, bits, words) = compacted.raw_parts();
+
+        let mut data = Vec::with_capacity(palette.len() * 4 + 4 + words.len() * 8);
+        for block in palette {
+            data.extend_from_slice(&block.as_raw().to_le_bytes());
+        }
+        data.extend_from_slice(&bits.to_le_bytes());
+        for word in words {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        fnv1a(&data)
+    }
+
+    /// Drops the palette entries no voxel uses anymore and packs the indices with the smallest bit width.
+    /// The palette is ordered by the first voxel that uses an entry.
+    pub fn compact(&mut self) {
+        *self = self.compacted();
+    }
+
+    /// Copy of the storage after `compact`.
+    pub fn compacted(&self) -> Self {
+        if self.bits_per_index == 0 {
+            return self.clone();
+        }
+
+        // old palette index -> new one
+        let mut remap = vec![usize::MAX; self.palette.len()];
+        let mut palette = vec![];
+        let indices: Vec<usize> = (0..CHUNK_VOLUME)
+            .map(|i| {
+                let old = self.read_packed(i);
+                if remap[old] == usize::MAX {
+                    remap[old] = palette.len();
+                    palette.push(self.palette[old]);
+                }
+                remap[old]
+            })
+            .collect();
+
+        let bits = usize::BITS - (palette.len() - 1).leading_zeros();
+        let mut compacted = Self { palette, bits_per_index: bits, words: vec![] };
+        if bits > 0 {
+            compacted.words = vec![0; CHUNK_VOLUME.div_ceil(Self::per_word(bits))];
+            for (i, index) in indices.into_iter().enumerate() {
+                compacted.write_packed(i, index);
+            }
+        }
+        compacted
+    }
+
+    /// Bytes used by the voxel data, excluding the palette.
+    pub fn memory_usage(&self) -> usize {
+        self.words.len() * std::mem::size_of::<u64>()
+    }
+
+    /// Returns the palette slot of `block`, adds it and grows the bit width if it is new.
+    fn palette_index(&mut self, block: BlockType) -> usize {
+        if let Some(index) = self.palette.iter().position(|b| *b == block) {
+            return index;
+        }
+
+        self.palette.push(block);
+
+        if self.palette.len() > 1 << self.bits_per_index {
+            self.grow(self.bits_per_index + 1);
+        }
+
+        self.palette.len() - 1
+    }
+
+    const fn per_word(bits: u32) -> usize {
+        (u64::BITS / bits) as usize
+    }
+
+    fn read_packed(&self, index: usize) -> usize {
+        if self.bits_per_index == 0 {
+            return 0;
+        }
+
+        let per_word = Self::per_word(self.bits_per_index);
+        let word = self.words[index / per_word];
+        let shift = (index % per_word) as u32 * self.bits_per_index;
+        let mask = (1u64 << self.bits_per_index) - 1;
+
+        ((word >> shift) & mask) as usize
+    }
+
+    fn write_packed(&mut self, index: usize, value: usize) {
+        if self.bits_per_index == 0 {
+            debug_assert!(value == 0);
+            return;
+        }
+
+        let per_word = Self::per_word(self.bits_per_index);
+        let word = &mut self.words[index / per_word];
+        let shift = (index % per_word) as u32 * self.bits_per_index;
+        let mask = (1u64 << self.bits_per_index) - 1;
+
+        *word = (*word & !(mask << shift)) | ((value as u64 & mask) << shift);
+    }
+
+    /// Repacks every index with the new bit width.
+    fn grow(&mut self, new_bits: u32) {
+        let mut grown = Self {
+            palette: vec![],
+            bits_per_index: new_bits,
+            words: vec![0; CHUNK_VOLUME.div_ceil(Self::per_word(new_bits))],
+        };
+
+        if self.bits_per_index > 0 {
+            for i in 0..CHUNK_VOLUME {
+                grown.write_packed(i, self.read_packed(i));
+            }
+        }
+
+        self.bits_per_index = new_bits;
+        self.words = grown.words;
+    }
+}
+
+/// Same voxels, the palettes and bit widths can differ.
+impl PartialEq for BlockStorage {
+    fn eq(&self, other: &Self) -> bool {
+        if self.raw_parts() == other.raw_parts() {
+            return true;
+        }
+        (0..CHUNK_VOLUME).all(|i| self.get_index(i) == other.get_index(i))
+    }
+}
+
+impl Eq for BlockStorage {}
+
+impl Default for BlockStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::block::TerrainBlocks;
+
+    #[test]
+    fn compact_drops_replaced_blocks() {
+        let mut storage = BlockStorage::new();
+        storage.set(0, 0, 0, TerrainBlocks::global().stone);
+        storage.set(1, 0, 0, TerrainBlocks::global().dirt);
+        storage.set(2, 0, 0, TerrainBlocks::global().sand);
+        assert_eq!(storage.bits_per_index(), 2);
+
+        storage.set(0, 0, 0, BlockType::AIR);
+        storage.set(2, 0, 0, BlockType::AIR);
+        storage.compact();
+
+        assert_eq!(storage.palette(), &[BlockType::AIR, TerrainBlocks::global().dirt]);
+        assert_eq!(storage.bits_per_index(), 1);
+        assert_eq!(storage.get(1, 0, 0), TerrainBlocks::global().dirt);
+        assert_eq!(storage.get(0, 0, 0), BlockType::AIR);
+
+        storage.set(1, 0, 0, BlockType::AIR);
+        storage.compact();
+        assert!(storage == BlockStorage::new());
+    }
+
+    #[test]
+    fn content_hash_ignores_palette_order() {
+        let mut a = BlockStorage::new();
+        a.set(3, 4, 5, TerrainBlocks::global().stone);
+        a.set(6, 7, 8, TerrainBlocks::global().grass);
+
+        let mut b = BlockStorage::filled(TerrainBlocks::global().grass);
+        b.set(3, 4, 5, TerrainBlocks::global().stone);
+        for i in 0..CHUNK_VOLUME {
+            if i != BlockStorage::index(3, 4, 5) && i != BlockStorage::index(6, 7, 8) {
+                b.set_index(i, BlockType::AIR);
+            }
+        }
+
+        assert_ne!(a.palette(), b.palette());
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        b.set(0, 0, 0, TerrainBlocks::global().dirt);
+        assert_ne!(a.content_hash(), b.content_hash());
+    }
+}
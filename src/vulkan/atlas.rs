@@ -0,0 +1,164 @@
+use std::{collections::HashMap, path::Path};
+
+use image::RgbaImage;
+
+use crate::core::{asset::AssetError, vfs::Vfs};
+
+/// Square tiles of `grid` pixels, layer after layer, see `ResourceManager::create_texture_array`.
+pub struct TextureArray {
+    pub dimensions: (u32, u32),
+    pub grid: u32,
+    pub pixel_size: u32,
+    pub data: Vec<u8>,
+}
+
+impl TextureArray {
+    pub fn layers(&self) -> u32 {
+        (self.dimensions.0 / self.grid) * (self.dimensions.1 / self.grid)
+    }
+
+    /// Rgba pixels of one layer.
+    pub fn layer(&self, layer: u32) -> &[u8] {
+        let size = (self.grid * self.grid * self.pixel_size) as usize;
+        &self.data[layer as usize * size..(layer as usize + 1) * size]
+    }
+}
+
+/// Texture array and the layer of every tile, the block registry resolves its tile names with `layers`.
+pub struct TextureAtlas {
+    pub array: TextureArray,
+    pub layers: HashMap<String, u32>,
+}
+
+/// Packs single block textures into a `TextureArray`, one layer per texture.
+///
+/// ```ignore
+/// let mut builder = AtlasBuilder::new();
+/// builder.add_directory(&format!("{}blocks/", TEXTURE_FOLDER))?;
+/// let atlas = builder.build()?;
+/// let materials = Materials::from_layers(&atlas.layers)?;
+/// ```
+#[derive(Default)]
+pub struct AtlasBuilder {
+    tiles: HashMap<String, RgbaImage>,
+}
+
+impl AtlasBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A tile added twice keeps the last image.
+    pub fn add(&mut self, name: &str, image: RgbaImage) {
+        self.tiles.insert(name.to_owned(), image);
+    }
+
+    pub fn add_png(&mut self, name: &str, png: &[u8]) -> Result<(), AssetError> {
+        let image = image::load_from_memory_with_format(png, image::ImageFormat::Png).map_err(|e| AssetError::Invalid(format!("{}: {}", name, e)))?;
+        self.add(name, image.to_rgba8());
+        Ok(())
+    }
+
+    /// Adds every png in the directory, the tile name is the file name without extension. Reads through the `Vfs`.
+    pub fn add_directory(&mut self, dir: &str) -> Result<(), AssetError> {
+        for file in Vfs::files_in(dir) {
+            let path = Path::new(&file);
+            if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")) {
+                continue;
+            }
+
+            let name = path.file_stem().and_then(|stem| stem.to_str()).ok_or_else(|| AssetError::Invalid(format!("{} has no utf-8 file name", file)))?;
+            self.add_png(name, &Vfs::read(&file)?)?;
+        }
+        Ok(())
+    }
+
+    /// Layers are given in name order, so the same textures always give the same layers.
+    pub fn build(self) -> Result<TextureAtlas, AssetError> {
+        let mut tiles: Vec<(String, RgbaImage)> = self.tiles.into_iter().collect();
+        tiles.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let Some((first_name, first)) = tiles.first() else {
+            return Err(AssetError::Invalid("the atlas has no textures".to_owned()));
+        };
+
+        let grid = first.width();
+        if grid == 0 || first.height() != grid {
+            return Err(AssetError::Invalid(format!("{} is {}x{}, textures have to be square", first_name, first.width(), first.height())));
+        }
+        if let Some((name, image)) = tiles.iter().find(|(_, image)| image.dimensions() != (grid, grid)) {
+            return Err(AssetError::Invalid(format!("{} is {}x{}, {} is {}x{}", name, image.width(), image.height(), first_name, grid, grid)));
+        }
+
+        let pixel_size = 4;
+        let mut data = Vec::with_capacity((grid * grid * pixel_size) as usize * tiles.len());
+        let mut layers = HashMap::with_capacity(tiles.len());
+        for (layer, (name, image)) in tiles.into_iter().enumerate() {
+            data.extend_from_slice(image.as_raw());
+            layers.insert(name, layer as u32);
+        }
+
+        let dimensions = (grid, grid * layers.len() as u32);
+        Ok(TextureAtlas { array: TextureArray { dimensions, grid, pixel_size, data }, layers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(size: u32, value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(size, size, image::Rgba([value; 4]))
+    }
+
+    #[test]
+    fn layers_are_in_name_order() {
+        let mut builder = AtlasBuilder::new();
+        builder.add("stone", tile(4, 3));
+        builder.add("dirt", tile(4, 1));
+        builder.add("grass", tile(4, 9));
+        // replaces the first one
+        builder.add("grass", tile(4, 2));
+
+        let atlas = builder.build().unwrap();
+        assert_eq!(atlas.layers, HashMap::from([("dirt".to_owned(), 0), ("grass".to_owned(), 1), ("stone".to_owned(), 2)]));
+        assert_eq!(atlas.array.dimensions, (4, 12));
+        assert_eq!(atlas.array.layers(), 3);
+
+        for (name, value) in [("dirt", 1), ("grass", 2), ("stone", 3)] {
+            let layer = atlas.array.layer(atlas.layers[name]);
+            assert_eq!(layer.len(), 4 * 4 * 4);
+            assert!(layer.iter().all(|&byte| byte == value), "{}", name);
+        }
+    }
+
+    #[test]
+    fn tiles_have_to_be_square_and_the_same_size() {
+        assert!(AtlasBuilder::new().build().is_err());
+
+        let mut builder = AtlasBuilder::new();
+        builder.add("wide", RgbaImage::new(8, 4));
+        assert!(matches!(builder.build(), Err(AssetError::Invalid(_))));
+
+        let mut builder = AtlasBuilder::new();
+        builder.add("a", tile(4, 0));
+        builder.add("b", tile(8, 0));
+        assert!(matches!(builder.build(), Err(AssetError::Invalid(_))));
+
+        let mut builder = AtlasBuilder::new();
+        builder.add("a", tile(4, 0));
+        builder.add("b", RgbaImage::new(4, 8));
+        assert!(matches!(builder.build(), Err(AssetError::Invalid(_))));
+    }
+
+    #[test]
+    fn pngs_are_decoded() {
+        let mut png = vec![];
+        tile(2, 7).write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+
+        let mut builder = AtlasBuilder::new();
+        builder.add_png("a", &png).unwrap();
+        assert!(builder.add_png("b", b"not a png").is_err());
+        assert_eq!(builder.build().unwrap().array.layer(0), [7; 16]);
+    }
+}
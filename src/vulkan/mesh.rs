@@ -13,6 +13,7 @@ pub trait Vertex {
     }
 }
 #[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Face {
     Right,
     Left,
@@ -22,6 +23,30 @@ pub enum Face {
     Back,
 }
 
+impl Face {
+    pub const ALL: [Face; 6] = [Face::Right, Face::Left, Face::Top, Face::Bottom, Face::Front, Face::Back];
+
+    /// Axis the face points along, 0 = x, 1 = y, 2 = z.
+    pub const fn axis(&self) -> usize {
+        match self {
+            Face::Right | Face::Left => 0,
+            Face::Top | Face::Bottom => 1,
+            Face::Front | Face::Back => 2,
+        }
+    }
+
+    /// Right, top and front point towards +x, +y and +z.
+    pub const fn is_positive(&self) -> bool {
+        matches!(self, Face::Right | Face::Top | Face::Front)
+    }
+
+    pub fn normal(&self) -> Vec3 {
+        let mut normal = [0.0; 3];
+        normal[self.axis()] = if self.is_positive() { 1.0 } else { -1.0 };
+        Vec3::from(normal)
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, align(16))]
 pub struct ChunkMesh {
@@ -61,6 +86,11 @@ pub struct VertexBlock {
     norm: glm::Vec3,
     uv: glm::Vec2,
     face_index: u32,
+    block_type: u32,
+    /// Sky light in bits 4 to 7, block light in bits 0 to 3, see `terrain::light::Light`.
+    light: u32,
+    /// Ambient occlusion of the corner, 3 is not occluded and 0 the darkest, see `GreedyMesh::vertex_ao`.
+    ao: u32,
 }
 
 impl Default for VertexBlock {
@@ -70,6 +100,9 @@ impl Default for VertexBlock {
             face_index: Default::default(),
             norm: Default::default(),
             uv: Default::default(),
+            block_type: Default::default(),
+            light: Default::default(),
+            ao: 3,
         }
     }
 }
@@ -81,6 +114,9 @@ impl Vertex for VertexBlock {
             vk::VertexInputAttributeDescription::default().binding(0).location(1).format(vk::Format::R32G32B32_SFLOAT).offset(memoffset::offset_of!(VertexBlock, norm) as u32),
             vk::VertexInputAttributeDescription::default().binding(0).location(2).format(vk::Format::R32G32_SFLOAT).offset(memoffset::offset_of!(VertexBlock, uv) as u32),
             vk::VertexInputAttributeDescription::default().binding(0).location(3).format(vk::Format::R32_UINT).offset(memoffset::offset_of!(VertexBlock, face_index) as u32),
+            vk::VertexInputAttributeDescription::default().binding(0).location(4).format(vk::Format::R32_UINT).offset(memoffset::offset_of!(VertexBlock, block_type) as u32),
+            vk::VertexInputAttributeDescription::default().binding(0).location(5).format(vk::Format::R32_UINT).offset(memoffset::offset_of!(VertexBlock, light) as u32),
+            vk::VertexInputAttributeDescription::default().binding(0).location(6).format(vk::Format::R32_UINT).offset(memoffset::offset_of!(VertexBlock, ao) as u32),
         ]
         .to_vec()
     }
@@ -133,7 +169,45 @@ impl VertexBlock {
     ];
 
     pub const fn new(pos: glm::Vec3, norm: Vec3, uv: Vec2, face_index: u32) -> Self {
-        Self { pos, norm, uv, face_index }
+        Self { pos, norm, uv, face_index, block_type: 0, light: 0, ao: 3 }
+    }
+
+    /// Block the face belongs to, used to look up the `GPUTexture` of chunk meshes.
+    pub const fn with_block_type(self, block_type: u32) -> Self {
+        Self { block_type, ..self }
+    }
+
+    pub fn face_index(&self) -> u32 {
+        self.face_index
+    }
+
+    pub fn block_type(&self) -> u32 {
+        self.block_type
+    }
+
+    /// Light the face is seen in, the mesh of a chunk takes it from the voxel in front of the face.
+    pub const fn with_light(self, light: u32) -> Self {
+        Self { light, ..self }
+    }
+
+    pub fn light(&self) -> u32 {
+        self.light
+    }
+
+    pub const fn with_ao(self, ao: u32) -> Self {
+        Self { ao, ..self }
+    }
+
+    pub fn ao(&self) -> u32 {
+        self.ao
+    }
+
+    pub fn norm(&self) -> Vec3 {
+        self.norm
+    }
+
+    pub fn uv(&self) -> Vec2 {
+        self.uv
     }
     pub fn new_quad(pos: glm::Vec3, size: glm::Vec3) -> Vec<VertexBlock> {
         let mut quad_vertices = vec![];
@@ -192,7 +266,7 @@ impl VertexBlock {
         quad_vertices
     }
 
-    fn generate_face(vertices: &mut Vec<VertexBlock>, position: &Vec<Vec3>, uv: &Vec<Vec2>, norm: &Vec3, face: u32) {
+    fn generate_face(vertices: &mut Vec<VertexBlock>, position: &[Vec3], uv: &[Vec2], norm: &Vec3, face: u32) {
         for i in 0..position.len() {
             vertices.push(VertexBlock::new(position[i], *norm, uv[i], face));
         }
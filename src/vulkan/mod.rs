@@ -24,6 +24,7 @@ use winit::{
 
 use crate::core::camera::Camera;
 
+pub mod atlas;
 pub mod builder;
 pub mod init;
 pub mod loader;
@@ -1,4 +1,4 @@
-use std::{ffi::CString, fs::File, io::Read, mem, slice};
+use std::{ffi::CString, mem, slice};
 
 use ash::{
     khr::swapchain,
@@ -6,8 +6,12 @@ use ash::{
     vk::{self, AccessFlags, BufferImageCopy, CommandBufferLevel, CommandPool, DependencyFlags, ImageAspectFlags, ImageLayout, Offset3D, SubmitInfo},
 };
 
-use crate::vulkan::{TKQueue, VulkanContext};
+use crate::{
+    core::vfs::Vfs,
+    vulkan::{TKQueue, VulkanContext},
+};
 
+pub use super::atlas::TextureArray;
 use super::{
     init,
     loader::{DebugLoaderEXT, ShaderLoaderEXT},
@@ -370,26 +374,14 @@ pub fn copy_to_image_from_image(device: &ash::Device, cmd: vk::CommandBuffer, sr
 }
 
 fn load_shader(path: String) -> Vec<u8> {
-    let mut file = File::open(path.clone()).expect(&format!("unable to read file {}", path));
-    let mut buffer = vec![];
-    file.read_to_end(&mut buffer).expect("unable to read file");
-
-    return buffer;
+    Vfs::read(&path).unwrap_or_else(|e| panic!("unable to read file {}: {}", path, e))
 }
 
-pub struct TextureArray {
-    pub dimensions: (u32, u32),
-    pub grid: u32,
-    pub pixel_size: u32,
-    pub data: Vec<u8>,
-}
-
-use image::io::Reader as ImageReader;
-
 pub fn load_texture_array(texture_name: &str, chunk_grid: u32) -> TextureArray {
     let path = format!("{}{}", TEXTURE_FOLDER, texture_name);
 
-    let image = ImageReader::open(path).unwrap().decode().unwrap().to_rgba8();
+    let bytes = Vfs::read(&path).unwrap_or_else(|e| panic!("unable to read texture {}: {}", path, e));
+    let image = image::load_from_memory(&bytes).unwrap().to_rgba8();
     let dimensions = image.dimensions();
     let pixel_size = 4;
     let raw = image.as_raw();